@@ -0,0 +1,324 @@
+//! Binary delta encoding used by THOR delta entries.
+//!
+//! A delta describes how to rebuild a file (the target) from a previous
+//! version of that file (the base) as a list of instructions: copy a range
+//! of the base, or insert literal bytes. Both the base and the target are
+//! identified by their size and SHA-256 digest, which lets the consumer
+//! refuse to patch unexpected content and verify the rebuilt file.
+//!
+//! Serialized format (little-endian):
+//! [magic "GDLT"]:[version u8]:[base size u64]:[base SHA-256]:
+//! [target size u64]:[target SHA-256]:[instructions...]
+//!
+//! With instructions being either `0:[offset u64]:[len u64]` (copy from the
+//! base) or `1:[len u64]:[bytes]` (insert literal data).
+//!
+//! Deltas are generated and applied in memory, so the base and the target
+//! can't be bigger than `MAX_DELTA_FILE_SIZE`.
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+
+use crate::{GrufError, Result};
+use sha2::{Digest, Sha256};
+
+const DELTA_MAGIC: &[u8; 4] = b"GDLT";
+const DELTA_VERSION: u8 = 1;
+/// Size of the header parsed by `read_delta_info`.
+pub const DELTA_HEADER_SIZE: usize = DELTA_MAGIC.len() + 1 + 2 * (8 + 32);
+/// Maximum size of the files a delta is generated from or rebuilds.
+pub const MAX_DELTA_FILE_SIZE: u64 = 512 * 1024 * 1024;
+const OP_COPY: u8 = 0;
+const OP_INSERT: u8 = 1;
+// Smallest block size used to index the base
+const MIN_BLOCK_SIZE: usize = 32;
+// Upper bound on the number of indexed blocks, keeps memory usage reasonable
+// for big files (maps, videos)
+const MAX_INDEXED_BLOCKS: usize = 1 << 21;
+const HASH_BASE: u64 = 0x0100_0000_01b3;
+
+/// Size and digest information stored at the beginning of a delta.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeltaInfo {
+    pub base_size: u64,
+    pub base_sha256: [u8; 32],
+    pub target_size: u64,
+    pub target_sha256: [u8; 32],
+}
+
+/// Computes the SHA-256 digest of `data`.
+pub fn sha256_digest(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Generates a delta that turns `base` into `target`.
+pub fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::with_capacity(DELTA_HEADER_SIZE + target.len() / 8);
+    delta.extend_from_slice(DELTA_MAGIC);
+    delta.push(DELTA_VERSION);
+    delta.extend_from_slice(&(base.len() as u64).to_le_bytes());
+    delta.extend_from_slice(&sha256_digest(base));
+    delta.extend_from_slice(&(target.len() as u64).to_le_bytes());
+    delta.extend_from_slice(&sha256_digest(target));
+
+    let block_size = std::cmp::max(
+        MIN_BLOCK_SIZE,
+        (base.len() / MAX_INDEXED_BLOCKS).next_power_of_two(),
+    );
+    if base.len() < block_size || target.len() < block_size {
+        push_insert(&mut delta, target);
+        return delta;
+    }
+
+    // Index the base's blocks by their hash. Only the first occurrence of a
+    // block is kept.
+    let mut block_index: HashMap<u64, usize> = HashMap::with_capacity(base.len() / block_size);
+    for offset in (0..=base.len() - block_size).step_by(block_size) {
+        block_index
+            .entry(block_hash(&base[offset..offset + block_size]))
+            .or_insert(offset);
+    }
+
+    // Highest power of the hash base in a window, used to roll the hash
+    let window_factor = (1..block_size).fold(1_u64, |acc, _| acc.wrapping_mul(HASH_BASE));
+    let mut literal_start = 0;
+    let mut pos = 0;
+    let mut hash = block_hash(&target[..block_size]);
+    while pos + block_size <= target.len() {
+        let matching_offset = block_index
+            .get(&hash)
+            .copied()
+            .filter(|&o| base[o..o + block_size] == target[pos..pos + block_size]);
+        match matching_offset {
+            Some(offset) => {
+                // Extend the match backward (into pending literal data) and forward
+                let mut start_target = pos;
+                let mut start_base = offset;
+                while start_target > literal_start
+                    && start_base > 0
+                    && base[start_base - 1] == target[start_target - 1]
+                {
+                    start_target -= 1;
+                    start_base -= 1;
+                }
+                let mut end_target = pos + block_size;
+                let mut end_base = offset + block_size;
+                while end_target < target.len()
+                    && end_base < base.len()
+                    && base[end_base] == target[end_target]
+                {
+                    end_target += 1;
+                    end_base += 1;
+                }
+                if start_target > literal_start {
+                    push_insert(&mut delta, &target[literal_start..start_target]);
+                }
+                push_copy(
+                    &mut delta,
+                    start_base as u64,
+                    (end_base - start_base) as u64,
+                );
+                literal_start = end_target;
+                pos = end_target;
+                if pos + block_size <= target.len() {
+                    hash = block_hash(&target[pos..pos + block_size]);
+                }
+            }
+            None => {
+                // Roll the hash one byte forward
+                if pos + block_size < target.len() {
+                    hash = hash
+                        .wrapping_sub((target[pos] as u64).wrapping_mul(window_factor))
+                        .wrapping_mul(HASH_BASE)
+                        .wrapping_add(target[pos + block_size] as u64);
+                }
+                pos += 1;
+            }
+        }
+    }
+    if literal_start < target.len() {
+        push_insert(&mut delta, &target[literal_start..]);
+    }
+    delta
+}
+
+/// Parses the header of a delta.
+pub fn read_delta_info(delta: &[u8]) -> Result<DeltaInfo> {
+    if delta.len() < DELTA_HEADER_SIZE || &delta[..DELTA_MAGIC.len()] != DELTA_MAGIC {
+        return Err(GrufError::parsing_error("Invalid delta header"));
+    }
+    if delta[DELTA_MAGIC.len()] != DELTA_VERSION {
+        return Err(GrufError::parsing_error("Unsupported delta version"));
+    }
+    let header = &delta[DELTA_MAGIC.len() + 1..DELTA_HEADER_SIZE];
+    Ok(DeltaInfo {
+        base_size: u64::from_le_bytes(header[0..8].try_into().unwrap()),
+        base_sha256: header[8..40].try_into().unwrap(),
+        target_size: u64::from_le_bytes(header[40..48].try_into().unwrap()),
+        target_sha256: header[48..80].try_into().unwrap(),
+    })
+}
+
+/// Rebuilds the target described by `delta` from `base`.
+///
+/// `base` is returned unchanged if it already matches the target (e.g. when
+/// a patch is applied twice). An error is returned if `base` isn't the
+/// content the delta has been generated from, or if the result doesn't match
+/// the target digest.
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let info = read_delta_info(delta)?;
+    if info.base_size > MAX_DELTA_FILE_SIZE || info.target_size > MAX_DELTA_FILE_SIZE {
+        return Err(GrufError::invalid_content("Delta files are too big"));
+    }
+    let base_digest = sha256_digest(base);
+    if base.len() as u64 == info.target_size && base_digest == info.target_sha256 {
+        return Ok(base.to_vec());
+    }
+    if base.len() as u64 != info.base_size || base_digest != info.base_sha256 {
+        return Err(GrufError::invalid_content(
+            "Delta base doesn't match the expected content",
+        ));
+    }
+
+    let target_size = usize::try_from(info.target_size)?;
+    let mut target = Vec::with_capacity(target_size);
+    let mut instructions = &delta[DELTA_HEADER_SIZE..];
+    while let Some((&op, remaining)) = instructions.split_first() {
+        match op {
+            OP_COPY => {
+                let (offset, remaining) = take_u64(remaining)?;
+                let (len, remaining) = take_u64(remaining)?;
+                let start = usize::try_from(offset)?;
+                let end = start
+                    .checked_add(usize::try_from(len)?)
+                    .filter(|&end| end <= base.len())
+                    .ok_or_else(|| GrufError::parsing_error("Delta copies out of bounds"))?;
+                target.extend_from_slice(&base[start..end]);
+                instructions = remaining;
+            }
+            OP_INSERT => {
+                let (len, remaining) = take_u64(remaining)?;
+                let len = usize::try_from(len)?;
+                if remaining.len() < len {
+                    return Err(GrufError::parsing_error("Truncated delta"));
+                }
+                target.extend_from_slice(&remaining[..len]);
+                instructions = &remaining[len..];
+            }
+            _ => return Err(GrufError::parsing_error("Invalid delta instruction")),
+        }
+        if target.len() > target_size {
+            return Err(GrufError::parsing_error("Delta output is too big"));
+        }
+    }
+
+    if target.len() != target_size || sha256_digest(&target) != info.target_sha256 {
+        return Err(GrufError::invalid_content(
+            "Patched content doesn't match the expected digest",
+        ));
+    }
+    Ok(target)
+}
+
+fn block_hash(block: &[u8]) -> u64 {
+    block.iter().fold(0_u64, |acc, &b| {
+        acc.wrapping_mul(HASH_BASE).wrapping_add(b as u64)
+    })
+}
+
+fn push_copy(delta: &mut Vec<u8>, offset: u64, len: u64) {
+    delta.push(OP_COPY);
+    delta.extend_from_slice(&offset.to_le_bytes());
+    delta.extend_from_slice(&len.to_le_bytes());
+}
+
+fn push_insert(delta: &mut Vec<u8>, data: &[u8]) {
+    if data.is_empty() {
+        return;
+    }
+    delta.push(OP_INSERT);
+    delta.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    delta.extend_from_slice(data);
+}
+
+fn take_u64(input: &[u8]) -> Result<(u64, &[u8])> {
+    if input.len() < 8 {
+        return Err(GrufError::parsing_error("Truncated delta"));
+    }
+    let (value, remaining) = input.split_at(8);
+    Ok((u64::from_le_bytes(value.try_into().unwrap()), remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random_data(size: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_apply() {
+        let base = pseudo_random_data(64 * 1024, 1);
+        let mut target = base.clone();
+        // Modify a few bytes, insert and remove some data
+        target[100] ^= 0xFF;
+        target[30_000..30_010].copy_from_slice(&[7; 10]);
+        target.splice(40_000..40_000, pseudo_random_data(500, 2));
+        target.drain(50_000..51_000);
+        target.extend_from_slice(b"trailing data");
+
+        let delta = diff(&base, &target);
+        // Most of the content is shared, the delta should be small
+        assert!(delta.len() < target.len() / 10);
+        assert_eq!(apply(&base, &delta).unwrap(), target);
+
+        let info = read_delta_info(&delta).unwrap();
+        assert_eq!(info.base_size, base.len() as u64);
+        assert_eq!(info.target_size, target.len() as u64);
+        assert_eq!(info.target_sha256, sha256_digest(&target));
+    }
+
+    #[test]
+    fn test_diff_apply_small() {
+        for (base, target) in [
+            (&b""[..], &b""[..]),
+            (&b""[..], &b"new file"[..]),
+            (&b"old file"[..], &b""[..]),
+            (&b"old"[..], &b"new"[..]),
+        ]
+        .iter()
+        {
+            let delta = diff(base, target);
+            assert_eq!(&apply(base, &delta).unwrap(), target);
+        }
+    }
+
+    #[test]
+    fn test_apply_already_patched() {
+        let base = pseudo_random_data(4096, 3);
+        let target = pseudo_random_data(4096, 4);
+        let delta = diff(&base, &target);
+        assert_eq!(apply(&target, &delta).unwrap(), target);
+    }
+
+    #[test]
+    fn test_apply_wrong_base() {
+        let base = pseudo_random_data(4096, 5);
+        let target = pseudo_random_data(4096, 6);
+        let delta = diff(&base, &target);
+        let mut other_base = base.clone();
+        other_base[0] ^= 1;
+        assert!(matches!(
+            apply(&other_base, &delta).unwrap_err(),
+            GrufError::InvalidContent(_)
+        ));
+        // Truncated delta
+        assert!(apply(&base, &delta[..delta.len() - 1]).is_err());
+    }
+}
@@ -217,6 +217,17 @@ pub struct GrfHeader {
     pub version_minor: u32,
 }
 
+impl GrfHeader {
+    /// Read an archive's header, without reading its file table.
+    pub fn read<R: Read>(reader: &mut R) -> Result<Self> {
+        let mut grf_header_buf = [0; GRF_HEADER_SIZE];
+        reader.read_exact(&mut grf_header_buf)?;
+        let (_parser_output, grf_header) = parse_grf_header(&grf_header_buf)
+            .map_err(|_| GrufError::parsing_error("Failed to parse archive (header)"))?;
+        Ok(grf_header)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum GrfTableInfo {
     Uncompressed(GrfTableInfo1),
@@ -503,6 +514,14 @@ mod tests {
             check_small_grf_entries(&mut grf);
         }
 
+        {
+            let grf_path = grf_dir_path.join("200-small.grf");
+            let grf_header = GrfHeader::read(&mut File::open(&grf_path).unwrap()).unwrap();
+            assert_eq!(grf_header.file_count, 8);
+            assert_eq!(grf_header.version_major, 2);
+            assert_eq!(grf_header.version_minor, 0);
+        }
+
         {
             let grf_path = grf_dir_path.join("103-empty.grf");
             let grf = GrfArchive::open(&grf_path).unwrap();
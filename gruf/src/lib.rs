@@ -1,4 +1,5 @@
 mod archive;
+pub mod delta;
 mod error;
 pub mod grf;
 pub mod thor;
@@ -4,14 +4,18 @@ use std::convert::TryFrom;
 use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
 
 use crate::archive::{serialize_as_win1252_str_into, serialize_to_win1252, GenericFileEntry};
+use crate::delta;
 use crate::thor::{
-    ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
+    archive_digest, hex_string, sha256_integrity_lines, DigestedEntry, ThorMode,
+    ARCHIVE_DIGEST_KEY, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE,
+    SHA256_INTEGRITY_PREFIX, THOR_HEADER_MAGIC,
 };
-use crate::Result;
+use crate::{GrufError, Result};
 use crc::crc32::{self, Hasher32};
 use flate2::write::ZlibEncoder;
 use flate2::Compression;
 use serde::Serialize;
+use sha2::{Digest, Sha256};
 
 const THOR_HEADER_FIXED_SIZE: usize = THOR_HEADER_MAGIC.len() + 0x8;
 
@@ -22,11 +26,14 @@ pub struct ThorArchiveBuilder<W: Write + Seek> {
     use_grf_merging: bool,
     target_grf_name: String,
     include_checksums: bool,
+    include_sha256_checksums: bool,
 }
 
 struct BuilderFileEntry {
     generic: GenericFileEntry,
     checksum: u32,
+    sha256: Option<[u8; 32]>,
+    is_delta: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -79,17 +86,75 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
             use_grf_merging,
             target_grf_name,
             include_checksums,
+            include_sha256_checksums: false,
         })
     }
 
-    pub fn append_file_update<R>(&mut self, entry_path: String, mut data: R) -> Result<()>
+    /// Also includes SHA-256 digests of the entries (and of the archive's
+    /// header and file table) in 'data.integrity'. Readers that don't support
+    /// them simply ignore these.
+    pub fn with_sha256_checksums(mut self, include_sha256_checksums: bool) -> Self {
+        self.include_sha256_checksums = include_sha256_checksums;
+        self
+    }
+
+    pub fn append_file_update<R>(&mut self, entry_path: String, data: R) -> Result<()>
+    where
+        R: Read,
+    {
+        self.append_entry(entry_path, data, false)
+    }
+
+    /// Appends an entry that contains a delta between `base_data` and
+    /// `new_data` instead of the whole content of `new_data`.
+    ///
+    /// When applying the patch, the delta is applied against the current
+    /// version of the file (in the game directory or inside the target GRF).
+    pub fn append_file_delta<R1, R2>(
+        &mut self,
+        entry_path: String,
+        mut base_data: R1,
+        mut new_data: R2,
+    ) -> Result<()>
+    where
+        R1: Read,
+        R2: Read,
+    {
+        let mut base_content = Vec::new();
+        base_data
+            .by_ref()
+            .take(delta::MAX_DELTA_FILE_SIZE + 1)
+            .read_to_end(&mut base_content)?;
+        let mut new_content = Vec::new();
+        new_data
+            .by_ref()
+            .take(delta::MAX_DELTA_FILE_SIZE + 1)
+            .read_to_end(&mut new_content)?;
+        if base_content.len() as u64 > delta::MAX_DELTA_FILE_SIZE
+            || new_content.len() as u64 > delta::MAX_DELTA_FILE_SIZE
+        {
+            return Err(GrufError::invalid_content(format!(
+                "Files shipped as deltas must not be bigger than {} bytes",
+                delta::MAX_DELTA_FILE_SIZE
+            )));
+        }
+        let delta = delta::diff(&base_content, &new_content);
+        self.append_entry(entry_path, delta.as_slice(), true)
+    }
+
+    fn append_entry<R>(&mut self, entry_path: String, mut data: R, is_delta: bool) -> Result<()>
     where
         R: Read,
     {
         // Compress it
         let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
-        let (data_size, data_checksum) = if self.include_checksums {
-            copy_and_measure_crc32(data.by_ref(), &mut encoder)?
+        let mut sha256 = if self.include_sha256_checksums {
+            Some(Sha256::new())
+        } else {
+            None
+        };
+        let (data_size, data_checksum) = if self.include_checksums || sha256.is_some() {
+            copy_and_measure_checksums(data.by_ref(), &mut encoder, sha256.as_mut())?
         } else {
             (io::copy(data.by_ref(), &mut encoder)?, 0)
         };
@@ -111,6 +176,8 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
                     entry_type: 1,
                 },
                 checksum: data_checksum,
+                sha256: sha256.map(|hasher| hasher.finalize().into()),
+                is_delta,
             }),
         );
         Ok(())
@@ -127,15 +194,22 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
         self.finished = true;
 
         // Append 'data.integrity' if needed
-        if self.include_checksums {
+        if self.include_checksums || self.include_sha256_checksums {
             self.append_data_integrity()?;
         }
         let (file_table_offset, compressed_table_size) = self.write_file_table()?;
         // Update the header
         self.obj.seek(SeekFrom::Start(0))?;
+        let contains_deltas = self.entries.values().flatten().any(|entry| entry.is_delta);
+        let mode = if contains_deltas {
+            ThorMode::MultipleFilesWithDeltas
+        } else {
+            ThorMode::MultipleFiles
+        };
         write_thor_header(
             &mut self.obj,
             self.use_grf_merging,
+            mode,
             self.entries.len(),
             self.target_grf_name.as_str(),
             compressed_table_size,
@@ -158,8 +232,9 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
                 }
                 Some(entry) => {
                     // File update or file creation
+                    const DELTA_FILE: u8 = 0b10;
                     let thor_file_entry = SerializableThorFileEntryAdd {
-                        flags: 0,
+                        flags: if entry.is_delta { DELTA_FILE } else { 0 },
                         offset: u32::try_from(entry.generic.offset)?,
                         size: entry.generic.size,
                         size_compressed: entry.generic.size_compressed,
@@ -191,13 +266,56 @@ impl<W: Write + Seek> ThorArchiveBuilder<W> {
     }
 
     fn generate_data_integrity(&self) -> Result<Vec<u8>> {
-        let content = self.entries.iter().fold(String::new(), |acc, v| {
-            if let Some(entry) = v.1 {
-                acc + format!("{}=0x{:08x}\r\n", v.0, entry.checksum).as_str()
-            } else {
-                acc
-            }
-        });
+        let mut content = String::new();
+        if self.include_checksums {
+            content = self.entries.iter().fold(content, |acc, v| {
+                if let Some(entry) = v.1 {
+                    acc + format!("{}=0x{:08x}\r\n", v.0, entry.checksum).as_str()
+                } else {
+                    acc
+                }
+            });
+        }
+        if self.include_sha256_checksums {
+            let sha256_lines = sha256_integrity_lines(self.entries.iter().filter_map(|v| {
+                let digest = v.1.as_ref()?.sha256.as_ref()?;
+                Some((v.0.as_str(), digest))
+            }));
+            let archive_digest = archive_digest(
+                self.use_grf_merging,
+                &self.target_grf_name,
+                self.entries
+                    .iter()
+                    .map(|(relative_path, entry)| match entry {
+                        None => DigestedEntry {
+                            relative_path,
+                            is_removed: true,
+                            is_delta: false,
+                            offset: 0,
+                            size: 0,
+                            size_compressed: 0,
+                            sha256: None,
+                        },
+                        Some(entry) => DigestedEntry {
+                            relative_path,
+                            is_removed: false,
+                            is_delta: entry.is_delta,
+                            offset: entry.generic.offset,
+                            size: entry.generic.size as u64,
+                            size_compressed: entry.generic.size_compressed as u64,
+                            sha256: entry.sha256.as_ref(),
+                        },
+                    }),
+            );
+            content += sha256_lines.as_str();
+            content += format!(
+                "{}={}{}\r\n",
+                ARCHIVE_DIGEST_KEY,
+                SHA256_INTEGRITY_PREFIX,
+                hex_string(&archive_digest)
+            )
+            .as_str();
+        }
         serialize_to_win1252(content.as_str())
     }
 }
@@ -212,6 +330,7 @@ impl<W: Write + Seek> Drop for ThorArchiveBuilder<W> {
 fn write_thor_header<W: Write>(
     writer: &mut W,
     use_grf_merging: bool,
+    mode: ThorMode,
     file_count: usize,
     target_grf_name: &str,
     file_table_compressed_size: usize,
@@ -222,7 +341,8 @@ fn write_thor_header<W: Write>(
         magic: THOR_HEADER_MAGIC,
         use_grf_merging,
         file_count: u32::try_from(file_count)?,
-        mode: thor_mode_to_i16(ThorMode::MultipleFiles).unwrap(),
+        mode: thor_mode_to_i16(mode)
+            .ok_or_else(|| GrufError::serialization_error("Invalid THOR header mode"))?,
     };
     let table_desc = SerializableFileTableDesc {
         file_table_compressed_size: u32::try_from(file_table_compressed_size)?,
@@ -238,6 +358,7 @@ fn thor_mode_to_i16(mode: ThorMode) -> Option<i16> {
     match mode {
         ThorMode::SingleFile => Some(33),
         ThorMode::MultipleFiles => Some(48),
+        ThorMode::MultipleFilesWithDeltas => Some(49),
         ThorMode::Invalid => None,
     }
 }
@@ -264,10 +385,11 @@ fn serialize_thor_slice_into<W: Write>(mut writer: W, slice: &[u8]) -> Result<()
     Ok(())
 }
 
-/// Computes a CRC32 checksum from a reader.
-fn copy_and_measure_crc32<R: ?Sized, W: ?Sized>(
+/// Computes a CRC32 checksum (and optionally a SHA-256 digest) from a reader.
+fn copy_and_measure_checksums<R: ?Sized, W: ?Sized>(
     reader: &mut R,
     writer: &mut W,
+    mut sha256: Option<&mut Sha256>,
 ) -> Result<(u64, u32)>
 where
     R: Read,
@@ -285,6 +407,9 @@ where
             Err(e) => return Err(e.into()),
         };
         digest.write(&buf[..len]);
+        if let Some(sha256) = sha256.as_mut() {
+            sha256.update(&buf[..len]);
+        }
         writer.write_all(&buf[..len])?;
         written += len as u64;
     }
@@ -294,7 +419,7 @@ where
 mod tests {
     use super::*;
     use crate::thor::{ThorArchive, ThorFileEntry};
-    use std::fs::File;
+    use std::fs::{self, File};
     use tempfile::tempdir;
 
     #[test]
@@ -391,6 +516,57 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_append_file_delta() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("builder.thor");
+        let base_content: Vec<u8> = (0..4096).map(|x| (x % 251) as u8).collect();
+        let mut new_content = base_content.clone();
+        new_content[2000..2004].copy_from_slice(b"THOR");
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, true).unwrap();
+            builder
+                .append_file_delta(
+                    "data\\test1".to_string(),
+                    base_content.as_slice(),
+                    new_content.as_slice(),
+                )
+                .unwrap();
+            builder
+                .append_file_update("data\\test2".to_string(), &[1, 2, 3][..])
+                .unwrap();
+        }
+        {
+            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+            assert!(thor_archive.is_valid().unwrap());
+            assert!(thor_archive.get_file_entry("data\\test1").unwrap().is_delta);
+            assert!(!thor_archive.get_file_entry("data\\test2").unwrap().is_delta);
+            let delta = thor_archive.read_file_content("data\\test1").unwrap();
+            assert!(delta.len() < new_content.len());
+            assert_eq!(delta::apply(&base_content, &delta).unwrap(), new_content);
+            // The delta's header can be read without decompressing the rest
+            let delta_header = thor_archive
+                .read_file_prefix("data\\test1", delta::DELTA_HEADER_SIZE)
+                .unwrap();
+            assert_eq!(delta_header, delta[..delta::DELTA_HEADER_SIZE]);
+            assert_eq!(
+                delta::read_delta_info(&delta_header).unwrap().target_size,
+                new_content.len() as u64
+            );
+        }
+        {
+            // Archives with deltas use their own mode, which older readers
+            // reject, and deltas aren't accepted in regular archives
+            const MODE_OFFSET: usize = THOR_HEADER_MAGIC.len() + 5;
+            let mut content = fs::read(&output_path).unwrap();
+            assert_eq!(content[MODE_OFFSET..MODE_OFFSET + 2], 49_i16.to_le_bytes());
+            content[MODE_OFFSET..MODE_OFFSET + 2].copy_from_slice(&48_i16.to_le_bytes());
+            fs::write(&output_path, content).unwrap();
+            assert!(ThorArchive::open(&output_path).is_err());
+        }
+    }
+
     #[test]
     fn test_data_integrity() {
         let temp_dir = tempdir().unwrap();
@@ -414,4 +590,64 @@ mod tests {
             assert!(thor_archive.is_valid().unwrap());
         }
     }
+
+    #[test]
+    fn test_sha256_data_integrity() {
+        let temp_dir = tempdir().unwrap();
+        let output_path = temp_dir.path().join("builder.thor");
+        let content: Vec<u8> = (0..4096).map(|x| (x % 251) as u8).collect();
+        {
+            let output_file = File::create(&output_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(output_file, false, None, true)
+                .unwrap()
+                .with_sha256_checksums(true);
+            builder
+                .append_file_update("data\\test1".to_string(), content.as_slice())
+                .unwrap();
+            builder
+                .append_file_update("data\\test2".to_string(), &[5, 6][..])
+                .unwrap();
+            builder.append_file_removal("data\\test3".to_string());
+        }
+        let test1_offset = {
+            let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+            let integrity_data = thor_archive.read_file_content(INTEGRITY_FILE_NAME).unwrap();
+            let integrity_data = String::from_utf8(integrity_data).unwrap();
+            assert!(integrity_data.contains("data\\test2=0x"));
+            assert!(integrity_data.contains(&format!(
+                "data\\test2=sha256:{}\r\n",
+                hex_string(&Sha256::digest([5, 6]))
+            )));
+            assert!(integrity_data.contains("*=sha256:"));
+
+            let report = thor_archive.check_integrity().unwrap();
+            assert!(report.is_valid());
+            assert_eq!(report.checked_entries, 2);
+            assert_eq!(report.archive_digest_valid, Some(true));
+            thor_archive.get_file_entry("data\\test1").unwrap().offset
+        };
+        // The archive's digest covers the header and the file table
+        {
+            let mut content = fs::read(&output_path).unwrap();
+            content[THOR_HEADER_MAGIC.len()] = 1; // Use GRF merging
+            let mut thor_archive = ThorArchive::new(Cursor::new(content)).unwrap();
+            let report = thor_archive.check_integrity().unwrap();
+            assert!(report.corrupt_entries.is_empty());
+            assert_eq!(report.archive_digest_valid, Some(false));
+        }
+        // Corrupt the first entry
+        {
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(&output_path)
+                .unwrap();
+            file.seek(SeekFrom::Start(test1_offset + 4)).unwrap();
+            file.write_all(&[0xFF; 8]).unwrap();
+        }
+        let mut thor_archive = ThorArchive::open(&output_path).unwrap();
+        let report = thor_archive.check_integrity().unwrap();
+        assert!(!report.is_valid());
+        assert_eq!(report.corrupt_entries, vec!["data\\test1".to_string()]);
+        assert!(report.missing_entries.is_empty());
+    }
 }
@@ -3,15 +3,103 @@ pub mod reader;
 
 pub use builder::ThorArchiveBuilder;
 pub use reader::{
-    patch_list_from_string, ThorArchive, ThorFileEntry, ThorPatchInfo, ThorPatchList,
+    patch_list_from_string, ThorArchive, ThorFileEntry, ThorIntegrityReport, ThorPatchInfo,
+    ThorPatchList,
 };
+use sha2::{Digest, Sha256};
 
 const THOR_HEADER_MAGIC: &[u8; 24] = b"ASSF (C) 2007 Aeomin DEV";
 const INTEGRITY_FILE_NAME: &str = "data.integrity";
+/// Command that the patcher runs once the archive's been applied (if allowed)
+pub const HOOK_FILE_NAME: &str = "kpatcher.hook";
+// SHA-256 digests are stored in 'data.integrity' as `<path>=sha256:<hex>`,
+// which readers that only know CRC32 checksums skip
+const SHA256_INTEGRITY_PREFIX: &str = "sha256:";
+// Key of the archive's digest (see `archive_digest`)
+const ARCHIVE_DIGEST_KEY: &str = "*";
 const MULTIPLE_FILES_TABLE_DESC_SIZE: usize = 2 * std::mem::size_of::<i32>();
+
+/// Serializes SHA-256 integrity lines, sorted by path.
+fn sha256_integrity_lines<'a, I>(entries: I) -> String
+where
+    I: Iterator<Item = (&'a str, &'a [u8; 32])>,
+{
+    let mut entries: Vec<_> = entries.collect();
+    entries.sort_unstable_by_key(|(path, _)| *path);
+    entries
+        .into_iter()
+        .fold(String::new(), |acc, (path, digest)| {
+            acc + format!(
+                "{}={}{}\r\n",
+                path,
+                SHA256_INTEGRITY_PREFIX,
+                hex_string(digest)
+            )
+            .as_str()
+        })
+}
+
+/// Entry of an archive's file table, as covered by the archive's digest.
+struct DigestedEntry<'a> {
+    relative_path: &'a str,
+    is_removed: bool,
+    is_delta: bool,
+    offset: u64,
+    size: u64,
+    size_compressed: u64,
+    sha256: Option<&'a [u8; 32]>,
+}
+
+/// Computes the archive's digest. It covers the header's GRF settings and
+/// every entry of the file table (removed ones included) with its flags,
+/// location, sizes and SHA-256 digest. 'data.integrity', which stores the
+/// digest, is left out.
+fn archive_digest<'a, I>(use_grf_merging: bool, target_grf_name: &str, entries: I) -> [u8; 32]
+where
+    I: Iterator<Item = DigestedEntry<'a>>,
+{
+    let mut entries: Vec<_> = entries
+        .filter(|entry| entry.relative_path != INTEGRITY_FILE_NAME)
+        .collect();
+    entries.sort_unstable_by_key(|entry| entry.relative_path);
+    let mut hasher = Sha256::new();
+    hasher.update(format!(
+        "use_grf_merging={} target_grf_name={:?}\r\n",
+        use_grf_merging, target_grf_name
+    ));
+    for entry in entries {
+        let line = if entry.is_removed {
+            format!("{:?} removed\r\n", entry.relative_path)
+        } else {
+            format!(
+                "{:?} delta={} offset={} size={} size_compressed={} sha256={}\r\n",
+                entry.relative_path,
+                entry.is_delta,
+                entry.offset,
+                entry.size,
+                entry.size_compressed,
+                entry
+                    .sha256
+                    .map(|digest| hex_string(digest))
+                    .unwrap_or_default()
+            )
+        };
+        hasher.update(line);
+    }
+    hasher.finalize().into()
+}
+
+fn hex_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum ThorMode {
     SingleFile,
     MultipleFiles,
+    // Same layout as `MultipleFiles`, used when some entries are deltas so
+    // that patchers which don't know deltas reject the archive instead of
+    // writing deltas over the game's files
+    MultipleFilesWithDeltas,
     Invalid,
 }
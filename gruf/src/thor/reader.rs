@@ -1,12 +1,15 @@
 use std::boxed::Box;
 use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::Path;
 
 use crate::thor::{
-    ThorMode, INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, THOR_HEADER_MAGIC,
+    archive_digest, DigestedEntry, ThorMode, ARCHIVE_DIGEST_KEY, HOOK_FILE_NAME,
+    INTEGRITY_FILE_NAME, MULTIPLE_FILES_TABLE_DESC_SIZE, SHA256_INTEGRITY_PREFIX,
+    THOR_HEADER_MAGIC,
 };
 use crate::{GrufError, Result};
 use crc::crc32;
@@ -15,6 +18,7 @@ use encoding::DecoderTrap;
 use flate2::read::ZlibDecoder;
 use nom::number::complete::{le_i16, le_i32, le_u32, le_u8};
 use nom::*;
+use sha2::{Digest, Sha256};
 
 // Packed structs' sizes in bytes
 const MAX_FILE_NAME_SIZE: usize = 256;
@@ -24,6 +28,10 @@ const SINGLE_FILE_ENTRY_MAX_SIZE: usize = 9 + MAX_FILE_NAME_SIZE;
 pub type ThorPatchList = Vec<ThorPatchInfo>;
 
 /// Parses Thor's plist.txt file
+///
+/// Lines follow the `<index> <file_name>` format and may contain optional
+/// attributes after the file name, which other patchers ignore:
+/// `size=<bytes> sha256=<hex digest> grf=<target GRF> desc="<text>" critical`
 pub fn patch_list_from_string(content: &str) -> ThorPatchList {
     let vec_lines: Vec<&str> = content.lines().collect();
     let mut sorted_patch_list: ThorPatchList = vec_lines
@@ -35,19 +43,25 @@ pub fn patch_list_from_string(content: &str) -> ThorPatchList {
     sorted_patch_list
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ThorPatchInfo {
     pub index: usize,
     pub file_name: String,
+    pub size: Option<u64>,           // Size of the patch file in bytes
+    pub sha256: Option<[u8; 32]>,    // SHA-256 digest of the patch file
+    pub target_grf: Option<String>,  // GRF to patch instead of the patch's target
+    pub description: Option<String>, // Human-readable description
+    pub critical: bool,              // The game shouldn't be started without this patch
 }
 
 impl ThorPatchInfo {
-    /// Parses a line to extract patch index and patch file name.
+    /// Parses a line to extract patch index, patch file name and optional
+    /// attributes.
     /// Returns a PatchInfo struct in case of success.
     /// Returns None in case of failure
     fn from_string(line: &str) -> Option<ThorPatchInfo> {
-        let words: Vec<_> = line.trim().split_whitespace().collect();
-        let index_str = words.get(0)?;
+        let words = split_patch_list_line(line);
+        let index_str = words.first()?;
         let index = match str::parse(index_str) {
             Ok(v) => v,
             Err(_) => {
@@ -55,20 +69,97 @@ impl ThorPatchInfo {
             }
         };
         let file_name = words.get(1)?;
-        Some(ThorPatchInfo {
+        let mut patch_info = ThorPatchInfo {
             index,
-            file_name: (*file_name).to_string(),
-        })
+            file_name: file_name.clone(),
+            ..Default::default()
+        };
+        // Unknown and invalid attributes are ignored
+        for word in &words[2..] {
+            match word.split_once('=') {
+                Some(("size", value)) => patch_info.size = value.parse().ok(),
+                Some(("sha256", value)) => patch_info.sha256 = parse_sha256_digest(value),
+                Some(("grf", value)) if !value.is_empty() => {
+                    patch_info.target_grf = Some(value.to_string())
+                }
+                Some(("desc", value)) => patch_info.description = Some(value.to_string()),
+                None if word == "critical" => patch_info.critical = true,
+                _ => {}
+            }
+        }
+        Some(patch_info)
     }
 }
 
+/// Formats the patch info as a plist.txt line.
+impl fmt::Display for ThorPatchInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.index, self.file_name)?;
+        if let Some(size) = self.size {
+            write!(f, " size={}", size)?;
+        }
+        if let Some(sha256) = &self.sha256 {
+            write!(f, " sha256=")?;
+            for byte in sha256 {
+                write!(f, "{:02x}", byte)?;
+            }
+        }
+        if let Some(target_grf) = &self.target_grf {
+            write!(f, " grf={}", target_grf)?;
+        }
+        if let Some(description) = &self.description {
+            write!(f, " desc=\"{}\"", description.replace('"', "'"))?;
+        }
+        if self.critical {
+            write!(f, " critical")?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits a plist.txt line on whitespaces, except between double quotes.
+fn split_patch_list_line(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current_word: Option<String> = None;
+    let mut in_quotes = false;
+    for c in line.chars() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                current_word.get_or_insert_with(String::new);
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if let Some(word) = current_word.take() {
+                    words.push(word);
+                }
+            }
+            c => current_word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if let Some(word) = current_word {
+        words.push(word);
+    }
+    words
+}
+
+fn parse_sha256_digest(hex_digest: &str) -> Option<[u8; 32]> {
+    if hex_digest.len() != 64 || !hex_digest.is_ascii() {
+        return None;
+    }
+    let mut digest = [0_u8; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex_digest[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(digest)
+}
+
 fn parse_data_integrity_info(data: &str) -> HashMap<&str, u32> {
     let vec_lines: Vec<_> = data.lines().collect();
     vec_lines
         .into_iter()
         .filter_map(|line| {
             let words: Vec<&str> = line.trim().split('=').collect();
-            let file_name = words.get(0)?;
+            let file_name = words.first()?;
             let hash_str = words.get(1)?;
             let hash = match u32::from_str_radix(hash_str.trim_start_matches("0x"), 16) {
                 Ok(v) => v,
@@ -79,6 +170,43 @@ fn parse_data_integrity_info(data: &str) -> HashMap<&str, u32> {
         .collect()
 }
 
+/// Parses the SHA-256 lines of 'data.integrity'.
+///
+/// Returns the entries' digests and the archive's digest, if present.
+fn parse_sha256_integrity_info(data: &str) -> (HashMap<&str, [u8; 32]>, Option<[u8; 32]>) {
+    let mut archive_digest = None;
+    let entries = data
+        .lines()
+        .filter_map(|line| {
+            let (file_name, digest_str) = line.trim().split_once('=')?;
+            let digest = parse_sha256_digest(digest_str.strip_prefix(SHA256_INTEGRITY_PREFIX)?)?;
+            if file_name == ARCHIVE_DIGEST_KEY {
+                archive_digest = Some(digest);
+                return None;
+            }
+            Some((file_name, digest))
+        })
+        .collect();
+    (entries, archive_digest)
+}
+
+/// Result of an archive's integrity check.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ThorIntegrityReport {
+    pub checked_entries: usize,
+    pub corrupt_entries: Vec<String>, // Entries whose content doesn't match
+    pub missing_entries: Vec<String>, // Entries listed in 'data.integrity' but not in the archive
+    pub archive_digest_valid: Option<bool>, // None without SHA-256 digest, false if inconsistent
+}
+
+impl ThorIntegrityReport {
+    pub fn is_valid(&self) -> bool {
+        self.corrupt_entries.is_empty()
+            && self.missing_entries.is_empty()
+            && self.archive_digest_valid != Some(false)
+    }
+}
+
 #[derive(Debug)]
 pub struct ThorArchive<R: ?Sized> {
     obj: Box<R>,
@@ -155,6 +283,29 @@ impl<R: Read + Seek> ThorArchive<R> {
         Ok(decompressed_content)
     }
 
+    /// Decompresses only the first `len` bytes of an entry's content (or the
+    /// whole content if it's shorter).
+    pub fn read_file_prefix<S: AsRef<str> + Hash>(
+        &mut self,
+        file_path: S,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let file_entry = self
+            .get_file_entry(file_path)
+            .ok_or(GrufError::EntryNotFound)?
+            .clone();
+        if file_entry.size_compressed == 0 {
+            return Ok(vec![]);
+        }
+
+        self.obj.seek(SeekFrom::Start(file_entry.offset))?;
+        let file_chunk = self.obj.by_ref().take(file_entry.size_compressed as u64);
+        let mut decoder = ZlibDecoder::new(file_chunk).take(len as u64);
+        let mut prefix = Vec::with_capacity(len.min(file_entry.size));
+        decoder.read_to_end(&mut prefix)?;
+        Ok(prefix)
+    }
+
     pub fn extract_file<S: AsRef<str> + Hash>(
         &mut self,
         file_path: S,
@@ -175,19 +326,65 @@ impl<R: Read + Seek> ThorArchive<R> {
 
     /// Checks if the container has been unintentionnaly corrupted
     pub fn is_valid(&mut self) -> Result<bool> {
+        Ok(self.check_integrity()?.is_valid())
+    }
+
+    /// Checks the container's entries against 'data.integrity' and reports
+    /// the ones that are corrupt or missing.
+    ///
+    /// SHA-256 digests are used instead of CRC32 checksums when available.
+    pub fn check_integrity(&mut self) -> Result<ThorIntegrityReport> {
         let integrity_data = self.read_file_content(INTEGRITY_FILE_NAME)?;
         let integrity_data_as_str = string_from_win_1252(integrity_data.as_slice())?;
-        let integrity_info = parse_data_integrity_info(integrity_data_as_str.as_str());
-        for (file_path, hash) in integrity_info {
-            let file_content = match self.read_file_content(file_path) {
-                Ok(v) => v,
-                Err(_) => return Ok(false),
+        let crc32_info = parse_data_integrity_info(integrity_data_as_str.as_str());
+        let (sha256_info, expected_archive_digest) =
+            parse_sha256_integrity_info(integrity_data_as_str.as_str());
+
+        let mut report = ThorIntegrityReport::default();
+        if let Some(expected_digest) = expected_archive_digest {
+            let header = &self.container.header;
+            let digest = archive_digest(
+                header.use_grf_merging,
+                &header.target_grf_name,
+                self.container.entries.values().map(|entry| DigestedEntry {
+                    relative_path: &entry.relative_path,
+                    is_removed: entry.is_removed,
+                    is_delta: entry.is_delta,
+                    offset: entry.offset,
+                    size: entry.size as u64,
+                    size_compressed: entry.size_compressed as u64,
+                    sha256: sha256_info.get(entry.relative_path.as_str()),
+                }),
+            );
+            report.archive_digest_valid = Some(digest == expected_digest);
+        }
+        let mut file_paths: Vec<&str> = crc32_info
+            .keys()
+            .chain(sha256_info.keys())
+            .copied()
+            .collect();
+        file_paths.sort_unstable();
+        file_paths.dedup();
+        for file_path in file_paths {
+            report.checked_entries += 1;
+            if self.get_file_entry(file_path).is_none() {
+                report.missing_entries.push(file_path.to_string());
+                continue;
+            }
+            let is_entry_valid = match self.read_file_content(file_path) {
+                Err(_) => false,
+                Ok(file_content) => match sha256_info.get(file_path) {
+                    Some(expected_digest) => {
+                        Sha256::digest(&file_content)[..] == expected_digest[..]
+                    }
+                    None => crc32::checksum_ieee(&file_content) == crc32_info[file_path],
+                },
             };
-            if crc32::checksum_ieee(file_content.as_slice()) != hash {
-                return Ok(false);
+            if !is_entry_valid {
+                report.corrupt_entries.push(file_path.to_string());
             }
         }
-        Ok(true)
+        Ok(report)
     }
 }
 
@@ -229,12 +426,13 @@ pub struct ThorFileEntry {
     pub size: usize,
     pub relative_path: String,
     pub is_removed: bool,
+    pub is_delta: bool, // Content is a delta against the current file
     pub offset: u64,
 }
 
 impl ThorFileEntry {
     pub fn is_internal(&self) -> bool {
-        self.relative_path == INTEGRITY_FILE_NAME
+        self.relative_path == INTEGRITY_FILE_NAME || self.relative_path == HOOK_FILE_NAME
     }
 }
 
@@ -254,6 +452,7 @@ fn i16_to_thor_mode(i: i16) -> ThorMode {
     match i {
         33 => ThorMode::SingleFile,
         48 => ThorMode::MultipleFiles,
+        49 => ThorMode::MultipleFilesWithDeltas,
         _ => ThorMode::Invalid,
     }
 }
@@ -264,6 +463,12 @@ fn is_file_removed(flags: u8) -> bool {
     (flags & 0b1) == 1
 }
 
+/// Checks entries' flags
+/// If the second bit is 1, the entry contains a delta (see `crate::delta`)
+fn is_file_delta(flags: u8) -> bool {
+    (flags & 0b10) != 0
+}
+
 named!(parse_thor_header<&[u8], ThorHeader>,
     do_parse!(
         tag!(THOR_HEADER_MAGIC)
@@ -329,6 +534,7 @@ named!(parse_single_file_entry<&[u8], ThorFileEntry>,
             size: size as usize,
             relative_path,
             is_removed: false,
+            is_delta: false,
             offset: 0, // This field is set outside the parser
         }
     )
@@ -362,6 +568,7 @@ named!(parse_multiple_files_entry<&[u8], ThorFileEntry>,
             size: size as usize,
             relative_path,
             is_removed: is_file_removed(flags),
+            is_delta: !is_file_removed(flags) && is_file_delta(flags),
             offset: offset as u64,
         }
     )
@@ -401,7 +608,7 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
                     .collect(),
             })
         }
-        ThorMode::MultipleFiles => {
+        ThorMode::MultipleFiles | ThorMode::MultipleFilesWithDeltas => {
             let (output, table) = parse_multiple_files_table(output)
                 .map_err(|_| GrufError::parsing_error("Failed to parse THOR file table"))?;
             let consumed_bytes = output.as_ptr() as u64 - thor_header_buf.as_ptr() as u64;
@@ -428,6 +635,13 @@ pub fn parse_thor_patch<R: Seek + Read>(reader: &mut R) -> Result<ThorContainer>
                     entries
                 }
             };
+            if header.mode != ThorMode::MultipleFilesWithDeltas
+                && entries.values().any(|entry| entry.is_delta)
+            {
+                return Err(GrufError::parsing_error(
+                    "THOR archive contains delta entries but doesn't declare them",
+                ));
+            }
             Ok(ThorContainer {
                 header,
                 table: ThorTable::MultipleFiles(table),
@@ -476,6 +690,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_patch_list_with_attributes() {
+        let sha256_hex = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+        let plist_content = format!(
+            "2 data.thor size=1024 sha256={} grf=rdata.grf desc=\"New maps\" critical
+1 old.thor size=invalid unknown=value sha256=00",
+            sha256_hex
+        );
+        let thor_patch_list = patch_list_from_string(&plist_content);
+        assert_eq!(thor_patch_list.len(), 2);
+
+        let old_patch = &thor_patch_list[0];
+        assert_eq!(old_patch.index, 1);
+        assert_eq!(old_patch.file_name, "old.thor");
+        assert_eq!(old_patch.size, None);
+        assert_eq!(old_patch.sha256, None);
+        assert!(!old_patch.critical);
+
+        let new_patch = &thor_patch_list[1];
+        assert_eq!(new_patch.size, Some(1024));
+        assert_eq!(new_patch.sha256.unwrap()[..2], [0x9f, 0x86]);
+        assert_eq!(new_patch.target_grf.as_deref(), Some("rdata.grf"));
+        assert_eq!(new_patch.description.as_deref(), Some("New maps"));
+        assert!(new_patch.critical);
+
+        // Formatting a patch info gives back the same line
+        let line = new_patch.to_string();
+        assert!(line.contains(sha256_hex));
+        assert_eq!(patch_list_from_string(&line)[0], *new_patch);
+    }
+
     #[test]
     fn test_open_empty_container() {
         let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
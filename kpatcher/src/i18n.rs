@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+
+use crate::patcher::UserSettings;
+
+/// Messages of each language, English being used for missing messages
+const CATALOGS: [(Language, &str); 3] = [
+    (Language::English, include_str!("../locales/en.yml")),
+    (
+        Language::BrazilianPortuguese,
+        include_str!("../locales/pt-BR.yml"),
+    ),
+    (Language::Spanish, include_str!("../locales/es.yml")),
+];
+
+static LANGUAGE: RwLock<Language> = RwLock::new(Language::English);
+static MESSAGES: OnceLock<HashMap<Language, HashMap<String, String>>> = OnceLock::new();
+
+/// Languages the patcher's messages are translated to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    English,
+    BrazilianPortuguese,
+    Spanish,
+}
+
+impl Language {
+    /// IETF language tag, given to the UI.
+    pub fn tag(self) -> &'static str {
+        match self {
+            Language::English => "en",
+            Language::BrazilianPortuguese => "pt-BR",
+            Language::Spanish => "es",
+        }
+    }
+
+    /// Parses a language tag (`pt-BR`) or a POSIX locale (`pt_BR.UTF-8`).
+    /// Regional variants fall back to the language we have.
+    pub fn from_tag(tag: &str) -> Option<Language> {
+        let primary_subtag = tag.split(['-', '_', '.', '@']).next()?;
+        match primary_subtag.to_ascii_lowercase().as_str() {
+            "en" => Some(Language::English),
+            "pt" => Some(Language::BrazilianPortuguese),
+            "es" => Some(Language::Spanish),
+            _ => None,
+        }
+    }
+}
+
+/// Picks the language chosen by the player, then the one given in the
+/// configuration, then the system's.
+pub fn resolve_language(user_language: Option<&str>, config_language: Option<&str>) -> Language {
+    user_language
+        .and_then(Language::from_tag)
+        .or_else(|| config_language.and_then(Language::from_tag))
+        .or_else(|| {
+            sys_locale::get_locale()
+                .as_deref()
+                .and_then(Language::from_tag)
+        })
+        .unwrap_or(Language::English)
+}
+
+/// Selects the language of the messages from the player's settings and
+/// `config_language`.
+pub fn configure(config_language: Option<&str>) {
+    let settings = UserSettings::load();
+    let language = resolve_language(settings.language.as_deref(), config_language);
+    log::info!("Using language '{}'", language.tag());
+    set_language(language);
+}
+
+pub fn set_language(language: Language) {
+    if let Ok(mut current_language) = LANGUAGE.write() {
+        *current_language = language;
+    }
+}
+
+pub fn language() -> Language {
+    LANGUAGE
+        .read()
+        .map(|language| *language)
+        .unwrap_or(Language::English)
+}
+
+/// Returns the message identified by `key` in the current language.
+pub fn tr(key: &str) -> String {
+    tr_in(language(), key, &[])
+}
+
+/// Same as `tr`, with the `{name}` placeholders of the message replaced by
+/// the given values.
+pub fn tr_with(key: &str, args: &[(&str, &str)]) -> String {
+    tr_in(language(), key, args)
+}
+
+pub fn tr_in(language: Language, key: &str, args: &[(&str, &str)]) -> String {
+    let messages = MESSAGES.get_or_init(load_catalogs);
+    let message = messages
+        .get(&language)
+        .and_then(|catalog| catalog.get(key))
+        .or_else(|| messages.get(&Language::English)?.get(key));
+    let mut message = match message {
+        Some(message) => message.clone(),
+        None => {
+            log::warn!("Missing message '{}'", key);
+            return key.to_string();
+        }
+    };
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), value);
+    }
+    message
+}
+
+fn load_catalogs() -> HashMap<Language, HashMap<String, String>> {
+    CATALOGS
+        .iter()
+        .map(|(language, catalog)| {
+            let messages = serde_yaml::from_str(catalog).unwrap_or_else(|e| {
+                log::error!("Invalid '{}' messages: {}", language.tag(), e);
+                HashMap::new()
+            });
+            (*language, messages)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholders(message: &str) -> Vec<&str> {
+        let mut placeholders: Vec<&str> = message
+            .split('{')
+            .skip(1)
+            .filter_map(|part| part.split('}').next())
+            .collect();
+        placeholders.sort_unstable();
+        placeholders
+    }
+
+    #[test]
+    fn test_language_from_tag() {
+        assert_eq!(
+            Language::from_tag("pt-BR"),
+            Some(Language::BrazilianPortuguese)
+        );
+        assert_eq!(
+            Language::from_tag("pt_BR.UTF-8"),
+            Some(Language::BrazilianPortuguese)
+        );
+        assert_eq!(Language::from_tag("ES"), Some(Language::Spanish));
+        assert_eq!(Language::from_tag("en_US"), Some(Language::English));
+        assert_eq!(Language::from_tag("fr-FR"), None);
+        assert_eq!(Language::from_tag(""), None);
+        assert_eq!(
+            resolve_language(Some("es"), Some("pt-BR")),
+            Language::Spanish
+        );
+        assert_eq!(
+            resolve_language(Some("kr"), Some("pt-BR")),
+            Language::BrazilianPortuguese
+        );
+    }
+
+    #[test]
+    fn test_catalogs_are_complete() {
+        let messages = load_catalogs();
+        let english = &messages[&Language::English];
+        assert!(!english.is_empty());
+        for (language, _) in CATALOGS {
+            let catalog = &messages[&language];
+            let mut keys: Vec<&String> = catalog.keys().collect();
+            let mut english_keys: Vec<&String> = english.keys().collect();
+            keys.sort_unstable();
+            english_keys.sort_unstable();
+            assert_eq!(keys, english_keys, "{}", language.tag());
+            for (key, message) in catalog {
+                assert_eq!(
+                    placeholders(message),
+                    placeholders(&english[key]),
+                    "{}: {}",
+                    language.tag(),
+                    key
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(
+            tr_in(
+                Language::BrazilianPortuguese,
+                "error.patch_failed",
+                &[("patch", "1.thor")]
+            ),
+            "Falha ao aplicar o patch '1.thor'"
+        );
+        assert_eq!(
+            tr_in(Language::Spanish, "missing.message", &[]),
+            "missing.message"
+        );
+    }
+}
@@ -0,0 +1,328 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Context, Result};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Number of attempts made to reach the running instance, which may still be
+/// starting up
+const FORWARD_ATTEMPTS: u32 = 10;
+const FORWARD_RETRY_DELAY: Duration = Duration::from_millis(200);
+/// Time given to other instances to send their message
+#[cfg(not(windows))]
+const MESSAGE_READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Message sent to the running instance when the patcher is started again.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InstanceMessage {
+    Activate,            // Bring the window to the front
+    ApplyPatch(PathBuf), // Same as `Activate`, and apply a manual patch
+}
+
+/// Held by the only running instance of the patcher for a working directory.
+pub struct InstanceGuard {
+    _lock_file: fs::File,
+    listener: Listener,
+}
+
+impl InstanceGuard {
+    /// Calls `handler` with the messages sent by instances started later, from
+    /// a dedicated thread.
+    pub fn listen<F>(self, handler: F) -> std::thread::JoinHandle<()>
+    where
+        F: Fn(InstanceMessage) + Send + 'static,
+    {
+        let (message_tx, message_rx) = flume::unbounded();
+        std::thread::spawn(move || {
+            // The lock is released when the thread stops listening
+            let InstanceGuard {
+                _lock_file,
+                mut listener,
+            } = self;
+            loop {
+                match listener.accept() {
+                    Err(e) => {
+                        log::warn!("Stopped listening for other instances: {:#}", e);
+                        return;
+                    }
+                    // Messages are read on their own thread, so that a
+                    // connection that stays silent doesn't block the others
+                    Ok(mut connection) => {
+                        let message_tx = message_tx.clone();
+                        std::thread::spawn(move || match read_message(&mut connection) {
+                            Err(e) => log::warn!("Invalid message from another instance: {:#}", e),
+                            Ok(message) => {
+                                let _ = message_tx.send(message);
+                            }
+                        });
+                    }
+                }
+            }
+        });
+        std::thread::spawn(move || {
+            for message in message_rx {
+                handler(message);
+            }
+        })
+    }
+}
+
+/// Becomes the running instance of the patcher for `working_directory`.
+/// Returns `None` if another instance is already running.
+pub fn acquire_instance(working_directory: &Path) -> Result<Option<InstanceGuard>> {
+    let endpoint = instance_endpoint(working_directory)?;
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_file_path(&endpoint)?)
+        .context("Failed to create the instance lock file")?;
+    if lock_file.try_lock_exclusive().is_err() {
+        return Ok(None);
+    }
+    let listener = Listener::bind(&endpoint).context("Failed to listen for other instances")?;
+    Ok(Some(InstanceGuard {
+        _lock_file: lock_file,
+        listener,
+    }))
+}
+
+/// Sends `message` to the instance running for `working_directory`.
+pub fn forward_to_running_instance(
+    working_directory: &Path,
+    message: &InstanceMessage,
+) -> Result<()> {
+    let endpoint = instance_endpoint(working_directory)?;
+    let content = serde_json::to_vec(message)?;
+    let mut attempt = 1;
+    loop {
+        match connect(&endpoint) {
+            Ok(mut connection) => {
+                // Only the foreground process can let the running instance
+                // bring its window to the front
+                #[cfg(windows)]
+                unsafe {
+                    use winapi::um::winuser::{AllowSetForegroundWindow, ASFW_ANY};
+                    AllowSetForegroundWindow(ASFW_ANY);
+                }
+                return connection
+                    .write_all(&content)
+                    .context("Failed to send message to the running instance");
+            }
+            Err(e) if attempt >= FORWARD_ATTEMPTS => {
+                return Err(anyhow!("Failed to reach the running instance: {}", e));
+            }
+            Err(_) => {
+                attempt += 1;
+                std::thread::sleep(FORWARD_RETRY_DELAY);
+            }
+        }
+    }
+}
+
+fn read_message(connection: &mut impl Read) -> Result<InstanceMessage> {
+    let mut content = Vec::new();
+    connection.read_to_end(&mut content)?;
+    serde_json::from_slice(&content).context("Failed to deserialize message")
+}
+
+/// Name shared by the instances started by the current user in
+/// `working_directory`.
+fn instance_endpoint(working_directory: &Path) -> Result<String> {
+    let working_directory =
+        fs::canonicalize(working_directory).context("Failed to resolve the working directory")?;
+    // Named pipes are shared by all users on Windows, the runtime directory
+    // is specific to the current user
+    let mut hasher = Sha256::new();
+    hasher.update(runtime_dir()?.to_string_lossy().as_bytes());
+    hasher.update([0]);
+    hasher.update(working_directory.to_string_lossy().as_bytes());
+    let digest = hasher.finalize();
+    Ok(format!(
+        "{}-{}",
+        env!("CARGO_PKG_NAME"),
+        hex::encode(&digest[..8])
+    ))
+}
+
+fn lock_file_path(endpoint: &str) -> Result<PathBuf> {
+    Ok(runtime_dir()?.join(format!("{}.lock", endpoint)))
+}
+
+/// Directory that only the current user can write to.
+#[cfg(not(windows))]
+fn runtime_dir() -> Result<PathBuf> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    if let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(runtime_dir));
+    }
+    let uid = unsafe { libc::getuid() };
+    let runtime_dir = std::env::temp_dir().join(format!("{}-{}", env!("CARGO_PKG_NAME"), uid));
+    match fs::DirBuilder::new().mode(0o700).create(&runtime_dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+            return Err(e).context("Failed to create the runtime directory")
+        }
+        _ => {}
+    }
+    // The directory may have been created by someone else
+    let metadata = fs::symlink_metadata(&runtime_dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+        return Err(anyhow!(
+            "'{}' isn't a private directory",
+            runtime_dir.display()
+        ));
+    }
+    Ok(runtime_dir)
+}
+
+/// Directory that only the current user can write to.
+#[cfg(windows)]
+fn runtime_dir() -> Result<PathBuf> {
+    // Located in the user's profile
+    Ok(std::env::temp_dir())
+}
+
+#[cfg(not(windows))]
+fn socket_path(endpoint: &str) -> Result<PathBuf> {
+    Ok(runtime_dir()?.join(format!("{}.sock", endpoint)))
+}
+
+#[cfg(not(windows))]
+struct Listener(std::os::unix::net::UnixListener);
+
+#[cfg(not(windows))]
+impl Listener {
+    fn bind(endpoint: &str) -> Result<Listener> {
+        let path = socket_path(endpoint)?;
+        // Left behind by an instance that didn't exit properly
+        let _ = fs::remove_file(&path);
+        Ok(Listener(std::os::unix::net::UnixListener::bind(path)?))
+    }
+
+    fn accept(&mut self) -> Result<impl Read + Send + 'static> {
+        let connection = self.0.accept()?.0;
+        connection.set_read_timeout(Some(MESSAGE_READ_TIMEOUT))?;
+        Ok(connection)
+    }
+}
+
+#[cfg(not(windows))]
+fn connect(endpoint: &str) -> Result<impl Write> {
+    Ok(std::os::unix::net::UnixStream::connect(socket_path(
+        endpoint,
+    )?)?)
+}
+
+#[cfg(windows)]
+fn pipe_name(endpoint: &str) -> String {
+    format!(r"\\.\pipe\{}", endpoint)
+}
+
+/// Named pipe that accepts one connection per pipe instance.
+#[cfg(windows)]
+struct Listener {
+    name: String,
+    next_instance: fs::File, // Created beforehand, so clients can connect right away
+}
+
+#[cfg(windows)]
+impl Listener {
+    fn bind(endpoint: &str) -> Result<Listener> {
+        use winapi::um::winbase::FILE_FLAG_FIRST_PIPE_INSTANCE;
+
+        let name = pipe_name(endpoint);
+        let next_instance = create_pipe_instance(&name, FILE_FLAG_FIRST_PIPE_INSTANCE)?;
+        Ok(Listener {
+            name,
+            next_instance,
+        })
+    }
+
+    fn accept(&mut self) -> Result<impl Read + Send + 'static> {
+        use std::os::windows::io::AsRawHandle;
+        use winapi::um::namedpipeapi::ConnectNamedPipe;
+        // The client connected before `ConnectNamedPipe` was called
+        const ERROR_PIPE_CONNECTED: i32 = 535;
+
+        let handle = self.next_instance.as_raw_handle();
+        let connected = unsafe { ConnectNamedPipe(handle as _, std::ptr::null_mut()) };
+        if connected == 0 {
+            let e = std::io::Error::last_os_error();
+            if e.raw_os_error() != Some(ERROR_PIPE_CONNECTED) {
+                return Err(e.into());
+            }
+        }
+        let next_instance = create_pipe_instance(&self.name, 0)?;
+        Ok(std::mem::replace(&mut self.next_instance, next_instance))
+    }
+}
+
+#[cfg(windows)]
+fn create_pipe_instance(name: &str, flags: u32) -> Result<fs::File> {
+    use std::ffi::OsStr;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::FromRawHandle;
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::namedpipeapi::CreateNamedPipeW;
+    use winapi::um::winbase::{
+        PIPE_ACCESS_INBOUND, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE,
+        PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+
+    let name: Vec<u16> = OsStr::new(name).encode_wide().chain(Some(0)).collect();
+    let handle = unsafe {
+        CreateNamedPipeW(
+            name.as_ptr(),
+            PIPE_ACCESS_INBOUND | flags,
+            PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+            PIPE_UNLIMITED_INSTANCES,
+            0,
+            4096,
+            0,
+            std::ptr::null_mut(),
+        )
+    };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(unsafe { fs::File::from_raw_handle(handle as _) })
+}
+
+#[cfg(windows)]
+fn connect(endpoint: &str) -> Result<impl Write> {
+    Ok(fs::OpenOptions::new()
+        .write(true)
+        .open(pipe_name(endpoint))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_forward_to_running_instance() {
+        let working_directory = tempdir().unwrap();
+        let guard = acquire_instance(working_directory.path()).unwrap().unwrap();
+        assert!(acquire_instance(working_directory.path())
+            .unwrap()
+            .is_none());
+
+        let (tx, rx) = flume::unbounded();
+        guard.listen(move |message| {
+            let _ = tx.send(message);
+        });
+        // A connection that doesn't send anything doesn't block the others
+        let endpoint = instance_endpoint(working_directory.path()).unwrap();
+        let _silent_connection = connect(&endpoint).unwrap();
+        let message = InstanceMessage::ApplyPatch(PathBuf::from("patch.thor"));
+        forward_to_running_instance(working_directory.path(), &message).unwrap();
+        assert_eq!(rx.recv_timeout(Duration::from_secs(2)), Ok(message));
+    }
+}
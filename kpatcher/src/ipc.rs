@@ -0,0 +1,232 @@
+//! Request/response protocol between the web UI and the patcher.
+//!
+//! The UI sends requests with `kpatcher.call(method, params)`, which posts
+//! `{"kpatcher": 1, "id": 3, "method": "get_version", "params": {}}`. The
+//! patcher replies with `{"kpatcher": 1, "id": 3, "result": ...}` or
+//! `{"kpatcher": 1, "id": 3, "error": {"code": ..., "message": ...}}`, and
+//! pushes events (status, progress, notices) to the listeners registered with
+//! `kpatcher.on(event, callback)`.
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Version of the protocol, given in every request and response.
+/// IMPORTANT: Keep in sync with `BRIDGE_SCRIPT`
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Script injected in the web view, that defines the `kpatcher` object used
+/// by UIs.
+pub const BRIDGE_SCRIPT: &str = r#"
+window.kpatcher = (function () {
+  var nextId = 1, pending = {}, listeners = {};
+  return {
+    protocol: 1,
+    call: function (method, params) {
+      return new Promise(function (resolve, reject) {
+        var id = nextId++;
+        pending[id] = { resolve: resolve, reject: reject };
+        window.ipc.postMessage(JSON.stringify({ kpatcher: 1, id: id, method: method, params: params || {} }));
+      });
+    },
+    on: function (event, callback) {
+      (listeners[event] = listeners[event] || []).push(callback);
+    },
+    off: function (event, callback) {
+      listeners[event] = (listeners[event] || []).filter(function (l) { return l !== callback; });
+    },
+    _reply: function (response) {
+      var request = pending[response.id];
+      if (!request) return;
+      delete pending[response.id];
+      if (response.error) {
+        var error = new Error(response.error.message);
+        error.code = response.error.code;
+        request.reject(error);
+      } else {
+        request.resolve(response.result);
+      }
+    },
+    _emit: function (event, data) {
+      (listeners[event] || []).forEach(function (l) {
+        try { l(data); } catch (e) { console.error(e); }
+      });
+    }
+  };
+})();
+"#;
+
+#[derive(Deserialize, Debug)]
+pub struct IpcRequest {
+    pub kpatcher: u32, // Protocol version
+    pub id: Value,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+}
+
+impl IpcRequest {
+    /// Deserializes the request's parameters.
+    pub fn params<T: DeserializeOwned>(&self) -> Result<T, IpcError> {
+        let params = match &self.params {
+            // Parameters can be omitted
+            Value::Null => Value::Object(Default::default()),
+            params => params.clone(),
+        };
+        serde_json::from_value(params)
+            .map_err(|e| IpcError::new(IpcErrorCode::InvalidParams, e.to_string()))
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IpcErrorCode {
+    InvalidRequest,
+    UnsupportedVersion,
+    MethodNotFound,
+    InvalidParams,
+    UpdateInProgress,
+    ClientRunning,
+    Maintenance,
+    CriticalPatchMissing,
+    Failed,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct IpcError {
+    pub code: IpcErrorCode,
+    pub message: String,
+}
+
+impl IpcError {
+    pub fn new(code: IpcErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+pub type IpcResult = Result<Value, IpcError>;
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum IpcOutcome {
+    Result(Value),
+    Error(IpcError),
+}
+
+#[derive(Serialize, Debug)]
+pub struct IpcResponse {
+    kpatcher: u32,
+    id: Value,
+    #[serde(flatten)]
+    outcome: IpcOutcome,
+}
+
+impl IpcResponse {
+    pub fn new(id: Value, result: IpcResult) -> Self {
+        Self {
+            kpatcher: PROTOCOL_VERSION,
+            id,
+            outcome: match result {
+                Ok(value) => IpcOutcome::Result(value),
+                Err(e) => IpcOutcome::Error(e),
+            },
+        }
+    }
+}
+
+/// Parses a message sent by the UI.
+///
+/// Returns `None` for messages that don't use this protocol (plain commands
+/// and `{function, parameters}` objects), and the response to send back for
+/// invalid requests.
+pub fn parse_request(message: &str) -> Option<Result<IpcRequest, IpcResponse>> {
+    let value: Value = serde_json::from_str(message).ok()?;
+    value.get("method")?;
+    let id = value.get("id").cloned().unwrap_or(Value::Null);
+    let request: IpcRequest = match serde_json::from_value(value) {
+        Err(e) => {
+            let error = IpcError::new(IpcErrorCode::InvalidRequest, e.to_string());
+            return Some(Err(IpcResponse::new(id, Err(error))));
+        }
+        Ok(v) => v,
+    };
+    if request.kpatcher != PROTOCOL_VERSION {
+        let error = IpcError::new(
+            IpcErrorCode::UnsupportedVersion,
+            format!("Protocol version {} isn't supported", request.kpatcher),
+        );
+        return Some(Err(IpcResponse::new(id, Err(error))));
+    }
+    Some(Ok(request))
+}
+
+/// Returns the script that delivers `response` to the UI.
+pub fn reply_script(response: &IpcResponse) -> String {
+    let response = serde_json::to_string(response).unwrap_or_default();
+    format!("if(window.kpatcher)window.kpatcher._reply({});", response)
+}
+
+/// Returns the script that sends an event to the listeners registered by the
+/// UI.
+pub fn event_script<T: Serialize>(event: &str, payload: &T) -> String {
+    let event = serde_json::to_string(event).unwrap_or_default();
+    let payload = serde_json::to_string(payload).unwrap_or_else(|_| "null".to_string());
+    format!(
+        "if(window.kpatcher)window.kpatcher._emit({}, {});",
+        event, payload
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_request() {
+        // Legacy messages
+        assert!(parse_request("play").is_none());
+        assert!(parse_request(r#"{"function": "open_url", "parameters": {}}"#).is_none());
+
+        let request = parse_request(r#"{"kpatcher": 1, "id": 4, "method": "get_version"}"#)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.id, json!(4));
+        assert_eq!(request.method, "get_version");
+        assert_eq!(request.params, Value::Null);
+
+        let response = parse_request(r#"{"kpatcher": 2, "id": "a", "method": "play"}"#)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            serde_json::to_value(&response).unwrap(),
+            json!({
+                "kpatcher": 1,
+                "id": "a",
+                "error": {
+                    "code": "unsupported_version",
+                    "message": "Protocol version 2 isn't supported",
+                },
+            })
+        );
+        let response = parse_request(r#"{"id": 5, "method": 3}"#)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(
+            serde_json::to_value(&response).unwrap()["error"]["code"],
+            json!("invalid_request")
+        );
+    }
+
+    #[test]
+    fn test_reply_script() {
+        let response = IpcResponse::new(json!(1), Ok(json!({"message": "\"quoted\"\n"})));
+        assert_eq!(
+            reply_script(&response),
+            r#"if(window.kpatcher)window.kpatcher._reply({"kpatcher":1,"id":1,"result":{"message":"\"quoted\"\n"}});"#
+        );
+    }
+}
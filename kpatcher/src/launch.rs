@@ -0,0 +1,151 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+
+use crate::patcher::LaunchProfile;
+use crate::process::{start_executable, ChildProcess, ProcessOptions};
+
+/// Arguments that precede the profile's arguments when logging in directly,
+/// unless the profile defines `login_arguments`
+const DEFAULT_LOGIN_ARGUMENTS: [&str; 3] = ["-t:{password}", "{login}", "{server}"];
+/// Value of `{server}` when the UI doesn't give one
+pub const DEFAULT_SERVER_ARGUMENT: &str = "server";
+
+/// Values of the placeholders used in argument templates.
+#[derive(Default)]
+pub struct LaunchVariables<'a> {
+    pub login: &'a str,
+    pub password: &'a str,
+    pub server: &'a str,
+    pub lang: &'a str,
+}
+
+impl LaunchProfile {
+    /// Arguments given to the game client when started with the Play button.
+    pub fn play_arguments(&self, variables: &LaunchVariables) -> Vec<String> {
+        expand_arguments(&self.arguments, variables)
+    }
+
+    /// Arguments given to the game client when the player logs in from the
+    /// UI.
+    pub fn login_arguments(&self, variables: &LaunchVariables) -> Vec<String> {
+        match &self.login_arguments {
+            Some(templates) => expand_arguments(templates, variables),
+            None => {
+                let templates: Vec<String> = DEFAULT_LOGIN_ARGUMENTS
+                    .iter()
+                    .map(|template| template.to_string())
+                    .chain(self.arguments.iter().cloned())
+                    .collect();
+                expand_arguments(&templates, variables)
+            }
+        }
+    }
+}
+
+/// Starts the game client described by `profile`.
+pub fn start_game_client(
+    profile: &LaunchProfile,
+    arguments: &[String],
+) -> Result<Option<ChildProcess>> {
+    let options = ProcessOptions {
+        working_directory: profile.working_directory.as_deref().map(Path::new),
+        env: Some(&profile.env),
+        wrapper: profile.wrapper.as_ref(),
+    };
+    log::info!("Starting the game client with profile '{}'", profile.name);
+    start_executable(&profile.path, arguments, &options).context("Failed to start client")
+}
+
+/// Replaces the placeholders found in `templates`. Arguments that are only
+/// made of placeholders with empty values are left out.
+fn expand_arguments(templates: &[String], variables: &LaunchVariables) -> Vec<String> {
+    templates
+        .iter()
+        .filter_map(|template| {
+            let argument = expand_template(template, variables);
+            if argument.is_empty() && !template.is_empty() {
+                None
+            } else {
+                Some(argument)
+            }
+        })
+        .collect()
+}
+
+/// Replaces placeholders in a single pass, so that values containing braces
+/// are left untouched. Unknown placeholders are kept as is.
+fn expand_template(template: &str, variables: &LaunchVariables) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find('}') {
+            Some(end) => end,
+            None => break,
+        };
+        let value = match &rest[1..end] {
+            "login" => variables.login,
+            "password" => variables.password,
+            "server" => variables.server,
+            "lang" => variables.lang,
+            _ => &rest[..=end],
+        };
+        result.push_str(value);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(rest);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_launch_arguments() {
+        let profile = LaunchProfile {
+            name: "2020".to_string(),
+            path: "ragexe2020.exe".to_string(),
+            arguments: vec!["-1sak1".to_string(), "{lang}".to_string()],
+            ..Default::default()
+        };
+        let variables = LaunchVariables {
+            login: "player",
+            password: "secret",
+            server: DEFAULT_SERVER_ARGUMENT,
+            lang: "",
+        };
+        assert_eq!(profile.play_arguments(&variables), vec!["-1sak1"]);
+        assert_eq!(
+            profile.login_arguments(&variables),
+            vec!["-t:secret", "player", "server", "-1sak1"]
+        );
+
+        let profile = LaunchProfile {
+            login_arguments: Some(vec![
+                "/account:{login}".to_string(),
+                "/lang:{lang}".to_string(),
+            ]),
+            ..profile
+        };
+        let variables = LaunchVariables {
+            lang: "pt",
+            ..variables
+        };
+        assert_eq!(
+            profile.login_arguments(&variables),
+            vec!["/account:player", "/lang:pt"]
+        );
+
+        let variables = LaunchVariables {
+            login: "{password}",
+            ..variables
+        };
+        assert_eq!(
+            expand_template("{login}:{unknown}{", &variables),
+            "{password}:{unknown}{"
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::patcher::{get_patcher_name, LoggingConfiguration};
+
+const DEFAULT_LOG_LEVEL: LevelFilter = LevelFilter::Info;
+/// Size (in KiB) at which log files are rotated, unless configured otherwise
+const DEFAULT_MAX_SIZE: u64 = 1024;
+/// Number of rotated log files kept, unless configured otherwise
+const DEFAULT_MAX_FILES: usize = 3;
+/// Lines kept in memory until file logging is configured
+const MAX_PENDING_LINES: usize = 256;
+
+static LOGGER: Logger = Logger {
+    output: Mutex::new(FileOutput::Pending(Vec::new())),
+    warnings: AtomicUsize::new(0),
+    errors: AtomicUsize::new(0),
+};
+static SESSION_START: OnceLock<OffsetDateTime> = OnceLock::new();
+
+/// Logs the patcher's records to the standard output and to a log file.
+struct Logger {
+    output: Mutex<FileOutput>,
+    warnings: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+enum FileOutput {
+    Pending(Vec<String>), // File logging hasn't been configured yet
+    Enabled(RotatingFile),
+    Disabled,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Dependencies' records are left out
+        metadata.level() <= log::max_level()
+            && metadata.target().starts_with(env!("CARGO_PKG_NAME"))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            Level::Error => self.errors.fetch_add(1, Ordering::Relaxed),
+            Level::Warn => self.warnings.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+        let line = format!(
+            "{} {:<5} [{}] {}\n",
+            format_timestamp(OffsetDateTime::now_utc()),
+            record.level(),
+            record.module_path().unwrap_or_else(|| record.target()),
+            record.args()
+        );
+        let _ = io::stdout().write_all(line.as_bytes());
+        if let Ok(mut output) = self.output.lock() {
+            match &mut *output {
+                FileOutput::Pending(lines) if lines.len() < MAX_PENDING_LINES => lines.push(line),
+                FileOutput::Enabled(file) => {
+                    let _ = file.write_line(&line);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger. Records are only written to the standard output and
+/// kept in memory until `configure` is called.
+pub fn init() -> Result<()> {
+    SESSION_START.get_or_init(OffsetDateTime::now_utc);
+    log::set_logger(&LOGGER).map_err(|e| anyhow!("{}", e))?;
+    log::set_max_level(DEFAULT_LOG_LEVEL);
+    Ok(())
+}
+
+/// Applies the logging configuration and starts writing to the log file,
+/// along with the records logged so far.
+pub fn configure(config: Option<&LoggingConfiguration>) {
+    let default_config = LoggingConfiguration::default();
+    let config = config.unwrap_or(&default_config);
+    let level = match config.level.as_deref().map(str::parse::<LevelFilter>) {
+        None => DEFAULT_LOG_LEVEL,
+        Some(Ok(level)) => level,
+        Some(Err(_)) => {
+            log::warn!("Invalid log level, defaulting to {}", DEFAULT_LOG_LEVEL);
+            DEFAULT_LOG_LEVEL
+        }
+    };
+    log::set_max_level(level);
+
+    let file = match config.enabled.unwrap_or(true) {
+        false => None,
+        true => match open_log_file(config) {
+            Ok(file) => Some(file),
+            Err(e) => {
+                log::warn!("Failed to open the log file: {:#}", e);
+                None
+            }
+        },
+    };
+    if let Ok(mut output) = LOGGER.output.lock() {
+        let pending_lines = match &*output {
+            FileOutput::Pending(lines) => lines.clone(),
+            _ => Vec::new(),
+        };
+        *output = match file {
+            None => FileOutput::Disabled,
+            Some(mut file) => {
+                for line in pending_lines {
+                    let _ = file.write_line(&line);
+                }
+                FileOutput::Enabled(file)
+            }
+        };
+    }
+}
+
+/// Log files written by the patcher, starting with the current one.
+pub fn log_files() -> Vec<PathBuf> {
+    let (path, max_files) = match LOGGER.output.lock().as_deref() {
+        Ok(FileOutput::Enabled(file)) => (file.path.clone(), file.max_files),
+        _ => return Vec::new(),
+    };
+    std::iter::once(path.clone())
+        .chain((1..=max_files).map(|number| rotated_log_path(&path, number)))
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Counts of the warnings and errors logged since the patcher started.
+pub fn logged_problems() -> (usize, usize) {
+    (
+        LOGGER.warnings.load(Ordering::Relaxed),
+        LOGGER.errors.load(Ordering::Relaxed),
+    )
+}
+
+/// Time at which the logger's been installed.
+pub fn session_start() -> Option<OffsetDateTime> {
+    SESSION_START.get().copied()
+}
+
+/// Formats `timestamp` with a millisecond precision.
+pub fn format_timestamp(timestamp: OffsetDateTime) -> String {
+    let timestamp = timestamp
+        .replace_millisecond(timestamp.millisecond())
+        .unwrap_or(timestamp);
+    timestamp
+        .format(&Rfc3339)
+        .unwrap_or_else(|_| timestamp.unix_timestamp().to_string())
+}
+
+fn open_log_file(config: &LoggingConfiguration) -> Result<RotatingFile> {
+    let path = match &config.path {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(get_patcher_name()?).with_extension("log"),
+    };
+    let max_size = config.max_size.unwrap_or(DEFAULT_MAX_SIZE);
+    let max_files = config.max_files.unwrap_or(DEFAULT_MAX_FILES);
+    RotatingFile::open(path, max_size.saturating_mul(1024), max_files)
+}
+
+/// Log file that's renamed to `<path>.1` when it gets too big. Older files
+/// are shifted to `<path>.2` and so on, and the oldest one is deleted.
+struct RotatingFile {
+    path: PathBuf,
+    file: fs::File,
+    size: u64,
+    max_size: u64,    // In bytes, 0 means unlimited
+    max_files: usize, // Rotated files kept
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> Result<RotatingFile> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open '{}'", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let line_size = line.len() as u64;
+        if self.max_size > 0 && self.size > 0 && self.size + line_size > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line_size;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+        } else {
+            for number in (1..self.max_files).rev() {
+                let _ = fs::rename(
+                    rotated_log_path(&self.path, number),
+                    rotated_log_path(&self.path, number + 1),
+                );
+            }
+            fs::rename(&self.path, rotated_log_path(&self.path, 1))?;
+            self.file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn rotated_log_path(path: &Path, number: usize) -> PathBuf {
+    let mut rotated_path = OsString::from(path.as_os_str());
+    rotated_path.push(format!(".{}", number));
+    PathBuf::from(rotated_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rotating_file() {
+        let log_dir = tempdir().unwrap();
+        let path = log_dir.path().join("kpatcher.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_line(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            fs::read_to_string(rotated_log_path(&path, 1)).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated_log_path(&path, 2)).unwrap(),
+            "second\n"
+        );
+        assert!(!rotated_log_path(&path, 3).exists());
+
+        // Existing content counts towards the limit
+        let mut file = RotatingFile::open(path.clone(), 10, 0).unwrap();
+        file.write_line("fifth\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fifth\n");
+    }
+}
@@ -1,24 +1,30 @@
 #![windows_subsystem = "windows"]
 
+mod i18n;
+mod instance;
+mod ipc;
+mod launch;
+mod logging;
 mod patcher;
 mod process;
 mod ui;
 
-use log::LevelFilter;
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
-use simple_logger::SimpleLogger;
+use instance::InstanceMessage;
 use structopt::StructOpt;
 use tao::event::{Event, WindowEvent};
 use tao::event_loop::{ControlFlow, EventLoop};
 use tinyfiledialogs as tfd;
 use ui::{UiController, UiEvent};
+use url::Url;
 
 use patcher::{
     patcher_thread_routine, retrieve_patcher_configuration, PatcherCommand, PatcherConfiguration,
+    Severity,
 };
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
@@ -32,8 +38,17 @@ struct Opt {
     /// Sets a custom working directory
     #[structopt(short, long, parse(from_os_str))]
     working_directory: Option<PathBuf>,
+    /// Applies a patch file, in the patcher that's already running if any
+    #[structopt(long, parse(from_os_str))]
+    apply: Option<PathBuf>,
+    /// Checks a configuration file and exits
+    #[structopt(long, parse(from_os_str))]
+    validate_config: Option<PathBuf>,
 }
 
+/// Exit code of `--validate-config` when the configuration has errors
+const INVALID_CONFIG_EXIT_CODE: i32 = 2;
+
 fn main() -> Result<()> {
     // Cleanup old executable if it exists
     if let Ok(current_exe) = env::current_exe() {
@@ -43,26 +58,56 @@ fn main() -> Result<()> {
         }
     }
 
-    SimpleLogger::new()
-        .with_level(LevelFilter::Off)
-        .with_module_level(PKG_NAME, LevelFilter::Info)
-        .init()
-        .with_context(|| "Failed to initalize the logger")?;
+    logging::init().with_context(|| "Failed to initalize the logger")?;
 
     // Parse CLI arguments
     let cli_args = Opt::from_args();
+    if let Some(config_path) = cli_args.validate_config {
+        let exit_code = match validate_config(&config_path)? {
+            true => 0,
+            false => INVALID_CONFIG_EXIT_CODE,
+        };
+        std::process::exit(exit_code);
+    }
+    // Relative to the directory the patcher's been started from
+    let patch_to_apply = cli_args.apply.map(|path| match env::current_dir() {
+        Ok(current_dir) => current_dir.join(path),
+        Err(_) => path,
+    });
     if let Some(working_directory) = cli_args.working_directory {
         env::set_current_dir(working_directory)
             .with_context(|| "Specified working directory is invalid or inaccessible")?;
     };
 
+    // Only one patcher can run for a given working directory, the others hand
+    // their arguments over to it
+    let working_directory = env::current_dir().context("Failed to get current directory")?;
+    let instance_guard = match instance::acquire_instance(&working_directory) {
+        Ok(Some(guard)) => Some(guard),
+        Ok(None) => {
+            log::info!("The patcher is already running, activating it");
+            let message = match patch_to_apply {
+                Some(path) => InstanceMessage::ApplyPatch(path),
+                None => InstanceMessage::Activate,
+            };
+            return instance::forward_to_running_instance(&working_directory, &message);
+        }
+        Err(e) => {
+            log::warn!("Failed to check for other instances: {:#}", e);
+            None
+        }
+    };
+
     let mut config = match retrieve_patcher_configuration(None) {
         Err(e) => {
-            let err_msg = "Failed to retrieve the patcher's configuration";
+            logging::configure(None);
+            log::error!("Failed to retrieve the patcher's configuration: {:#}", e);
+            i18n::configure(None);
             // Sanitize error message to avoid issues with double quotes in tinyfiledialogs
-            let formatted_error = format!("Error: {}: {:#}.", err_msg, e).replace('"', "'");
+            let formatted_error =
+                format!("{}: {:#}.", i18n::tr("config_error"), e).replace('"', "'");
             tfd::message_box_ok(
-                "Error",
+                &i18n::tr("error_title"),
                 formatted_error.as_str(),
                 tfd::MessageBoxIcon::Error,
             );
@@ -70,38 +115,61 @@ fn main() -> Result<()> {
         }
         Ok(v) => v,
     };
+    logging::configure(config.logging.as_ref());
+    log::info!("Starting {} {}", PKG_NAME, PKG_VERSION);
+    i18n::configure(config.language.as_deref());
 
     // Resolve relative path for index_url if it's not a remote URL or absolute file URI
     if !config.web.index_url.starts_with("http://")
         && !config.web.index_url.starts_with("https://")
         && !config.web.index_url.starts_with("file://")
     {
-        let current_dir = env::current_dir().context("Failed to get current directory")?;
-        let absolute_path = current_dir.join(&config.web.index_url);
+        let absolute_path = working_directory.join(&config.web.index_url);
         // Convert to slash-based path for file:/// URI
         let path_str = absolute_path.to_string_lossy().replace('\\', "/");
         config.web.index_url = format!("file:///{}", path_str);
         log::info!("Resolved local index URL: {}", config.web.index_url);
     }
+    // Lets the UI show its texts in the patcher's language
+    if let Ok(mut index_url) = Url::parse(&config.web.index_url) {
+        index_url
+            .query_pairs_mut()
+            .append_pair("lang", i18n::language().tag());
+        config.web.index_url = index_url.into();
+    }
 
     // Event Loop
     let event_loop = EventLoop::<UiEvent>::with_user_event();
     let proxy = event_loop.create_proxy();
+    if let Some(instance_guard) = instance_guard {
+        let proxy = proxy.clone();
+        instance_guard.listen(move |message| {
+            let patch_path = match message {
+                InstanceMessage::Activate => None,
+                InstanceMessage::ApplyPatch(path) => Some(path),
+            };
+            let _ = proxy.send_event(UiEvent::Activate(patch_path));
+        });
+    }
 
     // Create a channel to allow the patcher thread to communicate with the patching thread (which we spawn)
     // Wait, the patching thread receives PatcherCommand from UI.
     // The UI Controller sends UiEvent to Main Thread.
     let (tx, rx) = flume::bounded(32);
+    let patcher_tx = tx.clone();
 
-    let (webview, patching_in_progress) =
-        ui::build_webview(&event_loop, config.clone(), tx, proxy.clone())
+    let ui_ctrl = UiController::new(proxy);
+    let (webview, patching_in_progress, play_blocked) =
+        ui::build_webview(&event_loop, config.clone(), tx, ui_ctrl.clone())
             .with_context(|| "Failed to build a web view")?;
 
     // Spawn a patching thread
-    let ui_ctrl = UiController::new(proxy);
     // new_patching_thread returns a JoinHandle, but we can't join it easily in tao loop.
     // We just spawn it and let it run.
     let _patching_thread = new_patching_thread(rx, ui_ctrl, config.clone());
+    if let Some(path) = patch_to_apply {
+        let _ = patcher_tx.send(PatcherCommand::ApplyPatch(path));
+    }
 
     // Prevent dragging images
     webview
@@ -123,11 +191,28 @@ fn main() -> Result<()> {
         match event {
             Event::UserEvent(ui_event) => match ui_event {
                 UiEvent::PatchingStatus(status) => {
+                    if let Some(play_block) = ui::PlayBlock::after_status(&status) {
+                        if let Ok(mut play_blocked) = play_blocked.lock() {
+                            *play_blocked = play_block;
+                        }
+                    }
+                    // The game can't be started without its critical patches
+                    let play_with_error = config.play.play_with_error.unwrap_or(false)
+                        && !matches!(
+                            &status,
+                            ui::PatchingStatus::Error(report) if report.critical_patch.is_some()
+                        );
+                    let event_script =
+                        ipc::event_script("status", &status.to_event_payload(play_with_error));
                     let script = match status {
                         ui::PatchingStatus::Ready => "patchingStatusReady()".to_string(),
-                        ui::PatchingStatus::Error(msg) => {
-                            let play_with_error = config.play.play_with_error.unwrap_or(false);
-                            format!("patchingStatusError(\"{}\", {})", msg, play_with_error)
+                        ui::PatchingStatus::Error(report) => {
+                            let msg = serde_json::to_string(&report.message).unwrap_or_default();
+                            let report = serde_json::to_string(&report).unwrap_or_default();
+                            format!(
+                                "patchingStatusError({}, {}, {})",
+                                msg, play_with_error, report
+                            )
                         }
                         ui::PatchingStatus::DownloadInProgress(nb, total, rate) => {
                             format!("patchingStatusDownloading({}, {}, {})", nb, total, rate)
@@ -136,9 +221,33 @@ fn main() -> Result<()> {
                             format!("patchingStatusInstalling({}, {})", nb, total)
                         }
                         ui::PatchingStatus::ManualPatchApplied(name) => {
-                            format!("patchingStatusPatchApplied(\"{}\")", name)
+                            let name = serde_json::to_string(&name).unwrap_or_default();
+                            format!("patchingStatusPatchApplied({})", name)
+                        }
+                        ui::PatchingStatus::Maintenance(msg) => {
+                            // Falls back to the error callback for older UIs
+                            let msg = serde_json::to_string(&msg).unwrap_or_default();
+                            format!(
+                                "if(typeof patchingStatusMaintenance==='function')patchingStatusMaintenance({0});else patchingStatusError({0}, false);",
+                                msg
+                            )
+                        }
+                        ui::PatchingStatus::Paused => {
+                            "if(typeof patchingStatusPaused==='function')patchingStatusPaused();"
+                                .to_string()
+                        }
+                        ui::PatchingStatus::FilesInUse(msg) => {
+                            // Falls back to the error callback for older UIs
+                            let msg = serde_json::to_string(&msg).unwrap_or_default();
+                            format!(
+                                "if(typeof patchingStatusFilesInUse==='function')patchingStatusFilesInUse({0});else patchingStatusError({0}, false);",
+                                msg
+                            )
                         }
                     };
+                    // Event listeners are notified first, UIs that only use them
+                    // don't define the legacy callbacks
+                    let script = format!("{}{};", event_script, script);
                     if let Err(e) = webview.evaluate_script(&script) {
                         log::warn!("Failed to dispatch patching status: {}.", e);
                     }
@@ -147,7 +256,7 @@ fn main() -> Result<()> {
                     patching_in_progress.store(val, std::sync::atomic::Ordering::Relaxed);
                 }
                 UiEvent::Exit => {
-                    ui::save_window_position(webview.window());
+                    ui::save_window_state(webview.window());
                     *control_flow = ControlFlow::Exit;
                 }
                 UiEvent::RunScript(script) => {
@@ -155,12 +264,51 @@ fn main() -> Result<()> {
                         log::warn!("Failed to run script: {}.", e);
                     }
                 }
+                UiEvent::GameExited(exit, remaining_clients) => {
+                    if exit.crashed {
+                        log::warn!("Game client crashed (exit code: {:?})", exit.code);
+                    } else {
+                        log::info!("Game client exited (exit code: {:?})", exit.code);
+                    }
+                    // The window's been minimized when the game started, it's
+                    // restored once every client has exited
+                    if config.play.minimize_on_start.unwrap_or(false) && remaining_clients == 0 {
+                        webview.window().set_minimized(false);
+                        webview.window().set_focus();
+                    }
+                    let payload = serde_json::json!({"code": exit.code, "crashed": exit.crashed});
+                    let script = format!(
+                        "{}if(typeof gameExited==='function')gameExited({}, {});",
+                        ipc::event_script("game_exited", &payload),
+                        payload["code"],
+                        exit.crashed
+                    );
+                    if let Err(e) = webview.evaluate_script(&script) {
+                        log::warn!("Failed to dispatch game client exit: {}.", e);
+                    }
+                }
+                UiEvent::Activate(patch_path) => {
+                    let window = webview.window();
+                    window.set_visible(true);
+                    window.set_minimized(false);
+                    window.set_focus();
+                    if let Some(path) = patch_path {
+                        if patching_in_progress.load(std::sync::atomic::Ordering::Relaxed) {
+                            log::warn!(
+                                "Not applying '{}', an update is in progress",
+                                path.display()
+                            );
+                        } else {
+                            let _ = patcher_tx.send(PatcherCommand::ApplyPatch(path));
+                        }
+                    }
+                }
             },
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 ..
             } => {
-                ui::save_window_position(webview.window());
+                ui::save_window_state(webview.window());
                 *control_flow = ControlFlow::Exit;
             }
             Event::WindowEvent {
@@ -179,6 +327,42 @@ fn main() -> Result<()> {
     });
 }
 
+/// Prints the problems found in the configuration file located at
+/// `config_path`. Returns `false` if the configuration has errors.
+fn validate_config(config_path: &Path) -> Result<bool> {
+    attach_parent_console();
+    let validation = patcher::validate_configuration_file(config_path)?;
+    for diagnostic in &validation.diagnostics {
+        match diagnostic.severity {
+            Severity::Error => eprintln!("{}:{}", config_path.display(), diagnostic),
+            Severity::Warning => println!("{}:{}", config_path.display(), diagnostic),
+        }
+    }
+    if validation.has_errors() {
+        return Ok(false);
+    }
+    println!("{}: configuration is valid", config_path.display());
+    Ok(true)
+}
+
+/// Release builds don't have a console, output is shown in the console the
+/// patcher's been started from, unless it's redirected.
+#[cfg(windows)]
+fn attach_parent_console() {
+    use winapi::um::processenv::GetStdHandle;
+    use winapi::um::winbase::STD_OUTPUT_HANDLE;
+    use winapi::um::wincon::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+    unsafe {
+        if GetStdHandle(STD_OUTPUT_HANDLE).is_null() {
+            AttachConsole(ATTACH_PARENT_PROCESS);
+        }
+    }
+}
+
+#[cfg(not(windows))]
+fn attach_parent_console() {}
+
 /// Spawns a new thread that runs a single threaded tokio runtime to execute the patcher routine
 fn new_patching_thread(
     rx: flume::Receiver<PatcherCommand>,
@@ -0,0 +1,67 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Limits the download speed of patches. Shared by concurrent downloads, so
+/// that the limit applies to their combined speed.
+pub struct BandwidthLimiter {
+    bytes_per_sec: u64,
+    next_slot: Mutex<Instant>, // When the previously received bytes are "paid for"
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec.max(1),
+            next_slot: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Builds a limiter from a limit given in KiB/s. `None` and 0 mean
+    /// unlimited.
+    pub fn from_kib_per_sec(limit: Option<u64>) -> Option<Self> {
+        match limit {
+            None | Some(0) => None,
+            Some(limit) => Some(Self::new(limit.saturating_mul(1024))),
+        }
+    }
+
+    /// Waits until `bytes` more bytes can be received without exceeding the
+    /// limit.
+    pub async fn consume(&self, bytes: u64) {
+        let deadline = {
+            let mut next_slot = match self.next_slot.lock() {
+                Ok(next_slot) => next_slot,
+                Err(_) => return,
+            };
+            let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec as f64);
+            *next_slot = (*next_slot).max(Instant::now()) + cost;
+            *next_slot
+        };
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_kib_per_sec() {
+        assert!(BandwidthLimiter::from_kib_per_sec(None).is_none());
+        assert!(BandwidthLimiter::from_kib_per_sec(Some(0)).is_none());
+        let limiter = BandwidthLimiter::from_kib_per_sec(Some(512)).unwrap();
+        assert_eq!(limiter.bytes_per_sec, 512 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_consume() {
+        let limiter = BandwidthLimiter::new(100_000);
+        let start = Instant::now();
+        for _ in 0..5 {
+            limiter.consume(10_000).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
@@ -3,9 +3,15 @@ use std::path::Path;
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct PatcherCache {
     pub last_patch_index: usize,
+    #[serde(default)]
+    pub last_patch_server: Option<String>, // Name of the server used last time
+    #[serde(default)]
+    pub last_full_repair: Option<String>, // ID of the last full repair requested by the server
+    #[serde(default)]
+    pub pending_after_update: bool, // The after_update hooks haven't succeeded since the last patch
 }
 
 pub async fn read_cache_file(cache_file_path: impl AsRef<Path>) -> Result<PatcherCache> {
@@ -13,6 +19,13 @@ pub async fn read_cache_file(cache_file_path: impl AsRef<Path>) -> Result<Patche
     serde_json::from_slice(&content).context("Failed to deserialize patcher cache")
 }
 
+/// Blocking version of `read_cache_file`, for use outside of the patching
+/// thread.
+pub fn read_cache_file_blocking(cache_file_path: impl AsRef<Path>) -> Result<PatcherCache> {
+    let content = std::fs::read(cache_file_path)?;
+    serde_json::from_slice(&content).context("Failed to deserialize patcher cache")
+}
+
 pub async fn write_cache_file(
     cache_file_path: impl AsRef<Path>,
     new_cache: PatcherCache,
@@ -34,6 +47,9 @@ mod tests {
 
         let cache = PatcherCache {
             last_patch_index: 42,
+            last_patch_server: Some("EU Patch Server".to_string()),
+            last_full_repair: None,
+            pending_after_update: false,
         };
 
         write_cache_file(cache_path, cache).await.unwrap();
@@ -41,5 +57,24 @@ mod tests {
         let read_cache = read_cache_file(cache_path).await.unwrap();
 
         assert_eq!(read_cache.last_patch_index, 42);
+        assert_eq!(
+            read_cache.last_patch_server.as_deref(),
+            Some("EU Patch Server")
+        );
+        let read_cache = read_cache_file_blocking(cache_path).unwrap();
+        assert_eq!(read_cache.last_patch_index, 42);
+    }
+
+    #[tokio::test]
+    async fn test_read_legacy_cache() {
+        let tmp_file = NamedTempFile::new().unwrap();
+        tokio::fs::write(tmp_file.path(), br#"{"last_patch_index":7}"#)
+            .await
+            .unwrap();
+
+        let read_cache = read_cache_file(tmp_file.path()).await.unwrap();
+
+        assert_eq!(read_cache.last_patch_index, 7);
+        assert!(read_cache.last_patch_server.is_none());
     }
 }
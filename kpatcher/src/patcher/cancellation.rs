@@ -1,22 +1,111 @@
+use anyhow::anyhow;
+use tokio::sync::watch;
+
 use super::PatcherCommand;
 
 pub type InterruptibleFnResult<T> = std::result::Result<T, InterruptibleFnError>;
 
 pub enum InterruptibleFnError {
-    Err(String), // An actual error
-    Interrupted, // An interruption
+    Err(anyhow::Error), // An actual error
+    Interrupted,        // An interruption
 }
 
 pub async fn wait_for_cancellation(
     patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
 ) -> InterruptibleFnError {
-    if let Ok(cmd) = patching_thread_rx.recv_async().await {
-        match cmd {
-            PatcherCommand::CancelUpdate => InterruptibleFnError::Interrupted,
-            _ => InterruptibleFnError::Err("Unexpected command received".to_string()),
+    loop {
+        if let Ok(cmd) = patching_thread_rx.recv_async().await {
+            match cmd {
+                PatcherCommand::CancelUpdate => return InterruptibleFnError::Interrupted,
+                // Only downloads can be paused, and servers are probed when
+                // the update starts anyway
+                PatcherCommand::PauseUpdate
+                | PatcherCommand::ResumeUpdate
+                | PatcherCommand::RetryUpdate
+                | PatcherCommand::ProbePatchServers => continue,
+                _ => return InterruptibleFnError::Err(anyhow!("Unexpected command received")),
+            }
+        } else {
+            return InterruptibleFnError::Err(anyhow!("Channel was closed"));
+        }
+    }
+}
+
+/// Same as `wait_for_cancellation` but also handles pause and resume commands,
+/// which are forwarded to `pause_tx`. `on_pause_change` is called when the
+/// state actually changes.
+pub async fn wait_for_cancellation_or_pause<F: FnMut(bool)>(
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    pause_tx: &watch::Sender<bool>,
+    mut on_pause_change: F,
+) -> InterruptibleFnError {
+    loop {
+        let paused = match patching_thread_rx.recv_async().await {
+            Err(_) => return InterruptibleFnError::Err(anyhow!("Channel was closed")),
+            Ok(PatcherCommand::CancelUpdate) => return InterruptibleFnError::Interrupted,
+            Ok(PatcherCommand::PauseUpdate) => true,
+            Ok(PatcherCommand::ResumeUpdate) => false,
+            Ok(PatcherCommand::ProbePatchServers) | Ok(PatcherCommand::RetryUpdate) => continue,
+            Ok(_) => return InterruptibleFnError::Err(anyhow!("Unexpected command received")),
+        };
+        if *pause_tx.borrow() != paused {
+            let _ = pause_tx.send(paused);
+            on_pause_change(paused);
+        }
+    }
+}
+
+/// Returns `Ok` when the user asks to retry, or an error if the update is
+/// canceled.
+pub async fn wait_for_retry(
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<()> {
+    loop {
+        match patching_thread_rx.recv_async().await {
+            Err(_) => return Err(InterruptibleFnError::Err(anyhow!("Channel was closed"))),
+            Ok(PatcherCommand::CancelUpdate) => return Err(InterruptibleFnError::Interrupted),
+            Ok(PatcherCommand::RetryUpdate) => return Ok(()),
+            Ok(PatcherCommand::PauseUpdate)
+            | Ok(PatcherCommand::ResumeUpdate)
+            | Ok(PatcherCommand::ProbePatchServers) => continue,
+            Ok(_) => {
+                return Err(InterruptibleFnError::Err(anyhow!(
+                    "Unexpected command received"
+                )))
+            }
+        }
+    }
+}
+
+/// Lets tasks wait while an update is paused.
+#[derive(Clone)]
+pub struct PauseGate {
+    paused_rx: watch::Receiver<bool>,
+}
+
+impl PauseGate {
+    pub fn new(paused_rx: watch::Receiver<bool>) -> Self {
+        Self { paused_rx }
+    }
+
+    /// Returns immediately if the update isn't paused.
+    pub async fn wait_until_resumed(&mut self) {
+        while *self.paused_rx.borrow_and_update() {
+            if self.paused_rx.changed().await.is_err() {
+                // Nobody can resume the update anymore
+                return;
+            }
+        }
+    }
+
+    /// Returns once the update gets paused (never if it can't be paused
+    /// anymore).
+    pub async fn wait_until_paused(&mut self) {
+        while !*self.paused_rx.borrow_and_update() {
+            if self.paused_rx.changed().await.is_err() {
+                futures::future::pending::<()>().await;
+            }
         }
-    } else {
-        InterruptibleFnError::Err("Channel was closed".to_string())
     }
 }
 
@@ -30,7 +119,7 @@ pub fn process_incoming_commands(
         },
         Err(e) => match e {
             flume::TryRecvError::Disconnected => {
-                Err(InterruptibleFnError::Err("Channel was closed".to_string()))
+                Err(InterruptibleFnError::Err(anyhow!("Channel was closed")))
             }
             flume::TryRecvError::Empty => Ok(()),
         },
@@ -1,12 +1,14 @@
+use std::collections::HashMap;
 use std::env;
-use std::fs::File;
-use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
 
 use super::get_patcher_name;
-use anyhow::{Context, Result};
+use super::validation::{validate_configuration, Severity};
+use anyhow::{anyhow, Context, Result};
 use flate2::read::GzDecoder;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use aes_gcm::{
     aead::{Aead, KeyInit},
     Aes256Gcm, Nonce // Or `Key`
@@ -19,7 +21,7 @@ const CONFIG_MARKER: &[u8; 4] = b"KCFG";
 /// IMPORTANTE: Manter sincronizado com mkpatch/src/embed.rs
 const ENCRYPTION_KEY: &[u8; 32] = b"kpatcher_secret_key_32_bytes!!!!";
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct PatcherConfiguration {
     pub window: WindowConfiguration,
     pub play: PlayConfiguration,
@@ -27,9 +29,12 @@ pub struct PatcherConfiguration {
     pub web: WebConfiguration,
     pub client: ClientConfiguration,
     pub patching: PatchingConfiguration,
+    pub signatures: Option<SignatureConfiguration>,
+    pub logging: Option<LoggingConfiguration>,
+    pub language: Option<String>, // Language of the patcher's messages (default: system's)
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct WindowConfiguration {
     pub title: String,
     pub width: i32,
@@ -40,69 +45,216 @@ pub struct WindowConfiguration {
     pub border_radius: Option<i32>,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct PlayConfiguration {
     pub path: String,
     pub arguments: Vec<String>,
     pub exit_on_success: Option<bool>,
     pub play_with_error: Option<bool>,
     pub minimize_on_start: Option<bool>,
+    #[serde(default)]
+    pub profiles: Vec<LaunchProfile>, // Other ways of starting the game
+    pub default_profile: Option<String>, // Profile used unless the player picks another one
+    pub wrapper: Option<WrapperConfiguration>, // Used by profiles that don't define theirs
 }
 
-#[derive(Deserialize, Clone)]
+/// Name of the launch profile made of `play.path` and `play.arguments`
+pub const DEFAULT_LAUNCH_PROFILE: &str = "default";
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct LaunchProfile {
+    pub name: String,
+    pub path: String,                      // Executable of the game client
+    pub working_directory: Option<String>, // Defaults to the executable's directory
+    #[serde(default)]
+    pub arguments: Vec<String>, // Argument templates ({login}, {password}, {server}, {lang})
+    pub login_arguments: Option<Vec<String>>, // Argument templates used to log in directly
+    #[serde(default)]
+    pub env: HashMap<String, String>, // Additional environment variables
+    pub wrapper: Option<WrapperConfiguration>,
+}
+
+/// Program that starts Windows executables on other systems (e.g. Wine).
+/// Ignored on Windows.
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[cfg_attr(windows, allow(dead_code))]
+pub struct WrapperConfiguration {
+    pub enabled: Option<bool>,   // Default: only for Windows executables
+    pub command: Option<String>, // Default: wine
+    #[serde(default)]
+    pub arguments: Vec<String>, // Arguments given before the executable
+    pub prefix: Option<String>,  // Wine prefix (WINEPREFIX)
+    #[serde(default)]
+    pub env: HashMap<String, String>, // Additional environment variables
+}
+
+impl PlayConfiguration {
+    /// Lists the launch profiles, starting with the default one made of
+    /// `path` and `arguments`.
+    pub fn launch_profiles(&self) -> Vec<LaunchProfile> {
+        let default_profile = LaunchProfile {
+            name: DEFAULT_LAUNCH_PROFILE.to_string(),
+            path: self.path.clone(),
+            arguments: self.arguments.clone(),
+            ..Default::default()
+        };
+        std::iter::once(default_profile)
+            .chain(self.profiles.iter().cloned())
+            .map(|mut profile| {
+                if profile.wrapper.is_none() {
+                    profile.wrapper = self.wrapper.clone();
+                }
+                profile
+            })
+            .collect()
+    }
+
+    pub fn launch_profile(&self, name: &str) -> Option<LaunchProfile> {
+        self.launch_profiles()
+            .into_iter()
+            .find(|profile| profile.name == name)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct SetupConfiguration {
     pub path: String,
     pub arguments: Vec<String>,
     pub exit_on_success: Option<bool>,
+    pub wrapper: Option<WrapperConfiguration>,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct WebConfiguration {
     pub index_url: String, // URL of the index file implementing the UI
     pub preferred_patch_server: Option<String>, // Name of the patch server to use in priority
+    pub probe_timeout: Option<u64>, // Time (in seconds) given to patch servers to answer
     pub patch_servers: Vec<PatchServerInfo>,
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone, Default)]
 pub struct PatchServerInfo {
     pub name: String,      // Name of that identifies the patch server
     pub plist_url: String, // URL of the plist.txt file
     pub patch_url: String, // URL of the directory containing .thor files
+    pub control_url: Option<String>, // URL of the control file (directives for the patcher)
+    pub headers: Option<HashMap<String, String>>, // Additional HTTP headers
+    pub auth: Option<PatchServerAuth>, // HTTP authentication
+    pub proxy: Option<String>, // URL of the proxy to use (http://, https:// or socks5://)
+    pub user_agent: Option<String>, // Custom User-Agent header
+    pub connect_timeout: Option<u64>, // Time (in seconds) allowed to connect to the server
+    pub timeout: Option<u64>, // Time (in seconds) allowed for each request, downloads included
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum PatchServerAuth {
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+    Bearer {
+        token: String,
+    },
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct ClientConfiguration {
     pub default_grf_name: String, // GRF file to patch by default
 }
 
-#[derive(Deserialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SignatureConfiguration {
+    pub public_keys: Vec<String>, // Hex-encoded Ed25519 public keys
+    pub required: Option<bool>,   // Refuse unsigned content (default: true)
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct LoggingConfiguration {
+    pub enabled: Option<bool>,    // Write logs to a file (default: true)
+    pub level: Option<String>,    // error, warn, info, debug or trace (default: info)
+    pub path: Option<String>,     // Default: <patcher name>.log
+    pub max_size: Option<u64>,    // Size (in KiB) at which the file is rotated (default: 1024)
+    pub max_files: Option<usize>, // Rotated files kept (default: 3)
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct PatchingConfiguration {
-    pub in_place: bool,        // In-place GRF patching
-    pub check_integrity: bool, // Check THOR archives' integrity
-    pub create_grf: bool,      // Create new GRFs if they don't exist
+    pub in_place: bool,                    // In-place GRF patching
+    pub check_integrity: bool,             // Check THOR archives' integrity
+    pub create_grf: bool,                  // Create new GRFs if they don't exist
+    pub check_disk_space: Option<bool>,    // Check free space before downloading and patching
+    pub in_place_fallback: Option<bool>,   // Patch in-place when out-of-place patching doesn't fit
+    pub hooks: Option<HooksConfiguration>, // Commands run at specific steps of the process
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct HooksConfiguration {
+    #[serde(default)]
+    pub before_update: Vec<HookCommand>, // Run before the patches are downloaded
+    #[serde(default)]
+    pub after_patch: Vec<HookCommand>, // Run after each applied patch
+    #[serde(default)]
+    pub after_update: Vec<HookCommand>, // Run once all the patches have been applied
+    #[serde(default)]
+    pub before_launch: Vec<HookCommand>, // Run before the game client is started
+    pub allow_patch_hooks: Option<bool>, // Run the commands carried by patches (default: false)
+}
+
+/// Command run by a hook. Also the format of the hooks carried by patches.
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct HookCommand {
+    pub command: String, // Executable, relative to the game's directory if it's a path
+    #[serde(default)]
+    pub arguments: Vec<String>,
+    pub working_directory: Option<String>, // Defaults to the game's directory
+    #[serde(default)]
+    pub env: HashMap<String, String>, // Additional environment variables
+    pub timeout: Option<u64>,              // Time (in seconds) allowed to run (default: 60)
+    pub on_failure: Option<HookFailurePolicy>, // Default: abort
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookFailurePolicy {
+    Abort,    // Stop the current operation
+    Continue, // Log the failure and go on
 }
 
 pub fn retrieve_patcher_configuration(
     config_file_path: Option<PathBuf>,
 ) -> Result<PatcherConfiguration> {
     // Primeiro, tentar extrair config embutido do próprio executável
-    if let Ok(config) = extract_embedded_config() {
+    if let Ok(yaml_content) = extract_embedded_config() {
         log::info!("Using embedded configuration");
-        return Ok(config);
+        return load_configuration(&yaml_content, Path::new("."), "embedded configuration");
     }
 
     // Fallback: carregar de arquivo externo
-    let patcher_name = get_patcher_name()?;
-    let config_file_path =
-        config_file_path.unwrap_or_else(|| PathBuf::from(patcher_name).with_extension("yml"));
+    let config_file_path = match config_file_path {
+        Some(config_file_path) => config_file_path,
+        None => get_configuration_file_path()?,
+    };
     log::info!("Loading configuration from: {}", config_file_path.display());
     parse_configuration(config_file_path)
 }
 
+/// Path of the configuration file used when none is embedded in the
+/// executable.
+pub fn get_configuration_file_path() -> Result<PathBuf> {
+    let patcher_name = get_patcher_name()?;
+    Ok(PathBuf::from(patcher_name).with_extension("yml"))
+}
+
+/// Indicates whether the configuration is embedded in the executable.
+pub fn is_configuration_embedded() -> bool {
+    extract_embedded_config().is_ok()
+}
+
 /// Tenta extrair configuração embutida no final do executável.
 ///
 /// Formato esperado: [EXE] + [YAML gzip] + [tamanho u32 LE] + [marcador "KCFG"]
-fn extract_embedded_config() -> Result<PatcherConfiguration> {
+fn extract_embedded_config() -> Result<String> {
     let exe_path = env::current_exe().context("Failed to get current executable path")?;
     let mut file = File::open(&exe_path).context("Failed to open executable")?;
 
@@ -157,12 +309,43 @@ fn extract_embedded_config() -> Result<PatcherConfiguration> {
         .read_to_string(&mut yaml_content)
         .context("Failed to decompress embedded config")?;
 
-    // Parse YAML
-    serde_yaml::from_str(&yaml_content).context("Invalid embedded configuration")
+    Ok(yaml_content)
 }
 
 fn parse_configuration(config_file_path: impl AsRef<Path>) -> Result<PatcherConfiguration> {
-    let config_file = File::open(config_file_path)?;
-    let config_reader = BufReader::new(config_file);
-    serde_yaml::from_reader(config_reader).context("Invalid configuration")
+    let config_file_path = config_file_path.as_ref();
+    let yaml_content = fs::read_to_string(config_file_path)?;
+    // Relative paths are resolved from the working directory
+    load_configuration(
+        &yaml_content,
+        Path::new("."),
+        &config_file_path.display().to_string(),
+    )
+}
+
+/// Deserializes and validates a configuration, `source` is used to locate
+/// the problems that are found.
+fn load_configuration(
+    yaml_content: &str,
+    base_dir: &Path,
+    source: &str,
+) -> Result<PatcherConfiguration> {
+    let validation = validate_configuration(yaml_content, base_dir);
+    let (errors, warnings): (Vec<_>, Vec<_>) = validation
+        .diagnostics
+        .iter()
+        .partition(|diagnostic| diagnostic.severity == Severity::Error);
+    for warning in warnings {
+        log::warn!("{}:{}", source, warning);
+    }
+    match validation.configuration {
+        Some(configuration) if errors.is_empty() => Ok(configuration),
+        _ => {
+            let errors: Vec<String> = errors
+                .iter()
+                .map(|error| format!("{}:{}", source, error))
+                .collect();
+            Err(anyhow!("{}", errors.join("\n"))).context("Invalid configuration")
+        }
+    }
 }
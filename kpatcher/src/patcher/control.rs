@@ -0,0 +1,106 @@
+use anyhow::{Context, Result};
+use gruf::thor::ThorPatchInfo;
+use serde::Deserialize;
+
+use super::cache::PatcherCache;
+
+/// Directives published by a patch server next to its patch list (YAML or
+/// JSON). They're applied before anything gets downloaded.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct ControlFile {
+    pub reset_cache_below: Option<usize>, // Clients whose last patch is older start over
+    #[serde(default)]
+    pub blocked_patches: Vec<String>, // Patches that mustn't be applied (file names)
+    pub full_repair: Option<String>, // Changing this ID makes every client apply all patches again
+    pub maintenance: Option<String>, // Prevents players from starting the game, with a message
+    pub notice: Option<String>,      // Message displayed to players
+}
+
+impl ControlFile {
+    pub fn from_slice(content: &[u8]) -> Result<Self> {
+        if content.iter().all(u8::is_ascii_whitespace) {
+            // Empty document, no directives
+            return Ok(Self::default());
+        }
+        serde_yaml::from_slice(content).context("Failed to parse control file")
+    }
+
+    /// Indicates whether `cache` must be discarded, so that all patches are
+    /// applied again.
+    pub fn requires_cache_reset(&self, cache: &PatcherCache) -> bool {
+        if let Some(reset_cache_below) = self.reset_cache_below {
+            if cache.last_patch_index < reset_cache_below {
+                return true;
+            }
+        }
+        match &self.full_repair {
+            Some(full_repair) => cache.last_full_repair.as_ref() != Some(full_repair),
+            None => false,
+        }
+    }
+
+    pub fn is_patch_blocked(&self, patch_info: &ThorPatchInfo) -> bool {
+        self.blocked_patches
+            .iter()
+            .any(|file_name| file_name == &patch_info.file_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_control_file() {
+        assert_eq!(
+            ControlFile::from_slice(b"").unwrap(),
+            ControlFile::default()
+        );
+        let control = ControlFile::from_slice(
+            br#"
+reset_cache_below: 100
+blocked_patches: [broken.thor]
+full_repair: "2024-05"
+maintenance: "Back at 18:00"
+"#,
+        )
+        .unwrap();
+        assert_eq!(control.reset_cache_below, Some(100));
+        assert_eq!(control.full_repair.as_deref(), Some("2024-05"));
+        assert_eq!(control.maintenance.as_deref(), Some("Back at 18:00"));
+        assert_eq!(control.notice, None);
+        assert!(control.is_patch_blocked(&ThorPatchInfo {
+            index: 3,
+            file_name: "broken.thor".to_string(),
+            ..Default::default()
+        }));
+        // JSON works too
+        let control = ControlFile::from_slice(br#"{"notice": "Double XP!"}"#).unwrap();
+        assert_eq!(control.notice.as_deref(), Some("Double XP!"));
+        assert!(ControlFile::from_slice(b"reset_cache_below: soon").is_err());
+    }
+
+    #[test]
+    fn test_requires_cache_reset() {
+        let cache = PatcherCache {
+            last_patch_index: 120,
+            last_full_repair: Some("1".to_string()),
+            ..Default::default()
+        };
+        assert!(!ControlFile::default().requires_cache_reset(&cache));
+
+        let reset_below = |index| ControlFile {
+            reset_cache_below: Some(index),
+            ..Default::default()
+        };
+        assert!(!reset_below(100).requires_cache_reset(&cache));
+        assert!(reset_below(121).requires_cache_reset(&cache));
+
+        let full_repair = |id: &str| ControlFile {
+            full_repair: Some(id.to_string()),
+            ..Default::default()
+        };
+        assert!(!full_repair("1").requires_cache_reset(&cache));
+        assert!(full_repair("2").requires_cache_reset(&cache));
+    }
+}
@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -14,19 +15,57 @@ use futures::stream::{StreamExt, TryStreamExt};
 use gruf::grf::GrfArchive;
 use gruf::thor::{self, ThorArchive, ThorPatchInfo, ThorPatchList};
 use gruf::GrufError;
+use sha2::{Digest, Sha256};
 use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::watch;
 use url::Url;
 
+use super::bandwidth::BandwidthLimiter;
 use super::cache::{read_cache_file, write_cache_file, PatcherCache};
 use super::cancellation::{
-    process_incoming_commands, wait_for_cancellation, InterruptibleFnError, InterruptibleFnResult,
+    process_incoming_commands, wait_for_cancellation, wait_for_cancellation_or_pause,
+    wait_for_retry, InterruptibleFnError, InterruptibleFnResult, PauseGate,
 };
 use super::config::PatchServerInfo;
-use super::patching::{apply_patch_to_disk, apply_patch_to_grf, GrfPatchingMethod};
+use super::control::ControlFile;
+use super::disk_space::{check_space_for_download, check_space_for_patching, PatchFile};
+use super::error::{ErrorReport, PatcherError};
+use super::hooks::{run_after_patch_hooks, run_hooks, AppliedPatch, HookCanceler, HookEvent};
+use super::http::build_http_client;
+use super::in_use::{check_game_files_in_use, GameFilesInUse};
+use super::patching::{
+    apply_patch_to_disk, apply_patch_to_grf, join_windows_relative_path, GrfPatchingMethod,
+};
+use super::settings::UserSettings;
+use super::signature::{SignatureVerifier, SIGNATURE_FILE_SUFFIX};
+use super::source::PatchLocation;
 use super::{get_patcher_name, PatcherCommand, PatcherConfiguration};
+use crate::i18n;
 use crate::patcher::patching::apply_grf_to_grf;
-use crate::ui::{PatchingStatus, UiController};
+use crate::ui::{PatchServerProbe, PatchingProgress, PatchingStatus, UiController};
+
+/// Time after which an unresponsive patch server is considered unavailable
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Latency margin within which the server used during the previous run is kept
+const LAST_SERVER_LATENCY_TOLERANCE: f64 = 1.25;
+/// Number of entries read to check that a GRF is still readable after patching
+const GRF_INTEGRITY_SAMPLE_SIZE: usize = 10;
+
+/// Patch server that answered our probe, along with the patches it serves.
+struct AvailablePatchServer {
+    name: String,
+    patch_list: ThorPatchList,
+    patch_location: PatchLocation,
+    control: ControlFile,
+    client: reqwest::Client,
+}
+
+/// Result of an update that went through.
+enum UpdateOutcome {
+    UpToDate,
+    Maintenance(String), // The game is under maintenance, with a message
+}
 
 /// Representation of a pending patch (a patch that's been downloaded but has
 /// not been applied yet).
@@ -62,6 +101,9 @@ pub async fn patcher_thread_routine(
                 PatcherCommand::ApplyPatch(patch_file_path) => {
                     apply_single_patch(patch_file_path, &ui_controller, config);
                 }
+                PatcherCommand::ProbePatchServers => {
+                    probe_patch_servers(&ui_controller, config).await;
+                }
                 _ => {}
             },
         }
@@ -78,7 +120,7 @@ async fn update_game(
     match take_update_lock().with_context(|| "Failed to take the update lock") {
         Err(err) => {
             log::error!("{:#}", err);
-            ui_controller.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)));
+            ui_controller.dispatch_patching_status(PatchingStatus::Error(ErrorReport::new(&err)));
             return;
         }
         Ok(lock_file) => {
@@ -89,24 +131,61 @@ async fn update_game(
                 ui_controller.set_patch_in_progress(false);
             });
 
-            let res = interruptible_update_routine(ui_controller, config, patcher_thread_rx).await;
+            let mut unapplied_critical_patches = Vec::new();
+            let res = interruptible_update_routine(
+                ui_controller,
+                config,
+                &mut unapplied_critical_patches,
+                patcher_thread_rx,
+            )
+            .await;
             match res {
                 Err(err) => {
                     log::error!("{:#}", err);
-                    ui_controller
-                        .dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)));
+                    let mut report = ErrorReport::new(&err);
+                    report.critical_patch = unapplied_critical_patches.into_iter().next();
+                    if let Some(critical_patch) = &report.critical_patch {
+                        log::warn!("Critical patch '{}' hasn't been applied", critical_patch);
+                    }
+                    ui_controller.dispatch_patching_status(PatchingStatus::Error(report));
                     // Nota: play_with_error apenas habilita o botão Play no JavaScript,
                     // o jogo só será lançado quando o usuário clicar no botão.
                 }
-                Ok(()) => {
+                Ok(UpdateOutcome::UpToDate) => {
                     ui_controller.dispatch_patching_status(PatchingStatus::Ready);
                     log::info!("Patching finished!");
                 }
+                Ok(UpdateOutcome::Maintenance(message)) => {
+                    log::warn!("Game is under maintenance: {}", message);
+                    ui_controller.dispatch_patching_status(PatchingStatus::Maintenance(message));
+                }
             }
         }
     }
 }
 
+/// Probes all the patch servers and sends the results to the UI
+async fn probe_patch_servers(ui_controller: &UiController, config: &PatcherConfiguration) {
+    let signature_verifier = match SignatureVerifier::from_configuration(config.signatures.as_ref())
+    {
+        Err(e) => {
+            log::error!("Invalid signature configuration: {:#}", e);
+            return;
+        }
+        Ok(v) => v,
+    };
+    let probe_timeout = get_probe_timeout(config);
+    let probes = config.web.patch_servers.iter().map(|server| async {
+        let probe_res =
+            probe_patch_server_with_timeout(server, probe_timeout, &signature_verifier).await;
+        ui_controller.record_probe_result(PatchServerProbe::new(
+            &server.name,
+            probe_res.as_ref().map(|(_, latency)| *latency),
+        ));
+    });
+    futures::future::join_all(probes).await;
+}
+
 /// Applies a manual patch given by the user
 fn apply_single_patch(
     patch_file_path: impl AsRef<Path>,
@@ -117,7 +196,7 @@ fn apply_single_patch(
     match take_update_lock().with_context(|| "Failed to take the update lock") {
         Err(err) => {
             log::error!("{:#}", err);
-            ui_controller.dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)));
+            ui_controller.dispatch_patching_status(PatchingStatus::Error(ErrorReport::new(&err)));
         }
         Ok(lock_file) => {
             // Tell the UI and other processes that we're currently working
@@ -133,7 +212,7 @@ fn apply_single_patch(
                 Err(err) => {
                     log::error!("{:#}", err);
                     ui_controller
-                        .dispatch_patching_status(PatchingStatus::Error(format!("{:#}", err)));
+                        .dispatch_patching_status(PatchingStatus::Error(ErrorReport::new(&err)));
                 }
                 Ok(current_working_dir) => {
                     let patch_file_name = patch_file_path
@@ -144,14 +223,62 @@ fn apply_single_patch(
                         .unwrap_or_default()
                         .to_string();
                     log::info!("Applying patch '{}'", patch_file_name);
-                    let res = apply_patch(patch_file_path, config, current_working_dir);
+                    let patch_file = PatchFile {
+                        path: patch_file_path.as_ref(),
+                        target_grf_name: None,
+                    };
+                    let res = verify_manual_patch_signature(patch_file_path.as_ref(), config)
+                        .and_then(|_| {
+                            let files_in_use =
+                                check_game_files_in_use(config, &current_working_dir);
+                            match files_in_use.is_empty() {
+                                true => Ok(()),
+                                false => Err(PatcherError::FilesInUse(files_in_use).into()),
+                            }
+                        })
+                        .and_then(|_| {
+                            check_space_for_patches(&[patch_file], config, &current_working_dir)
+                        })
+                        .and_then(|config| {
+                            apply_patch(
+                                &patch_file_path,
+                                None,
+                                &config,
+                                &current_working_dir,
+                                installation_progress_callback(
+                                    ui_controller,
+                                    &patch_file_name,
+                                    0,
+                                    1,
+                                ),
+                            )
+                            .map_err(|source| {
+                                PatcherError::PatchFailed {
+                                    patch: patch_file_name.clone(),
+                                    source,
+                                }
+                                .into()
+                            })
+                        })
+                        .and_then(|_| {
+                            let applied_patch = AppliedPatch {
+                                name: &patch_file_name,
+                                index: None,
+                                path: patch_file_path.as_ref(),
+                            };
+                            run_after_patch_hooks(
+                                config,
+                                &current_working_dir,
+                                &applied_patch,
+                                &HookCanceler::default(),
+                            )
+                        });
                     match res {
                         Err(err) => {
                             log::error!("{:#}", err);
-                            ui_controller.dispatch_patching_status(PatchingStatus::Error(format!(
-                                "{:#}",
-                                err
-                            )));
+                            ui_controller.dispatch_patching_status(PatchingStatus::Error(
+                                ErrorReport::new(&err),
+                            ));
                         }
                         Ok(()) => {
                             log::info!("Done");
@@ -166,12 +293,52 @@ fn apply_single_patch(
     }
 }
 
+/// Waits until no game client is running and the game's GRFs aren't used by
+/// other programs, as they can't be patched safely otherwise. Nothing is
+/// waited for when there's no patch to apply.
+///
+/// Files are checked again periodically, or when the user asks to retry.
+/// `on_files_in_use` is called when the files that are in use change.
+/// This function is interruptible.
+async fn wait_for_game_files<F: FnMut(&GameFilesInUse)>(
+    patch_count: usize,
+    config: &PatcherConfiguration,
+    game_dir: &Path,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+    mut on_files_in_use: F,
+) -> InterruptibleFnResult<()> {
+    const CHECK_INTERVAL: Duration = Duration::from_secs(3);
+    if patch_count == 0 {
+        return Ok(());
+    }
+    let mut last_message = String::new();
+    loop {
+        let files_in_use = check_game_files_in_use(config, game_dir);
+        if files_in_use.is_empty() {
+            return Ok(());
+        }
+        let message = files_in_use.to_string();
+        if message != last_message {
+            log::warn!("Waiting for the game's files: {}", message);
+            on_files_in_use(&files_in_use);
+            last_message = message;
+        }
+        tokio::select! {
+            retry_res = wait_for_retry(patching_thread_rx) => retry_res?,
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+        }
+    }
+}
+
 /// Takes an advisory lock that prevents multiple instances of the patcher to
 /// update the game at the same time
 fn take_update_lock() -> Result<std::fs::File> {
     let lock_file_name = get_update_lock_file_path()?;
     let lock_file = std::fs::File::create(lock_file_name)?;
-    lock_file.try_lock()?;
+    lock_file.try_lock().map_err(|e| match e {
+        std::fs::TryLockError::WouldBlock => PatcherError::UpdateInProgress.into(),
+        std::fs::TryLockError::Error(e) => anyhow::Error::from(e),
+    })?;
 
     Ok(lock_file)
 }
@@ -180,31 +347,73 @@ fn take_update_lock() -> Result<std::fs::File> {
 ///
 /// This routine is written in a way that makes it interuptible (or cancellable)
 /// with a relatively low latency.
+///
+/// `unapplied_critical_patches` is filled with the names of the critical
+/// patches that remain to be applied, in order.
 async fn interruptible_update_routine(
     ui_controller: &UiController,
     config: &PatcherConfiguration,
+    unapplied_critical_patches: &mut Vec<String>,
     patcher_thread_rx: &mut flume::Receiver<PatcherCommand>,
-) -> Result<()> {
+) -> Result<UpdateOutcome> {
     log::info!("Start patching");
 
+    // Try to read cache
+    let cache_file_path =
+        get_cache_file_path().with_context(|| "Failed to resolve patcher name")?;
+    let cached_state = read_cache_file(&cache_file_path).await.ok();
+
+    let signature_verifier = SignatureVerifier::from_configuration(config.signatures.as_ref())
+        .with_context(|| "Invalid signature configuration")
+        .map_err(|source| PatcherError::InvalidConfiguration { source })?;
+
     // Find a patch server that we can connect to
     log::info!("Looking for an available patch server ...");
-    let (mut patch_list, patch_data_url) = find_available_patch_server(
+    let probe_timeout = get_probe_timeout(config);
+    let user_settings = UserSettings::load();
+    // The server selected by the player takes precedence over the configuration
+    let preferred_patch_server = user_settings.preferred_patch_server(config);
+    let patch_server = find_available_patch_server(
         config.web.patch_servers.as_slice(),
-        &config.web.preferred_patch_server,
+        &preferred_patch_server,
+        cached_state
+            .as_ref()
+            .and_then(|cache| cache.last_patch_server.as_deref()),
+        probe_timeout,
+        &signature_verifier,
         patcher_thread_rx,
+        |probe| ui_controller.record_probe_result(probe),
     )
     .await
     .map_err(|e| match e {
-        InterruptibleFnError::Err(msg) => anyhow!(msg),
-        InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
+        InterruptibleFnError::Err(e) => e,
+        InterruptibleFnError::Interrupted => PatcherError::Canceled.into(),
     })?;
+    log::info!("Using patch server '{}'", patch_server.name);
+    let mut patch_list = patch_server.patch_list;
     log::debug!("Successfully fetched patch list: {:?}", patch_list);
 
-    // Try to read cache
-    let cache_file_path =
-        get_cache_file_path().with_context(|| "Failed to resolve patcher name")?;
-    if let Ok(patcher_cache) = read_cache_file(&cache_file_path).await {
+    // Act on the server's directives
+    let control = patch_server.control;
+    if let Some(message) = control.maintenance {
+        return Ok(UpdateOutcome::Maintenance(message));
+    }
+    if let Some(notice) = &control.notice {
+        ui_controller.dispatch_notice(notice);
+    }
+    let cached_state = cached_state.filter(|patcher_cache| {
+        let reset_cache = control.requires_cache_reset(patcher_cache);
+        if reset_cache {
+            log::info!("Patch server requested a cache reset, all patches will be applied");
+        }
+        !reset_cache
+    });
+
+    if let Some(patcher_cache) = &cached_state {
+        // A signed patch list can be replayed, make sure it isn't an old one
+        if signature_verifier.is_required() {
+            ensure_patch_list_is_current(&patch_list, patcher_cache.last_patch_index)?;
+        }
         // Ignore already applied patches if needed
         // First we verify that our cached index looks relevant
         let should_filter_patch_list = patch_list
@@ -214,24 +423,92 @@ async fn interruptible_update_routine(
             patch_list.retain(|x| x.index > patcher_cache.last_patch_index);
         }
     };
+    patch_list.retain(|patch_info| {
+        let is_blocked = control.is_patch_blocked(patch_info);
+        if is_blocked {
+            log::info!("Skipping blocked patch '{}'", patch_info.file_name);
+        }
+        !is_blocked
+    });
+    *unapplied_critical_patches = patch_list
+        .iter()
+        .filter(|patch_info| patch_info.critical)
+        .map(|patch_info| patch_info.file_name.clone())
+        .collect();
+
+    // Remember the selected server for the next runs, even if no patch gets
+    // applied
+    let mut patcher_cache = cached_state.unwrap_or_default();
+    if patcher_cache.last_patch_server.as_ref() != Some(&patch_server.name) {
+        patcher_cache.last_patch_server = Some(patch_server.name);
+        if let Err(e) = write_cache_file(&cache_file_path, patcher_cache.clone()).await {
+            log::warn!("Failed to write cache file: {}.", e);
+        }
+    }
+    // Saved along with the next applied patch
+    patcher_cache.last_full_repair = control.full_repair;
 
     // Try fetching patch files
-    log::info!("Downloading patches ...");
-    let patch_url =
-        Url::parse(patch_data_url.as_str()).with_context(|| "Failed to parse 'patch_url'")?;
     let tmp_dir = tempfile::tempdir().with_context(|| "Failed to create temporary directory")?;
+    if config.patching.check_disk_space.unwrap_or(true) {
+        ensure_space_for_download(
+            &patch_server.client,
+            &patch_server.patch_location,
+            &patch_list,
+            tmp_dir.path(),
+            patcher_thread_rx,
+        )
+        .await
+        .map_err(|e| match e {
+            InterruptibleFnError::Err(e) => e.context("Failed to download patches"),
+            InterruptibleFnError::Interrupted => PatcherError::Canceled.into(),
+        })?;
+    }
+    if !patch_list.is_empty() {
+        let update_variables = [("KPATCHER_PATCH_COUNT", patch_list.len().to_string())];
+        let hook_config = config.clone();
+        let game_dir =
+            env::current_dir().with_context(|| "Failed to resolve current working directory")?;
+        run_interruptible_hooks(
+            move |canceler| {
+                run_hooks(
+                    HookEvent::BeforeUpdate,
+                    &hook_config,
+                    &game_dir,
+                    &update_variables,
+                    canceler,
+                )
+            },
+            patcher_thread_rx,
+        )
+        .await
+        .map_err(|e| match e {
+            InterruptibleFnError::Err(e) => e,
+            InterruptibleFnError::Interrupted => PatcherError::Canceled.into(),
+        })?;
+    }
+    log::info!("Downloading patches ...");
+    let bandwidth_limiter = BandwidthLimiter::from_kib_per_sec(user_settings.bandwidth_limit);
+    if let Some(limit) = user_settings.bandwidth_limit.filter(|&limit| limit > 0) {
+        log::info!("Download speed is limited to {} KiB/s", limit);
+    }
     let pending_patch_queue = download_patches_concurrent(
-        patch_url,
+        &patch_server.client,
+        patch_server.patch_location,
         patch_list,
         tmp_dir.path(),
-        config.patching.check_integrity,
+        DownloadOptions {
+            check_integrity: config.patching.check_integrity,
+            signature_verifier: &signature_verifier,
+            bandwidth_limiter: bandwidth_limiter.as_ref(),
+        },
         &ui_controller,
         patcher_thread_rx,
     )
     .await
     .map_err(|e| match e {
-        InterruptibleFnError::Err(msg) => anyhow!("Failed to download patches: {}", msg),
-        InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
+        InterruptibleFnError::Err(e) => e.context("Failed to download patches"),
+        InterruptibleFnError::Interrupted => PatcherError::Canceled.into(),
     })?;
     log::info!("Patches have been downloaded");
 
@@ -241,36 +518,55 @@ async fn interruptible_update_routine(
         pending_patch_queue,
         config,
         &cache_file_path,
+        patcher_cache,
+        unapplied_critical_patches,
         &ui_controller,
         patcher_thread_rx,
     )
     .await
     .map_err(|e| match e {
-        InterruptibleFnError::Err(msg) => anyhow!("Failed to apply patches: {}", msg),
-        InterruptibleFnError::Interrupted => anyhow!("Patching was canceled"),
+        InterruptibleFnError::Err(e) => e.context("Failed to apply patches"),
+        InterruptibleFnError::Interrupted => PatcherError::Canceled.into(),
     })?;
     log::info!("Patches have been applied");
 
-    Ok(())
+    Ok(UpdateOutcome::UpToDate)
 }
 
-/// Iterates through `server_list` and returns the first available server's info.
-/// `preferred_server_name` is checked first if present.
-async fn find_available_patch_server(
+/// Probes the servers of `server_list` concurrently and returns the info of
+/// the available server that answered the fastest.
+///
+/// `preferred_server_name` is checked first if present and is used if it's
+/// available. `last_server_name` (the server used during the previous run) is
+/// kept if its latency is close to the best one.
+///
+/// `on_probe` is called with the result of each probe.
+async fn find_available_patch_server<F: FnMut(PatchServerProbe)>(
     server_list: &[PatchServerInfo],
     preferred_server_name: &Option<String>,
+    last_server_name: Option<&str>,
+    probe_timeout: Duration,
+    signature_verifier: &SignatureVerifier,
     patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
-) -> InterruptibleFnResult<(ThorPatchList, Url)> {
+    mut on_probe: F,
+) -> InterruptibleFnResult<AvailablePatchServer> {
     // Probe the preferred server first if it's specified and valid
     if let Some(preferred_server_name) = preferred_server_name {
         let preferred_server = server_list
             .iter()
             .find(|s| &s.name == preferred_server_name);
         if let Some(preferred_server) = preferred_server {
-            if let Ok((patch_list, patch_url)) = probe_patch_server(preferred_server).await {
-                return Ok((patch_list, patch_url));
-            } else {
-                log::warn!("'{}' is unavailable", preferred_server_name);
+            let probe_res = tokio::select! {
+                cancel_res = wait_for_cancellation(patching_thread_rx) => return Err(cancel_res),
+                probe_res = probe_patch_server_with_timeout(preferred_server, probe_timeout, signature_verifier) => probe_res,
+            };
+            on_probe(PatchServerProbe::new(
+                preferred_server_name,
+                probe_res.as_ref().map(|(_, latency)| *latency),
+            ));
+            match probe_res {
+                Ok((patch_server, _)) => return Ok(patch_server),
+                Err(e) => log::warn!("'{}' is unavailable: {:#}", preferred_server_name, e),
             }
         } else {
             log::warn!(
@@ -280,72 +576,272 @@ async fn find_available_patch_server(
         }
     }
 
-    // Probe other servers, if any
-    for server in server_list {
-        // Cancel the patching process if we've been asked to or if the other
-        // end of the channel has been disconnected
-        process_incoming_commands(patching_thread_rx)?;
-        if let Ok((patch_list, patch_url)) = probe_patch_server(server).await {
-            return Ok((patch_list, patch_url));
-        } else {
-            log::warn!("'{}' is unavailable", server.name);
+    // Probe other servers, if any, all at once
+    let other_servers = server_list
+        .iter()
+        .filter(|s| Some(&s.name) != preferred_server_name.as_ref());
+    let probes =
+        futures::future::join_all(other_servers.map(|server| {
+            probe_patch_server_with_timeout(server, probe_timeout, signature_verifier)
+        }));
+    // Cancel the patching process if we've been asked to or if the other
+    // end of the channel has been disconnected
+    let probe_results = tokio::select! {
+        cancel_res = wait_for_cancellation(patching_thread_rx) => return Err(cancel_res),
+        probe_results = probes => probe_results,
+    };
+    let mut available_servers = Vec::with_capacity(probe_results.len());
+    for (server, probe_res) in server_list
+        .iter()
+        .filter(|s| Some(&s.name) != preferred_server_name.as_ref())
+        .zip(probe_results)
+    {
+        on_probe(PatchServerProbe::new(
+            &server.name,
+            probe_res.as_ref().map(|(_, latency)| *latency),
+        ));
+        match probe_res {
+            Ok((patch_server, latency)) => {
+                log::info!("'{}' answered in {} ms", server.name, latency.as_millis());
+                available_servers.push((patch_server, latency));
+            }
+            Err(e) => log::warn!("'{}' is unavailable: {:#}", server.name, e),
+        }
+    }
+
+    let latencies: Vec<(&str, Duration)> = available_servers
+        .iter()
+        .map(|(server, latency)| (server.name.as_str(), *latency))
+        .collect();
+    match pick_fastest_server(&latencies, last_server_name) {
+        Some(i) => Ok(available_servers.swap_remove(i).0),
+        None => Err(InterruptibleFnError::Err(
+            PatcherError::NoPatchServerAvailable.into(),
+        )),
+    }
+}
+
+/// Returns the index of the server to use among `latencies`.
+///
+/// The server with the lowest latency is chosen, unless `last_server_name` is
+/// available and within `LAST_SERVER_LATENCY_TOLERANCE` of it. This avoids
+/// switching mirrors between runs because of small latency variations.
+fn pick_fastest_server(
+    latencies: &[(&str, Duration)],
+    last_server_name: Option<&str>,
+) -> Option<usize> {
+    let (fastest_index, fastest_latency) = latencies
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, (_, latency))| *latency)
+        .map(|(i, (_, latency))| (i, *latency))?;
+    let last_server = latencies
+        .iter()
+        .enumerate()
+        .find(|(_, (name, _))| Some(*name) == last_server_name);
+    if let Some((last_index, (_, last_latency))) = last_server {
+        if last_latency.as_secs_f64()
+            <= fastest_latency.as_secs_f64() * LAST_SERVER_LATENCY_TOLERANCE
+        {
+            return Some(last_index);
         }
     }
+    Some(fastest_index)
+}
+
+fn get_probe_timeout(config: &PatcherConfiguration) -> Duration {
+    config
+        .web
+        .probe_timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_PROBE_TIMEOUT)
+}
 
-    Err(InterruptibleFnError::Err(
-        "None of the patch servers are available at the moment".to_string(),
-    ))
+/// Probes a patch server and measures its latency.
+async fn probe_patch_server_with_timeout(
+    server_info: &PatchServerInfo,
+    probe_timeout: Duration,
+    signature_verifier: &SignatureVerifier,
+) -> Result<(AvailablePatchServer, Duration)> {
+    let start = Instant::now();
+    let probe = probe_patch_server(server_info, signature_verifier);
+    let patch_server = tokio::time::timeout(probe_timeout, probe)
+        .await
+        .map_err(|_| anyhow!("Timed out after {} s", probe_timeout.as_secs()))??;
+    Ok((patch_server, start.elapsed()))
 }
 
 /// Checks whether a patch server is up or not.
-/// Returns the list of patches served by the server as well as the URL to
-/// download them from.
-async fn probe_patch_server(server_info: &PatchServerInfo) -> Result<(ThorPatchList, Url)> {
-    let client = reqwest::Client::new();
-    // Parse URLs
-    let patch_list_url = Url::parse(server_info.plist_url.as_str())
+/// Returns the list of patches served by the server as well as the location
+/// to download them from and the HTTP client to use.
+async fn probe_patch_server(
+    server_info: &PatchServerInfo,
+    signature_verifier: &SignatureVerifier,
+) -> Result<AvailablePatchServer> {
+    let client = build_http_client(server_info)?;
+    // Parse locations
+    let patch_list_location = PatchLocation::parse(server_info.plist_url.as_str())
         .with_context(|| "Failed to parse 'plist_url'")?;
-    let patch_url = Url::parse(server_info.patch_url.as_str())
+    let patch_location = PatchLocation::parse(server_info.patch_url.as_str())
         .with_context(|| "Failed to parse 'patch_url'")?;
 
     // Fetch plist
-    let patch_list = fetch_patch_list(patch_list_url)
+    let control = match &server_info.control_url {
+        None => ControlFile::default(),
+        Some(control_url) => {
+            let control_location = PatchLocation::parse(control_url)?;
+            fetch_control_file(&client, &control_location, signature_verifier)
+                .await
+                .with_context(|| format!("Failed to retrieve control file '{}'", control_url))?
+        }
+    };
+    let patch_list = fetch_patch_list(&client, &patch_list_location, signature_verifier)
         .await
         .with_context(|| "Failed to retrieve the patch list")?;
 
     // Ensure that the server serves the patches (check the first patch of the list)
     if let Some(patch_info) = patch_list.get(0) {
-        let patch_resp = client
-            .head(patch_url.join(patch_info.file_name.as_str())?)
-            .send()
-            .await
-            .with_context(|| "Failed to HEAD URL")?;
-        // Return on error
-        patch_resp.error_for_status()?;
+        match patch_location.join(patch_info.file_name.as_str())? {
+            PatchLocation::Remote(patch_file_url) => {
+                let patch_resp = client
+                    .head(patch_file_url)
+                    .send()
+                    .await
+                    .with_context(|| "Failed to HEAD URL")?;
+                // Return on error
+                patch_resp.error_for_status()?;
+            }
+            PatchLocation::Local(patch_file_path) => {
+                if !patch_file_path.is_file() {
+                    return Err(anyhow!("'{}' not found", patch_file_path.display()));
+                }
+            }
+        }
     }
 
-    Ok((patch_list, patch_url))
+    Ok(AvailablePatchServer {
+        name: server_info.name.clone(),
+        patch_list,
+        patch_location,
+        control,
+        client,
+    })
 }
 
-/// Downloads and parses a 'plist.txt' file located as the URL contained in the
-/// `patch_list_url` argument.
+/// Retrieves and parses a 'plist.txt' file located at `patch_list_location`,
+/// after checking its signature.
 ///
 /// Returns a vector of `ThorPatchInfo` in case of success.
-async fn fetch_patch_list(patch_list_url: Url) -> Result<ThorPatchList> {
-    let resp = reqwest::get(patch_list_url)
+async fn fetch_patch_list(
+    client: &reqwest::Client,
+    patch_list_location: &PatchLocation,
+    signature_verifier: &SignatureVerifier,
+) -> Result<ThorPatchList> {
+    let patch_index_content = match patch_list_location {
+        PatchLocation::Remote(patch_list_url) => {
+            fetch_remote_patch_list(client, patch_list_url.clone()).await?
+        }
+        PatchLocation::Local(patch_list_path) => {
+            let read_res = tokio::fs::read_to_string(patch_list_path).await;
+            read_res.with_context(|| format!("Failed to read '{}'", patch_list_path.display()))?
+        }
+    };
+    if signature_verifier.is_enabled() {
+        let signature_location = patch_list_location.with_suffix(SIGNATURE_FILE_SUFFIX);
+        let signature = fetch_optional_file(client, &signature_location).await?;
+        signature_verifier.verify(
+            &patch_list_location.file_name(),
+            patch_index_content.as_bytes(),
+            signature.as_deref(),
+        )?;
+    }
+    log::info!("Parsing patch index...");
+
+    Ok(thor::patch_list_from_string(patch_index_content.as_str()))
+}
+
+/// Refuses a patch list whose newest patch is older than the last applied
+/// patch.
+fn ensure_patch_list_is_current(patch_list: &ThorPatchList, last_patch_index: usize) -> Result<()> {
+    let newest_index = patch_list
+        .iter()
+        .map(|patch_info| patch_info.index)
+        .max()
+        .unwrap_or(0);
+    if newest_index < last_patch_index {
+        return Err(PatcherError::OutdatedPatchList {
+            newest_index,
+            last_patch_index,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Retrieves and parses the control file located at `control_location`, after
+/// checking its signature. A missing control file contains no directives.
+async fn fetch_control_file(
+    client: &reqwest::Client,
+    control_location: &PatchLocation,
+    signature_verifier: &SignatureVerifier,
+) -> Result<ControlFile> {
+    let content = match fetch_optional_file(client, control_location).await? {
+        None => return Ok(ControlFile::default()),
+        Some(v) => v,
+    };
+    if signature_verifier.is_enabled() {
+        let signature_location = control_location.with_suffix(SIGNATURE_FILE_SUFFIX);
+        let signature = fetch_optional_file(client, &signature_location).await?;
+        signature_verifier.verify(
+            &control_location.file_name(),
+            content.as_slice(),
+            signature.as_deref(),
+        )?;
+    }
+    ControlFile::from_slice(&content)
+}
+
+/// Retrieves the content of a small file, `None` is returned if it doesn't
+/// exist.
+async fn fetch_optional_file(
+    client: &reqwest::Client,
+    location: &PatchLocation,
+) -> Result<Option<Vec<u8>>> {
+    match location {
+        PatchLocation::Remote(url) => {
+            let resp = client
+                .get(url.clone())
+                .send()
+                .await
+                .with_context(|| format!("Failed to GET '{}'", url))?;
+            if resp.status() == reqwest::StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            Ok(Some(resp.error_for_status()?.bytes().await?.to_vec()))
+        }
+        PatchLocation::Local(path) => match tokio::fs::read(path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(anyhow!("Failed to read '{}': {}", path.display(), e)),
+        },
+    }
+}
+
+/// Downloads the content of a 'plist.txt' file located at `patch_list_url`.
+async fn fetch_remote_patch_list(client: &reqwest::Client, patch_list_url: Url) -> Result<String> {
+    let resp = client
+        .get(patch_list_url)
+        .send()
         .await
         .with_context(|| "Failed to GET URL")?;
     if !resp.status().is_success() {
         return Err(anyhow!("Patch list file not found on the remote server"));
     }
-    let patch_index_content = resp.text().await.with_context(|| "Invalid responde body")?;
-    log::info!("Parsing patch index...");
-
-    Ok(thor::patch_list_from_string(patch_index_content.as_str()))
+    resp.text().await.with_context(|| "Invalid responde body")
 }
 
 /// Returns the patcher cache file's name as a `PathBuf` on success.
-fn get_cache_file_path() -> Result<PathBuf> {
+pub(super) fn get_cache_file_path() -> Result<PathBuf> {
     get_instance_asset_file_name("dat")
 }
 
@@ -362,27 +858,122 @@ fn get_instance_asset_file_name(extension: impl AsRef<std::ffi::OsStr>) -> Resul
     Ok(PathBuf::from(patcher_name).with_extension(extension))
 }
 
+/// Ensures that the patches of `patch_list` can be downloaded into
+/// `download_directory`.
+///
+/// This function is interruptible.
+async fn ensure_space_for_download(
+    client: &reqwest::Client,
+    patch_location: &PatchLocation,
+    patch_list: &ThorPatchList,
+    download_directory: &Path,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<()> {
+    let download_size = tokio::select! {
+        cancel_res = wait_for_cancellation(patching_thread_rx) => return Err(cancel_res),
+        download_size = fetch_download_size(client, patch_location, patch_list) => download_size,
+    };
+    log::info!("Total download size: {} bytes", download_size);
+    check_space_for_download(download_directory, download_size).map_err(InterruptibleFnError::Err)
+}
+
+/// Retrieves the total size of the patches of `patch_list`, using the sizes
+/// given in the patch list when available.
+///
+/// Patches whose size couldn't be retrieved are ignored.
+async fn fetch_download_size(
+    client: &reqwest::Client,
+    patch_location: &PatchLocation,
+    patch_list: &ThorPatchList,
+) -> u64 {
+    const CONCURRENT_REQUESTS: usize = 32;
+    futures::stream::iter(patch_list.iter().map(|patch_info| async move {
+        if let Some(patch_size) = patch_info.size {
+            return patch_size;
+        }
+        fetch_patch_size(client, patch_location, &patch_info.file_name)
+            .await
+            .unwrap_or_else(|e| {
+                log::warn!(
+                    "Failed to retrieve the size of '{}': {:#}",
+                    patch_info.file_name,
+                    e
+                );
+                0
+            })
+    }))
+    .buffer_unordered(CONCURRENT_REQUESTS)
+    .fold(0, |total_size, patch_size| async move {
+        total_size + patch_size
+    })
+    .await
+}
+
+async fn fetch_patch_size(
+    client: &reqwest::Client,
+    patch_location: &PatchLocation,
+    file_name: &str,
+) -> Result<u64> {
+    match patch_location.join(file_name)? {
+        PatchLocation::Remote(patch_file_url) => {
+            let resp = client
+                .head(patch_file_url)
+                .send()
+                .await?
+                .error_for_status()?;
+            // `Response::content_length` isn't reliable for HEAD requests
+            resp.headers()
+                .get(reqwest::header::CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| anyhow!("Missing Content-Length header"))
+        }
+        PatchLocation::Local(patch_file_path) => {
+            Ok(tokio::fs::metadata(patch_file_path).await?.len())
+        }
+    }
+}
+
+/// Options of patch downloads, and checks performed on downloaded patches
+/// before they're applied.
+#[derive(Clone, Copy)]
+struct DownloadOptions<'a> {
+    check_integrity: bool, // Check archives' integrity
+    signature_verifier: &'a SignatureVerifier,
+    bandwidth_limiter: Option<&'a BandwidthLimiter>, // Limits the download speed
+}
+
 /// Downloads a list of patches (described with a `ThorPatchList`).
 ///
-/// Files are downloaded from the remote directory located at the URL
-/// contained in the 'patch_url' argument.
+/// Files are downloaded from the remote or local directory located at
+/// `patch_location`.
 ///
-/// This function is interruptible.
+/// This function is interruptible and downloads can be paused.
 async fn download_patches_concurrent(
-    patch_url: Url,
+    client: &reqwest::Client,
+    patch_location: PatchLocation,
     patch_list: ThorPatchList,
     download_directory: impl AsRef<Path>,
-    ensure_integrity: bool,
+    download_options: DownloadOptions<'_>,
     ui_controller: &UiController,
     patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
 ) -> InterruptibleFnResult<Vec<PendingPatch>> {
     let patch_count = patch_list.len();
     ui_controller.dispatch_patching_status(PatchingStatus::DownloadInProgress(0, patch_count, 0));
+    let (pause_tx, pause_rx) = watch::channel(false);
+    let on_pause_change = |paused| {
+        if paused {
+            log::info!("Downloads paused");
+            ui_controller.dispatch_patching_status(PatchingStatus::Paused);
+        } else {
+            log::info!("Downloads resumed");
+        }
+    };
     // Download files in a cancelable manner
     let mut vec = tokio::select! {
-        cancel_res = wait_for_cancellation(patching_thread_rx) => return Err(cancel_res),
-        download_res = download_patches_concurrent_inner(patch_url, patch_list, download_directory, ensure_integrity, ui_controller) => {
-            download_res.map_err(|e| InterruptibleFnError::Err(format!("{:#}", e)))
+        cancel_res = wait_for_cancellation_or_pause(patching_thread_rx, &pause_tx, on_pause_change) => return Err(cancel_res),
+        download_res = download_patches_concurrent_inner(client, patch_location, patch_list, download_directory, download_options, PauseGate::new(pause_rx), ui_controller) => {
+            download_res.map_err(InterruptibleFnError::Err)
         },
     }?;
     // Sort patches by index before returning
@@ -390,32 +981,59 @@ async fn download_patches_concurrent(
     Ok(vec)
 }
 
+/// Progress of concurrent downloads
+struct DownloadProgressState {
+    downloaded_bytes: u64,
+    total_bytes: u64, // Grows when patches of unknown size start downloading
+    // Used to compute the download speed
+    window_start: Instant,
+    window_bytes: u64,
+}
+
+impl DownloadProgressState {
+    fn new() -> Self {
+        Self {
+            downloaded_bytes: 0,
+            total_bytes: 0,
+            window_start: Instant::now(),
+            window_bytes: 0,
+        }
+    }
+
+    /// Estimated time remaining, in seconds.
+    fn eta_secs(&self, bytes_per_sec: u64) -> Option<u64> {
+        if bytes_per_sec == 0 || self.total_bytes < self.downloaded_bytes {
+            return None;
+        }
+        Some((self.total_bytes - self.downloaded_bytes) / bytes_per_sec)
+    }
+}
+
 /// Actual implementation of the concurrent file download
 ///
 /// Returns an unordered vector of `PendingPatch`.
 async fn download_patches_concurrent_inner(
-    patch_url: Url,
+    client: &reqwest::Client,
+    patch_location: PatchLocation,
     patch_list: ThorPatchList,
     download_directory: impl AsRef<Path>,
-    ensure_integrity: bool,
+    download_options: DownloadOptions<'_>,
+    pause_gate: PauseGate,
     ui_controller: &UiController,
 ) -> Result<Vec<PendingPatch>> {
     const CONCURRENT_DOWNLOADS: usize = 32;
     const ONE_SECOND: Duration = Duration::from_secs(1);
-    // Shared reqwest client
-    let client = reqwest::Client::new();
     // Shared value that contains the number of downloaded patches
     let shared_patch_number = AtomicUsize::new(0_usize);
-    // Shared tuple that's used to compute the download speed
-    let shared_progress_state = Arc::new(std::sync::Mutex::new((Instant::now(), 0_u64)));
+    // Shared state that's used to compute the download speed and progress
+    let shared_progress_state = Arc::new(std::sync::Mutex::new(DownloadProgressState {
+        total_bytes: patch_list.iter().filter_map(|patch| patch.size).sum(),
+        ..DownloadProgressState::new()
+    }));
 
     // Collect stream of "PendingPatch" concurrently with an unordered_buffer
     let patch_count = patch_list.len();
     futures::stream::iter(patch_list.into_iter().map(|patch_info| async {
-        let client = &client;
-        let patch_file_url = patch_url
-            .join(patch_info.file_name.as_str())
-            .with_context(|| "Failed to generate URL for patch file")?;
         let local_file_path = download_directory
             .as_ref()
             .join(patch_info.file_name.as_str());
@@ -423,67 +1041,98 @@ async fn download_patches_concurrent_inner(
             .await
             .with_context(|| "Failed to create temporary file")?;
 
-        // Setup a progress callback that'll send the current download speed to the UI
+        // Setup a progress callback that'll send the current download speed
+        // and progress to the UI
         let shared_patch_number_ref = &shared_patch_number;
         let shared_state = shared_progress_state.clone();
+        let patch_name = patch_info.file_name.as_str();
+        let mut size_is_known = patch_info.size.is_some();
         let mut last_downloaded_bytes: u64 = 0;
-        let mut progress_callback = move |dl_now, _| {
-            let dl_delta = dl_now - last_downloaded_bytes;
-            // Return download speed if the required time has elapsed (1s)
-            let downloaded_bytes_per_sec = {
-                if let Ok(mut shared_state) = shared_state.lock() {
-                    shared_state.1 += dl_delta;
-                    if shared_state.0.elapsed() >= ONE_SECOND {
-                        let downloaded_bytes_per_sec = (shared_state.1 as f32
-                            / shared_state.0.elapsed().as_secs_f32())
-                        .round() as u64;
-                        shared_state.0 = Instant::now();
-                        shared_state.1 = 0;
-                        Some(downloaded_bytes_per_sec)
-                    } else {
-                        None
-                    }
-                } else {
-                    None
+        let mut progress_callback = move |dl_now: u64, dl_total: u64| {
+            if let Ok(mut shared_state) = shared_state.lock() {
+                if !size_is_known && dl_total > 0 {
+                    shared_state.total_bytes += dl_total;
+                    size_is_known = true;
+                }
+                // Downloads start over when the server can't resume them
+                shared_state.downloaded_bytes =
+                    (shared_state.downloaded_bytes + dl_now).saturating_sub(last_downloaded_bytes);
+                shared_state.window_bytes += dl_now.saturating_sub(last_downloaded_bytes);
+                // If speed is "available" (1s elapsed), update UI
+                if shared_state.window_start.elapsed() >= ONE_SECOND {
+                    let downloaded_bytes_per_sec = (shared_state.window_bytes as f32
+                        / shared_state.window_start.elapsed().as_secs_f32())
+                    .round() as u64;
+                    shared_state.window_start = Instant::now();
+                    shared_state.window_bytes = 0;
+
+                    let downloaded_patches = shared_patch_number_ref.load(Ordering::SeqCst);
+                    ui_controller.dispatch_patching_status(PatchingStatus::DownloadInProgress(
+                        downloaded_patches,
+                        patch_count,
+                        downloaded_bytes_per_sec,
+                    ));
+                    ui_controller.dispatch_progress(&PatchingProgress::Download {
+                        patch_name: patch_name.to_string(),
+                        downloaded_patches,
+                        total_patches: patch_count,
+                        downloaded_bytes: shared_state.downloaded_bytes,
+                        total_bytes: shared_state.total_bytes,
+                        bytes_per_sec: downloaded_bytes_per_sec,
+                        eta_secs: shared_state.eta_secs(downloaded_bytes_per_sec),
+                    });
                 }
-            };
-            // If speed is "available", update UI
-            if let Some(downloaded_bytes_per_sec) = downloaded_bytes_per_sec {
-                ui_controller.dispatch_patching_status(PatchingStatus::DownloadInProgress(
-                    shared_patch_number_ref.load(Ordering::SeqCst),
-                    patch_count,
-                    downloaded_bytes_per_sec,
-                ));
             }
             last_downloaded_bytes = dl_now;
         };
 
         download_patch_to_file(
             client,
-            &patch_file_url,
+            &patch_location,
             &patch_info,
             &mut tmp_file,
+            pause_gate.clone(),
+            download_options.bandwidth_limiter,
             &mut progress_callback,
         )
-        .await?;
+        .await
+        .map_err(|source| PatcherError::DownloadFailed {
+            patch: patch_info.file_name.clone(),
+            source,
+        })?;
 
-        // Check the archive's integrity if required
-        if ensure_integrity {
-            let path_to_check = local_file_path.clone();
-            let validity_check = tokio::task::spawn_blocking(move || is_archive_valid(&path_to_check))
-                .await
-                .map_err(|e| anyhow!("Integrity check task failed: {}", e))?;
+        // Check the patch against the size and digest given in the patch list
+        let path_to_check = local_file_path.clone();
+        let info_to_check = patch_info.clone();
+        tokio::task::spawn_blocking(move || verify_patch_file(&path_to_check, &info_to_check))
+            .await
+            .map_err(|e| anyhow!("Verification task failed: {}", e))??;
 
-            let context = || {
-                format!(
-                    "Failed to check archive's integrity: '{}'",
-                    patch_info.file_name
-                )
-            };
+        // Check the patch's signature
+        if download_options.signature_verifier.is_enabled() {
+            let signature_location = patch_location
+                .join(patch_info.file_name.as_str())?
+                .with_suffix(SIGNATURE_FILE_SUFFIX);
+            let signature = fetch_optional_file(client, &signature_location).await?;
+            let path_to_check = local_file_path.clone();
+            let patch_name = patch_info.file_name.clone();
+            let verifier = download_options.signature_verifier.clone();
+            tokio::task::spawn_blocking(move || {
+                verify_patch_file_signature(&verifier, &patch_name, &path_to_check, signature)
+            })
+            .await
+            .map_err(|e| anyhow!("Signature verification task failed: {}", e))??;
+        }
 
-            if !validity_check.with_context(context)? {
-                return Err(anyhow!("Archive '{}' is corrupt", patch_info.file_name));
-            }
+        // Check the archive's integrity if required
+        if download_options.check_integrity {
+            let path_to_check = local_file_path.clone();
+            let patch_name = patch_info.file_name.clone();
+            tokio::task::spawn_blocking(move || {
+                check_archive_integrity(&patch_name, &path_to_check)
+            })
+            .await
+            .map_err(|e| anyhow!("Integrity check task failed: {}", e))??;
         }
 
         // Update status
@@ -500,69 +1149,317 @@ async fn download_patches_concurrent_inner(
     .await
 }
 
-fn is_archive_valid(archive_path: impl AsRef<Path>) -> Result<bool> {
-    let mut archive =
-        ThorArchive::open(archive_path.as_ref()).with_context(|| "Failed to open archive")?;
-    match archive.is_valid() {
-        Err(e) => {
-            if let GrufError::EntryNotFound = e {
-                // No integrity file present, consider the archive valid
-                Ok(true)
-            } else {
-                // Only consider this an error if the integrity file was found
-                Err(anyhow!(
-                    "Archive's integrity file is invalid: {}",
-                    e.to_string(),
-                ))
+/// Checks a downloaded patch against the size and SHA-256 digest given in the
+/// patch list, if any.
+fn verify_patch_file(patch_file_path: &Path, patch_info: &ThorPatchInfo) -> Result<()> {
+    if let Some(expected_size) = patch_info.size {
+        let size = fs::metadata(patch_file_path)?.len();
+        if size != expected_size {
+            return Err(PatcherError::CorruptPatch {
+                patch: patch_info.file_name.clone(),
+                reason: format!("expected {} bytes, got {}", expected_size, size),
             }
+            .into());
         }
-        Ok(v) => Ok(v),
     }
+    if let Some(expected_digest) = &patch_info.sha256 {
+        let mut patch_file = fs::File::open(patch_file_path)?;
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut patch_file, &mut hasher)?;
+        if hasher.finalize()[..] != expected_digest[..] {
+            return Err(PatcherError::CorruptPatch {
+                patch: patch_info.file_name.clone(),
+                reason: "SHA-256 mismatch".to_string(),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Checks the signature of a manually applied patch, which is expected to be
+/// stored next to it (e.g. 'patch.thor.sig').
+fn verify_manual_patch_signature(
+    patch_file_path: &Path,
+    config: &PatcherConfiguration,
+) -> Result<()> {
+    let signature_verifier = SignatureVerifier::from_configuration(config.signatures.as_ref())
+        .with_context(|| "Invalid signature configuration")?;
+    if !signature_verifier.is_enabled() {
+        return Ok(());
+    }
+    let mut signature_path = patch_file_path.as_os_str().to_owned();
+    signature_path.push(SIGNATURE_FILE_SUFFIX);
+    let signature = match fs::read(&signature_path) {
+        Ok(content) => Some(content),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let patch_name = patch_file_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    verify_patch_file_signature(&signature_verifier, &patch_name, patch_file_path, signature)
+}
+
+fn verify_patch_file_signature(
+    signature_verifier: &SignatureVerifier,
+    patch_name: &str,
+    patch_file_path: &Path,
+    signature: Option<Vec<u8>>,
+) -> Result<()> {
+    let patch_file = fs::File::open(patch_file_path)?;
+    signature_verifier.verify(patch_name, patch_file, signature.as_deref())
+}
+
+/// Checks an archive against its integrity file, if any. The returned error
+/// names the entries that are corrupt.
+fn check_archive_integrity(archive_name: &str, archive_path: impl AsRef<Path>) -> Result<()> {
+    let context = || format!("Failed to check archive's integrity: '{}'", archive_name);
+    let mut archive = ThorArchive::open(archive_path.as_ref())
+        .with_context(|| "Failed to open archive")
+        .with_context(context)?;
+    let report = match archive.check_integrity() {
+        // No integrity file present, consider the archive valid
+        Err(GrufError::EntryNotFound) => return Ok(()),
+        // Only consider this an error if the integrity file was found
+        Err(e) => {
+            return Err(anyhow!("Archive's integrity file is invalid: {}", e)).with_context(context)
+        }
+        Ok(v) => v,
+    };
+    if report.is_valid() {
+        return Ok(());
+    }
+
+    let mut problems = Vec::new();
+    if !report.corrupt_entries.is_empty() {
+        problems.push(format!(
+            "corrupt file(s): {}",
+            report.corrupt_entries.join(", ")
+        ));
+    }
+    if !report.missing_entries.is_empty() {
+        problems.push(format!(
+            "missing file(s): {}",
+            report.missing_entries.join(", ")
+        ));
+    }
+    // The digest covers the header and the file table, but it's stored in the
+    // integrity file itself: it detects corruption (signatures protect
+    // against tampering)
+    if report.archive_digest_valid == Some(false) {
+        problems.push("file table doesn't match the integrity file".to_string());
+    }
+    Err(PatcherError::CorruptPatch {
+        patch: archive_name.to_string(),
+        reason: problems.join("; "),
+    }
+    .into())
 }
 
 /// Downloads a single patch described with a `ThorPatchInfo`.
+///
+/// The download is suspended while `pause_gate` is paused, and its speed is
+/// limited by `bandwidth_limiter` if given.
 async fn download_patch_to_file<CB: FnMut(u64, u64)>(
     client: &reqwest::Client,
-    patch_url: &Url,
+    patch_location: &PatchLocation,
     patch: &ThorPatchInfo,
     tmp_file: &mut File,
-    mut progress_callback: CB,
+    pause_gate: PauseGate,
+    bandwidth_limiter: Option<&BandwidthLimiter>,
+    progress_callback: CB,
 ) -> Result<()> {
-    let patch_file_url = patch_url.join(patch.file_name.as_str()).with_context(|| {
-        format!(
-            "Invalid file name '{}' given in patch list file",
-            patch.file_name
-        )
-    })?;
-    let mut resp = client
-        .get(patch_file_url)
-        .send()
-        .await
-        .with_context(|| format!("Failed to download file '{}'", patch.file_name))?;
-    if !resp.status().is_success() {
-        return Err(anyhow!(
-            "Patch file '{}' not found on the remote server",
-            patch.file_name
-        ));
+    let patch_file_location = patch_location
+        .join(patch.file_name.as_str())
+        .with_context(|| {
+            format!(
+                "Invalid file name '{}' given in patch list file",
+                patch.file_name
+            )
+        })?;
+    match patch_file_location {
+        PatchLocation::Remote(patch_file_url) => {
+            download_remote_patch_to_file(
+                client,
+                patch_file_url,
+                patch,
+                tmp_file,
+                pause_gate,
+                bandwidth_limiter,
+                progress_callback,
+            )
+            .await?
+        }
+        PatchLocation::Local(patch_file_path) => {
+            copy_local_patch_to_file(
+                &patch_file_path,
+                patch,
+                tmp_file,
+                pause_gate,
+                progress_callback,
+            )
+            .await?
+        }
     }
-    let bytes_to_download = resp.content_length().unwrap_or(0);
+    tmp_file
+        .sync_all()
+        .await
+        .with_context(|| format!("Failed to sync downloaded file '{}'", patch.file_name,))?;
+    Ok(())
+}
+
+/// Downloads a remote patch. The connection is closed when the download is
+/// paused, and the download is resumed with a range request.
+async fn download_remote_patch_to_file<CB: FnMut(u64, u64)>(
+    client: &reqwest::Client,
+    patch_file_url: Url,
+    patch: &ThorPatchInfo,
+    tmp_file: &mut File,
+    mut pause_gate: PauseGate,
+    bandwidth_limiter: Option<&BandwidthLimiter>,
+    mut progress_callback: CB,
+) -> Result<()> {
+    let mut bytes_to_download = patch.size.unwrap_or(0);
     let mut downloaded_bytes: u64 = 0;
-    while let Some(chunk) = resp
-        .chunk()
+    loop {
+        pause_gate.wait_until_resumed().await;
+        // The download can be paused right after its last chunk
+        if downloaded_bytes > 0 && downloaded_bytes == bytes_to_download {
+            return Ok(());
+        }
+        let mut request = client.get(patch_file_url.clone());
+        if downloaded_bytes > 0 {
+            request = request.header(
+                reqwest::header::RANGE,
+                format!("bytes={}-", downloaded_bytes),
+            );
+        }
+        let mut resp = request.send().await?;
+        if downloaded_bytes > 0 {
+            let (range_start, complete_length) = parse_content_range(&resp);
+            let resumed = match resp.status() {
+                reqwest::StatusCode::PARTIAL_CONTENT => range_start == Some(downloaded_bytes),
+                // Nothing was left to download
+                reqwest::StatusCode::RANGE_NOT_SATISFIABLE
+                    if complete_length == Some(downloaded_bytes) =>
+                {
+                    return Ok(())
+                }
+                _ => false,
+            };
+            if !resumed {
+                // Ranges aren't supported by the server, start over
+                log::debug!("Restarting download of '{}'", patch.file_name);
+                tmp_file.set_len(0).await?;
+                tmp_file.seek(std::io::SeekFrom::Start(0)).await?;
+                downloaded_bytes = 0;
+                if resp.status() != reqwest::StatusCode::OK {
+                    // The response doesn't contain the whole file
+                    continue;
+                }
+            }
+        }
+        match resp.status() {
+            status if status.is_success() => {}
+            reqwest::StatusCode::NOT_FOUND | reqwest::StatusCode::GONE => {
+                return Err(PatcherError::PatchNotFound {
+                    patch: patch.file_name.clone(),
+                }
+                .into());
+            }
+            // Server errors and rate limiting are usually temporary
+            status => {
+                return Err(PatcherError::DownloadFailed {
+                    patch: patch.file_name.clone(),
+                    source: anyhow!("The patch server answered with status {}", status),
+                }
+                .into());
+            }
+        }
+        if let Some(content_length) = resp.content_length() {
+            bytes_to_download = downloaded_bytes + content_length;
+        }
+
+        let paused = loop {
+            let chunk = tokio::select! {
+                biased;
+                _ = pause_gate.wait_until_paused() => break true,
+                chunk = resp.chunk() => chunk?,
+            };
+            match chunk {
+                None => break false,
+                Some(chunk) => {
+                    tmp_file.write_all(&chunk[..]).await?;
+                    downloaded_bytes += chunk.len() as u64;
+                    progress_callback(downloaded_bytes, bytes_to_download);
+                    if let Some(bandwidth_limiter) = bandwidth_limiter {
+                        bandwidth_limiter.consume(chunk.len() as u64).await;
+                    }
+                }
+            }
+        };
+        if !paused {
+            return Ok(());
+        }
+    }
+}
+
+/// Parses the `Content-Range` header of `resp` ('bytes 100-499/500' or
+/// 'bytes */500'), returns the first byte of the range and the complete
+/// length, when they're given.
+fn parse_content_range(resp: &reqwest::Response) -> (Option<u64>, Option<u64>) {
+    let content_range = resp
+        .headers()
+        .get(reqwest::header::CONTENT_RANGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("bytes "));
+    let (range, complete_length) = match content_range.and_then(|value| value.split_once('/')) {
+        Some(v) => v,
+        None => return (None, None),
+    };
+    let range_start = range
+        .split_once('-')
+        .and_then(|(range_start, _)| range_start.parse().ok());
+    (range_start, complete_length.parse().ok())
+}
+
+/// Copies a patch from a local directory, reporting progress the same way
+/// downloads do.
+async fn copy_local_patch_to_file<CB: FnMut(u64, u64)>(
+    patch_file_path: &Path,
+    patch: &ThorPatchInfo,
+    tmp_file: &mut File,
+    mut pause_gate: PauseGate,
+    mut progress_callback: CB,
+) -> Result<()> {
+    const CHUNK_SIZE: usize = 64 * 1024;
+    let mut patch_file = File::open(patch_file_path)
         .await
-        .with_context(|| format!("Failed to download file '{}'", patch.file_name))?
-    {
+        .with_context(|| format!("Patch file '{}' not found", patch_file_path.display()))?;
+    let bytes_to_copy = match patch_file.metadata().await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => patch.size.unwrap_or(0),
+    };
+    let mut copied_bytes: u64 = 0;
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        pause_gate.wait_until_resumed().await;
+        let read_bytes = patch_file
+            .read(&mut buffer)
+            .await
+            .with_context(|| format!("Failed to copy file '{}'", patch.file_name))?;
+        if read_bytes == 0 {
+            break;
+        }
         tmp_file
-            .write_all(&chunk[..])
+            .write_all(&buffer[..read_bytes])
             .await
-            .with_context(|| format!("Failed to download file '{}'", patch.file_name))?;
-        downloaded_bytes += chunk.len() as u64;
-        progress_callback(downloaded_bytes, bytes_to_download);
+            .with_context(|| format!("Failed to copy file '{}'", patch.file_name))?;
+        copied_bytes += read_bytes as u64;
+        progress_callback(copied_bytes, bytes_to_copy);
     }
-    tmp_file
-        .sync_all()
-        .await
-        .with_context(|| format!("Failed to sync downloaded file '{}'", patch.file_name,))?;
     Ok(())
 }
 
@@ -574,16 +1471,43 @@ async fn apply_patches(
     pending_patch_queue: Vec<PendingPatch>,
     config: &PatcherConfiguration,
     cache_file_path: impl AsRef<Path>,
+    mut patcher_cache: PatcherCache,
+    unapplied_critical_patches: &mut Vec<String>,
     ui_controller: &UiController,
     patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
 ) -> InterruptibleFnResult<()> {
-    let current_working_dir = env::current_dir().map_err(|e| {
-        InterruptibleFnError::Err(format!(
-            "Failed to resolve current working directory: {}.",
-            e
-        ))
-    })?;
+    let current_working_dir = env::current_dir()
+        .with_context(|| "Failed to resolve current working directory")
+        .map_err(InterruptibleFnError::Err)?;
+    // Make sure we won't run out of space in the middle of the process
+    let patch_files: Vec<PatchFile> = pending_patch_queue
+        .iter()
+        .map(|pending_patch| PatchFile {
+            path: pending_patch.local_file_path.as_path(),
+            target_grf_name: pending_patch.info.target_grf.as_deref(),
+        })
+        .collect();
+    let config = check_space_for_patches(&patch_files, config, &current_working_dir)
+        .map_err(InterruptibleFnError::Err)?;
+    let config = Arc::new(config.into_owned());
     let patch_count = pending_patch_queue.len();
+    // Running game clients keep GRFs open
+    wait_for_game_files(
+        patch_count,
+        &config,
+        &current_working_dir,
+        patching_thread_rx,
+        |files_in_use| {
+            ui_controller.dispatch_patching_status(PatchingStatus::FilesInUse(
+                files_in_use.describe(i18n::language()),
+            ))
+        },
+    )
+    .await?;
+    // The after_update hooks run again next time until they succeed
+    if patch_count > 0 {
+        patcher_cache.pending_after_update = true;
+    }
     ui_controller.dispatch_patching_status(PatchingStatus::InstallationInProgress(0, patch_count));
     for (patch_number, pending_patch) in pending_patch_queue.into_iter().enumerate() {
         // Cancel the patching process if we've been asked to or if the other
@@ -591,34 +1515,186 @@ async fn apply_patches(
         process_incoming_commands(patching_thread_rx)?;
 
         let patch_name = pending_patch.info.file_name;
-        log::info!("Processing {}", patch_name);
-        apply_patch(pending_patch.local_file_path, config, &current_working_dir).map_err(|e| {
-            InterruptibleFnError::Err(format!("Failed to apply patch '{}': {}.", patch_name, e))
+        match &pending_patch.info.description {
+            Some(description) => log::info!("Processing {} ({})", patch_name, description),
+            None => log::info!("Processing {}", patch_name),
+        }
+        apply_patch(
+            &pending_patch.local_file_path,
+            pending_patch.info.target_grf.as_deref(),
+            &config,
+            &current_working_dir,
+            installation_progress_callback(ui_controller, &patch_name, patch_number, patch_count),
+        )
+        .map_err(|source| {
+            InterruptibleFnError::Err(
+                PatcherError::PatchFailed {
+                    patch: patch_name.clone(),
+                    source,
+                }
+                .into(),
+            )
         })?;
-        // Update the cache file with the last successful patch's index
-        if let Err(e) = write_cache_file(
-            &cache_file_path,
-            PatcherCache {
-                last_patch_index: pending_patch.info.index,
+        // The patch is applied again next time if its hooks fail
+        let hook_config = config.clone();
+        let game_dir = current_working_dir.clone();
+        let hook_patch_name = patch_name.clone();
+        let patch_index = pending_patch.info.index;
+        let patch_path = pending_patch.local_file_path.clone();
+        run_interruptible_hooks(
+            move |canceler| {
+                let applied_patch = AppliedPatch {
+                    name: &hook_patch_name,
+                    index: Some(patch_index),
+                    path: &patch_path,
+                };
+                run_after_patch_hooks(&hook_config, &game_dir, &applied_patch, canceler)
             },
+            patching_thread_rx,
         )
-        .await
-        {
+        .await?;
+        // Update the cache file with the last successful patch's index
+        patcher_cache.last_patch_index = pending_patch.info.index;
+        if let Err(e) = write_cache_file(&cache_file_path, patcher_cache.clone()).await {
             log::warn!("Failed to write cache file: {}.", e);
         }
+        unapplied_critical_patches.retain(|critical_patch| critical_patch != &patch_name);
         // Update status
         ui_controller.dispatch_patching_status(PatchingStatus::InstallationInProgress(
             1 + patch_number,
             patch_count,
         ));
     }
+    if patcher_cache.pending_after_update {
+        let update_variables = [("KPATCHER_PATCH_COUNT", patch_count.to_string())];
+        let hook_config = config.clone();
+        run_interruptible_hooks(
+            move |canceler| {
+                run_hooks(
+                    HookEvent::AfterUpdate,
+                    &hook_config,
+                    &current_working_dir,
+                    &update_variables,
+                    canceler,
+                )
+            },
+            patching_thread_rx,
+        )
+        .await?;
+        patcher_cache.pending_after_update = false;
+        if let Err(e) = write_cache_file(&cache_file_path, patcher_cache).await {
+            log::warn!("Failed to write cache file: {}.", e);
+        }
+    }
     Ok(())
 }
 
-fn apply_patch(
+/// Runs hooks on a blocking thread, so that the update can still be canceled
+/// while they run. The running command is killed when it is.
+async fn run_interruptible_hooks<F>(
+    run: F,
+    patching_thread_rx: &mut flume::Receiver<PatcherCommand>,
+) -> InterruptibleFnResult<()>
+where
+    F: FnOnce(&HookCanceler) -> Result<()> + Send + 'static,
+{
+    let canceler = HookCanceler::default();
+    let hook_canceler = canceler.clone();
+    let mut hooks_task = tokio::task::spawn_blocking(move || run(&hook_canceler));
+    tokio::select! {
+        hooks_res = &mut hooks_task => hooks_res
+            .map_err(|e| InterruptibleFnError::Err(anyhow!("Hook task failed: {}", e)))?
+            .map_err(InterruptibleFnError::Err),
+        cancel_res = wait_for_cancellation(patching_thread_rx) => {
+            canceler.cancel();
+            let _ = hooks_task.await;
+            Err(cancel_res)
+        }
+    }
+}
+
+/// Returns a callback that sends the progress of the installation of a patch
+/// to the UI.
+fn installation_progress_callback<'a>(
+    ui_controller: &'a UiController,
+    patch_name: &'a str,
+    installed_patches: usize,
+    total_patches: usize,
+) -> impl FnMut(usize, usize) + 'a {
+    // Patches can contain thousands of entries, don't flood the UI
+    const DISPATCH_INTERVAL: Duration = Duration::from_millis(100);
+    let mut last_dispatch: Option<Instant> = None;
+    move |processed_entries, total_entries| {
+        let too_soon = matches!(last_dispatch, Some(t) if t.elapsed() < DISPATCH_INTERVAL);
+        if !too_soon || processed_entries == total_entries {
+            last_dispatch = Some(Instant::now());
+            ui_controller.dispatch_progress(&PatchingProgress::Installation {
+                patch_name: patch_name.to_string(),
+                installed_patches,
+                total_patches,
+                processed_entries,
+                total_entries,
+            });
+        }
+    }
+}
+
+/// Ensures that there's enough disk space to apply `patch_files`.
+///
+/// Returns the configuration to apply them with, which uses in-place patching
+/// if out-of-place patching doesn't fit and falling back is allowed.
+fn check_space_for_patches<'a>(
+    patch_files: &[PatchFile],
+    config: &'a PatcherConfiguration,
+    game_dir: &Path,
+) -> Result<Cow<'a, PatcherConfiguration>> {
+    if !config.patching.check_disk_space.unwrap_or(true) {
+        return Ok(Cow::Borrowed(config));
+    }
+    let default_grf_name = config.client.default_grf_name.as_str();
+    if config.patching.in_place {
+        check_space_for_patching(
+            patch_files,
+            default_grf_name,
+            game_dir,
+            GrfPatchingMethod::InPlace,
+        )?;
+        return Ok(Cow::Borrowed(config));
+    }
+    match check_space_for_patching(
+        patch_files,
+        default_grf_name,
+        game_dir,
+        GrfPatchingMethod::OutOfPlace,
+    ) {
+        Ok(()) => Ok(Cow::Borrowed(config)),
+        Err(e) if config.patching.in_place_fallback.unwrap_or(false) => {
+            log::warn!("{:#}. Falling back to in-place patching", e);
+            check_space_for_patching(
+                patch_files,
+                default_grf_name,
+                game_dir,
+                GrfPatchingMethod::InPlace,
+            )?;
+            let mut config = config.clone();
+            config.patching.in_place = true;
+            Ok(Cow::Owned(config))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies a single patch.
+///
+/// `target_grf_override` replaces the GRF targeted by the patch, if given.
+/// `progress_callback` is called with the number of processed entries and the
+/// total number of entries.
+fn apply_patch<CB: FnMut(usize, usize)>(
     patch_file_path: impl AsRef<Path>,
+    target_grf_override: Option<&str>,
     config: &PatcherConfiguration,
     current_working_dir: impl AsRef<Path>,
+    progress_callback: CB,
 ) -> Result<()> {
     let patch_path = patch_file_path.as_ref();
     let extension = patch_path
@@ -657,26 +1733,30 @@ fn apply_patch(
         let mut source_grf = GrfArchive::open(&temp_grf_path)?;
 
         // Target GRF (use default from config)
-        let target_grf_name = &config.client.default_grf_name;
+        let target_grf_name = target_grf_override.unwrap_or(&config.client.default_grf_name);
         log::trace!("Target GRF: {:?}", target_grf_name);
 
         let grf_patching_method = match config.patching.in_place {
             true => GrfPatchingMethod::InPlace,
             false => GrfPatchingMethod::OutOfPlace,
         };
-        let target_grf_path = current_working_dir.as_ref().join(target_grf_name);
+        let target_grf_path =
+            join_windows_relative_path(current_working_dir.as_ref(), target_grf_name);
 
         apply_grf_to_grf(
             grf_patching_method,
             config.patching.create_grf,
             &target_grf_path,
             &mut source_grf,
+            progress_callback,
         )?;
 
         // Verificar integridade do GRF após patch (se check_integrity estiver habilitado)
         if config.patching.check_integrity {
-            verify_grf_integrity(&target_grf_path)
-                .with_context(|| format!("Verificação de integridade falhou para: {}", target_grf_path.display()))?;
+            verify_grf_integrity(&target_grf_path).map_err(|source| PatcherError::CorruptGrf {
+                file: target_grf_name.to_string(),
+                source,
+            })?;
         }
 
         // temp_dir will be deleted when it goes out of scope
@@ -686,7 +1766,9 @@ fn apply_patch(
         if thor_archive.use_grf_merging() {
             // Patch GRF file
             let target_grf_name = {
-                if thor_archive.target_grf_name().is_empty() {
+                if let Some(target_grf_name) = target_grf_override {
+                    target_grf_name.to_string()
+                } else if thor_archive.target_grf_name().is_empty() {
                     config.client.default_grf_name.clone()
                 } else {
                     thor_archive.target_grf_name()
@@ -697,61 +1779,62 @@ fn apply_patch(
                 true => GrfPatchingMethod::InPlace,
                 false => GrfPatchingMethod::OutOfPlace,
             };
-            let target_grf_path = current_working_dir.as_ref().join(&target_grf_name);
+            let target_grf_path =
+                join_windows_relative_path(current_working_dir.as_ref(), &target_grf_name);
             apply_patch_to_grf(
                 grf_patching_method,
                 config.patching.create_grf,
                 &target_grf_path,
                 &mut thor_archive,
+                progress_callback,
             )?;
 
             // Verificar integridade do GRF após patch (se check_integrity estiver habilitado)
             if config.patching.check_integrity {
-                verify_grf_integrity(&target_grf_path)
-                    .with_context(|| format!("Verificação de integridade falhou para: {}", target_grf_path.display()))?;
+                verify_grf_integrity(&target_grf_path).map_err(|source| {
+                    PatcherError::CorruptGrf {
+                        file: target_grf_name.clone(),
+                        source,
+                    }
+                })?;
             }
 
             Ok(())
         } else {
             // Patch root directory
-            apply_patch_to_disk(current_working_dir, &mut thor_archive)
+            apply_patch_to_disk(current_working_dir, &mut thor_archive, progress_callback)
         }
     }
 }
 
-/// Verifica a integridade de um arquivo GRF após aplicar patches.
-/// Abre o GRF e verifica se todos os arquivos podem ser lidos corretamente.
-#[allow(dead_code)]
+/// Checks that a GRF can still be read after being patched.
+/// Only the first entries are read, to keep it quick with large GRFs.
 fn verify_grf_integrity(grf_path: impl AsRef<Path>) -> Result<()> {
-    let mut grf_archive = GrfArchive::open(grf_path.as_ref())
-        .with_context(|| format!("Falha ao abrir GRF para verificação: {}", grf_path.as_ref().display()))?;
-    
-    let file_count = grf_archive.file_count();
-    log::trace!("Verificando integridade de {} arquivos no GRF", file_count);
-    
-    // Verificar se podemos ler as entradas do arquivo
+    let grf_path = grf_path.as_ref();
+    let mut grf_archive = GrfArchive::open(grf_path)
+        .with_context(|| format!("Failed to open GRF '{}'", grf_path.display()))?;
+    log::trace!(
+        "Checking the integrity of '{}' ({} files)",
+        grf_path.display(),
+        grf_archive.file_count()
+    );
+
     let entries: Vec<_> = grf_archive.get_entries().cloned().collect();
-    
-    // Verificar amostra de arquivos para não demorar muito em GRFs grandes
-    let sample_size = std::cmp::min(10, entries.len());
-    for entry in entries.iter().take(sample_size) {
-        // Tentar ler o conteúdo do arquivo
+    for entry in entries.iter().take(GRF_INTEGRITY_SAMPLE_SIZE) {
         if entry.size > 0 {
-            grf_archive.read_file_content(&entry.relative_path)
-                .with_context(|| format!(
-                    "Falha ao ler arquivo '{}' do GRF durante verificação de integridade",
-                    entry.relative_path
-                ))?;
+            grf_archive
+                .read_file_content(&entry.relative_path)
+                .with_context(|| format!("Failed to read '{}'", entry.relative_path))?;
         }
     }
-    
-    log::trace!("Verificação de integridade concluída com sucesso");
+    log::trace!("'{}' is readable", grf_path.display());
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use gruf::thor::ThorArchiveBuilder;
     use httptest::{matchers::*, responders::*, Expectation, Server};
     use std::io::SeekFrom;
     use tokio::io::{AsyncReadExt, AsyncSeekExt};
@@ -778,13 +1861,16 @@ mod tests {
         let patch_info = ThorPatchInfo {
             index: 0,
             file_name: patch_name.to_string(),
+            ..Default::default()
         };
         let mut tmp_file = File::from_std(tempfile::tempfile().unwrap());
         download_patch_to_file(
             &reqwest::Client::new(),
-            &from_url,
+            &PatchLocation::Remote(from_url),
             &patch_info,
             &mut tmp_file,
+            PauseGate::new(watch::channel(false).1),
+            None,
             |_, _| {},
         )
         .await
@@ -799,4 +1885,555 @@ mod tests {
         // Content check
         assert_eq!(body_content, file_content);
     }
+
+    #[tokio::test]
+    async fn test_pause_and_resume_download() {
+        let body_content: Vec<u8> = (0..4 * 1024 * 1024).map(|x| x as u8).collect();
+        let patch_name = "patch_archive";
+        let patch_path = format!("/{}", patch_name);
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", patch_path.clone()),
+                not(request::headers(contains(key("range")))),
+            ])
+            .respond_with(status_code(200).body(body_content.clone())),
+        );
+        // Resumed with a range request, which this server ignores
+        server.expect(
+            Expectation::matching(all_of![
+                request::method_path("GET", patch_path.clone()),
+                request::headers(contains(key("range"))),
+            ])
+            .respond_with(status_code(200).body(body_content.clone())),
+        );
+
+        let from_url = Url::parse(server.url("/").to_string().as_str()).unwrap();
+        let patch_location = PatchLocation::Remote(from_url);
+        let client = reqwest::Client::new();
+        let patch_info = ThorPatchInfo {
+            index: 0,
+            file_name: patch_name.to_string(),
+            ..Default::default()
+        };
+        let (pause_tx, pause_rx) = watch::channel(false);
+        let mut resume_gate = PauseGate::new(pause_rx.clone());
+        let mut tmp_file = File::from_std(tempfile::tempfile().unwrap());
+        let mut paused_once = false;
+        let (download_res, _) = tokio::join!(
+            download_patch_to_file(
+                &client,
+                &patch_location,
+                &patch_info,
+                &mut tmp_file,
+                PauseGate::new(pause_rx),
+                None,
+                // Pause once, as soon as something has been downloaded
+                |_, _| {
+                    if !paused_once {
+                        paused_once = true;
+                        let _ = pause_tx.send(true);
+                    }
+                },
+            ),
+            async {
+                resume_gate.wait_until_paused().await;
+                let _ = pause_tx.send(false);
+            }
+        );
+        download_res.unwrap();
+
+        tmp_file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut file_content = Vec::new();
+        tmp_file.read_to_end(&mut file_content).await.unwrap();
+        assert_eq!(body_content, file_content);
+    }
+
+    /// Serves `content` over HTTP, with support for `Range: bytes=<start>-`
+    /// headers. Returns the server's URL and the start of the requested ranges.
+    fn serve_with_ranges(content: Vec<u8>) -> (Url, Arc<std::sync::Mutex<Vec<Option<usize>>>>) {
+        use std::io::{BufRead, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let requested_ranges = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let ranges = requested_ranges.clone();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut range_start = None;
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    if let Some(range) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        range_start = range.trim().trim_end_matches('-').parse().ok();
+                    }
+                    line.clear();
+                }
+                ranges.lock().unwrap().push(range_start);
+                let start = range_start.unwrap_or(0);
+                let length = content.len();
+                let status_and_headers = match range_start {
+                    None => format!("200 OK\r\nContent-Length: {}", length),
+                    Some(start) if start >= length => format!(
+                        "416 Range Not Satisfiable\r\nContent-Range: bytes */{}\r\nContent-Length: 0",
+                        length
+                    ),
+                    Some(start) => format!(
+                        "206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\nContent-Length: {}",
+                        start,
+                        length - 1,
+                        length,
+                        length - start
+                    ),
+                };
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nConnection: close\r\n\r\n",
+                    status_and_headers
+                );
+                // Fails when the client disconnects, as it does when paused
+                let _ = stream.write_all(content.get(start..).unwrap_or_default());
+            }
+        });
+        (url, requested_ranges)
+    }
+
+    /// Downloads a patch from `url`, pausing and resuming the download once
+    /// `pause_at` bytes have been downloaded.
+    async fn download_with_pause(url: Url, pause_at: u64) -> Vec<u8> {
+        let patch_info = ThorPatchInfo {
+            index: 0,
+            file_name: "patch_archive".to_string(),
+            ..Default::default()
+        };
+        let client = reqwest::Client::new();
+        let patch_location = PatchLocation::Remote(url);
+        let (pause_tx, pause_rx) = watch::channel(false);
+        let mut resume_gate = PauseGate::new(pause_rx.clone());
+        let mut tmp_file = File::from_std(tempfile::tempfile().unwrap());
+        let mut paused_once = false;
+        let (download_res, _) = tokio::join!(
+            download_patch_to_file(
+                &client,
+                &patch_location,
+                &patch_info,
+                &mut tmp_file,
+                PauseGate::new(pause_rx),
+                None,
+                |downloaded_bytes, _| {
+                    if !paused_once && downloaded_bytes >= pause_at {
+                        paused_once = true;
+                        let _ = pause_tx.send(true);
+                    }
+                },
+            ),
+            async {
+                resume_gate.wait_until_paused().await;
+                let _ = pause_tx.send(false);
+            }
+        );
+        download_res.unwrap();
+
+        tmp_file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut file_content = Vec::new();
+        tmp_file.read_to_end(&mut file_content).await.unwrap();
+        file_content
+    }
+
+    #[tokio::test]
+    async fn test_resume_download_with_range() {
+        let body_content: Vec<u8> = (0..4 * 1024 * 1024).map(|x| x as u8).collect();
+        let (url, requested_ranges) = serve_with_ranges(body_content.clone());
+        assert_eq!(download_with_pause(url, 1).await, body_content);
+        // The rest of the file has been appended to what was downloaded
+        let requested_ranges = requested_ranges.lock().unwrap().clone();
+        assert_eq!(requested_ranges.len(), 2);
+        assert_eq!(requested_ranges[0], None);
+        assert!(requested_ranges[1].unwrap() > 0);
+
+        // Paused after the last chunk, there's nothing left to request
+        let body_content = b"small patch".to_vec();
+        let (url, requested_ranges) = serve_with_ranges(body_content.clone());
+        let size = body_content.len() as u64;
+        assert_eq!(download_with_pause(url, size).await, body_content);
+        assert_eq!(*requested_ranges.lock().unwrap(), vec![None]);
+    }
+
+    #[tokio::test]
+    async fn test_download_error_status() {
+        let server = Server::run();
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/missing.thor"))
+                .respond_with(status_code(404)),
+        );
+        server.expect(
+            Expectation::matching(request::method_path("GET", "/busy.thor"))
+                .respond_with(status_code(503)),
+        );
+        let patch_location =
+            PatchLocation::Remote(Url::parse(server.url("/").to_string().as_str()).unwrap());
+        let download = |file_name: &str| {
+            let patch_info = ThorPatchInfo {
+                file_name: file_name.to_string(),
+                ..Default::default()
+            };
+            let patch_location = patch_location.clone();
+            async move {
+                let mut tmp_file = File::from_std(tempfile::tempfile().unwrap());
+                download_patch_to_file(
+                    &reqwest::Client::new(),
+                    &patch_location,
+                    &patch_info,
+                    &mut tmp_file,
+                    PauseGate::new(watch::channel(false).1),
+                    None,
+                    |_, _| {},
+                )
+                .await
+                .unwrap_err()
+            }
+        };
+
+        let err = download("missing.thor").await;
+        assert!(matches!(
+            err.downcast_ref::<PatcherError>(),
+            Some(PatcherError::PatchNotFound { .. })
+        ));
+        let err = download("busy.thor").await;
+        let report = ErrorReport::new(&err);
+        assert_eq!(report.code, "download_failed");
+        assert!(report.retryable);
+        assert!(report.details.contains("503"));
+    }
+
+    #[test]
+    fn test_verify_patch_file() {
+        let patch_content = b"patch content";
+        let patch_file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(patch_file.path(), patch_content).unwrap();
+        let mut patch_info = ThorPatchInfo {
+            index: 1,
+            file_name: "patch.thor".to_string(),
+            ..Default::default()
+        };
+        // Nothing to check
+        assert!(verify_patch_file(patch_file.path(), &patch_info).is_ok());
+
+        patch_info.size = Some(patch_content.len() as u64);
+        patch_info.sha256 = Some(Sha256::digest(patch_content).into());
+        assert!(verify_patch_file(patch_file.path(), &patch_info).is_ok());
+
+        patch_info.sha256 = Some([0; 32]);
+        assert!(verify_patch_file(patch_file.path(), &patch_info).is_err());
+        patch_info.size = Some(1);
+        assert!(verify_patch_file(patch_file.path(), &patch_info).is_err());
+    }
+
+    #[test]
+    fn test_check_archive_integrity() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let archive_path = tmp_dir.path().join("patch.thor");
+        let content: Vec<u8> = (0..4096).map(|x| (x % 251) as u8).collect();
+        {
+            let archive_file = fs::File::create(&archive_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(archive_file, false, None, true)
+                .unwrap()
+                .with_sha256_checksums(true);
+            builder
+                .append_file_update("data\\clientinfo.xml".to_string(), content.as_slice())
+                .unwrap();
+        }
+        assert!(check_archive_integrity("patch.thor", &archive_path).is_ok());
+
+        // Corrupt the entry's content
+        let offset = ThorArchive::open(&archive_path)
+            .unwrap()
+            .get_file_entry("data\\clientinfo.xml")
+            .unwrap()
+            .offset;
+        let mut archive_content = fs::read(&archive_path).unwrap();
+        archive_content[offset as usize + 4..offset as usize + 12].copy_from_slice(&[0xFF; 8]);
+        fs::write(&archive_path, archive_content).unwrap();
+        let err = check_archive_integrity("patch.thor", &archive_path).unwrap_err();
+        assert!(err.to_string().contains("data\\clientinfo.xml"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_wait_for_game_files() {
+        let game_dir = tempfile::tempdir().unwrap();
+        let client_path = game_dir.path().join("client");
+        fs::copy("/bin/sleep", &client_path).unwrap();
+        let config: PatcherConfiguration = serde_yaml::from_str(
+            "
+window: {title: Test, width: 800, height: 600, resizable: false}
+play: {path: client, arguments: []}
+setup: {path: '', arguments: []}
+web: {index_url: 'https://example.com/', patch_servers: []}
+client: {default_grf_name: data.grf}
+patching: {in_place: true, check_integrity: false, create_grf: true}
+",
+        )
+        .unwrap();
+        let (_tx, mut rx) = flume::bounded(1);
+        let mut client = std::process::Command::new(&client_path)
+            .arg("10")
+            .spawn()
+            .unwrap();
+
+        // An up-to-date game can be played while the patcher runs
+        let mut reported_files = Vec::new();
+        let up_to_date_res = wait_for_game_files(0, &config, game_dir.path(), &mut rx, |_| {
+            reported_files.push(())
+        })
+        .await;
+        // Patches wait for the client to exit
+        let patching_res = tokio::time::timeout(
+            Duration::from_millis(500),
+            wait_for_game_files(1, &config, game_dir.path(), &mut rx, |_| {
+                reported_files.push(())
+            }),
+        )
+        .await;
+        client.kill().unwrap();
+        let _ = client.wait();
+        assert!(up_to_date_res.is_ok());
+        assert!(patching_res.is_err());
+        assert_eq!(reported_files.len(), 1);
+    }
+
+    #[test]
+    fn test_ensure_patch_list_is_current() {
+        let patch_list = thor::patch_list_from_string("1 1.thor\n2 2.thor\n3 3.thor");
+        assert!(ensure_patch_list_is_current(&patch_list, 2).is_ok());
+        assert!(ensure_patch_list_is_current(&patch_list, 3).is_ok());
+        // An older patch list replayed by a mirror
+        let err = ensure_patch_list_is_current(&patch_list, 5).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PatcherError>(),
+            Some(PatcherError::OutdatedPatchList {
+                newest_index: 3,
+                last_patch_index: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn test_pick_fastest_server() {
+        let ms = Duration::from_millis;
+        let latencies = [("EU", ms(120)), ("US", ms(40)), ("BR", ms(45))];
+        assert_eq!(pick_fastest_server(&[], None), None);
+        assert_eq!(pick_fastest_server(&latencies, None), Some(1));
+        assert_eq!(pick_fastest_server(&latencies, Some("Unknown")), Some(1));
+        // The previous server is kept if it's almost as fast as the fastest one
+        assert_eq!(pick_fastest_server(&latencies, Some("BR")), Some(2));
+        assert_eq!(pick_fastest_server(&latencies, Some("EU")), Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_find_available_patch_server() {
+        let plist_content = "1 patch1.thor\n2 patch2.thor";
+        let servers: Vec<Server> = (0..3).map(|_| Server::run()).collect();
+        // First server doesn't serve its patches
+        servers[0].expect(
+            Expectation::matching(request::method_path("GET", "/plist.txt"))
+                .times(..)
+                .respond_with(status_code(200).body(plist_content)),
+        );
+        servers[0].expect(
+            Expectation::matching(request::method_path("HEAD", "/data/patch1.thor"))
+                .times(..)
+                .respond_with(status_code(404)),
+        );
+        for server in &servers[1..] {
+            server.expect(
+                Expectation::matching(request::method_path("GET", "/plist.txt"))
+                    .times(..)
+                    .respond_with(status_code(200).body(plist_content)),
+            );
+            server.expect(
+                Expectation::matching(request::method_path("HEAD", "/data/patch1.thor"))
+                    .times(..)
+                    .respond_with(status_code(200)),
+            );
+        }
+        let server_list: Vec<PatchServerInfo> = servers
+            .iter()
+            .enumerate()
+            .map(|(i, server)| PatchServerInfo {
+                name: format!("server{}", i),
+                plist_url: server.url("/plist.txt").to_string(),
+                patch_url: server.url("/data/").to_string(),
+                ..Default::default()
+            })
+            .collect();
+
+        let signature_verifier = SignatureVerifier::from_configuration(None).unwrap();
+        let (_tx, mut rx) = flume::unbounded();
+        let patch_server = find_available_patch_server(
+            &server_list,
+            &Some("server2".to_string()),
+            None,
+            DEFAULT_PROBE_TIMEOUT,
+            &signature_verifier,
+            &mut rx,
+            |_| {},
+        )
+        .await
+        .unwrap_or_else(|_| panic!("No patch server found"));
+        assert_eq!(patch_server.name, "server2");
+        assert_eq!(patch_server.patch_list.len(), 2);
+        assert_eq!(
+            patch_server.patch_location.to_string(),
+            server_list[2].patch_url
+        );
+
+        // Without a preferred server, any server but the first one can be used
+        let mut probes = Vec::new();
+        let patch_server = find_available_patch_server(
+            &server_list,
+            &None,
+            Some("server0"),
+            DEFAULT_PROBE_TIMEOUT,
+            &signature_verifier,
+            &mut rx,
+            |probe| probes.push(probe),
+        )
+        .await
+        .unwrap_or_else(|_| panic!("No patch server found"));
+        assert_ne!(patch_server.name, "server0");
+        // Every server has been probed
+        assert_eq!(probes.len(), 3);
+        assert!(probes
+            .iter()
+            .any(|probe| probe.name == "server0" && !probe.available));
+    }
+
+    #[tokio::test]
+    async fn test_find_fastest_patch_server() {
+        // First server is much slower than the second one
+        let server_urls = [
+            serve_with_delay("1 patch1.thor", Duration::from_millis(500)),
+            serve_with_delay("1 patch1.thor", Duration::ZERO),
+        ];
+        let server_list: Vec<PatchServerInfo> = server_urls
+            .iter()
+            .enumerate()
+            .map(|(i, url)| PatchServerInfo {
+                name: format!("server{}", i),
+                plist_url: url.join("plist.txt").unwrap().to_string(),
+                patch_url: url.join("data/").unwrap().to_string(),
+                ..Default::default()
+            })
+            .collect();
+
+        let signature_verifier = SignatureVerifier::from_configuration(None).unwrap();
+        let (_tx, mut rx) = flume::unbounded();
+        // The last server isn't kept when it's much slower
+        for last_server_name in [None, Some("server0")] {
+            let patch_server = find_available_patch_server(
+                &server_list,
+                &None,
+                last_server_name,
+                DEFAULT_PROBE_TIMEOUT,
+                &signature_verifier,
+                &mut rx,
+                |_| {},
+            )
+            .await
+            .unwrap_or_else(|_| panic!("No patch server found"));
+            assert_eq!(patch_server.name, "server1");
+        }
+    }
+
+    /// Serves `content` for every request, after waiting for `delay`.
+    fn serve_with_delay(content: &'static str, delay: Duration) -> Url {
+        use std::io::{BufRead, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap_or(0) > 2 {
+                    line.clear();
+                }
+                std::thread::sleep(delay);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    content.len(),
+                    content
+                );
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_local_patch_server() {
+        let patch_dir = tempfile::tempdir().unwrap();
+        let patch_content: Vec<u8> = (0..200_000).map(|x| x as u8).collect();
+        std::fs::write(patch_dir.path().join("plist.txt"), "1 patch1.thor").unwrap();
+        std::fs::write(patch_dir.path().join("patch1.thor"), &patch_content).unwrap();
+        std::fs::write(patch_dir.path().join("control.yml"), "notice: Hello").unwrap();
+        let server_list = vec![
+            PatchServerInfo {
+                name: "USB".to_string(),
+                plist_url: patch_dir.path().join("plist.txt").display().to_string(),
+                control_url: Some(patch_dir.path().join("control.yml").display().to_string()),
+                patch_url: Url::from_directory_path(patch_dir.path())
+                    .unwrap()
+                    .to_string(),
+                ..Default::default()
+            },
+            PatchServerInfo {
+                name: "Missing".to_string(),
+                plist_url: patch_dir.path().join("missing.txt").display().to_string(),
+                patch_url: patch_dir.path().display().to_string(),
+                ..Default::default()
+            },
+        ];
+
+        let signature_verifier = SignatureVerifier::from_configuration(None).unwrap();
+        let (_tx, mut rx) = flume::unbounded();
+        let patch_server = find_available_patch_server(
+            &server_list,
+            &None,
+            None,
+            DEFAULT_PROBE_TIMEOUT,
+            &signature_verifier,
+            &mut rx,
+            |_| {},
+        )
+        .await
+        .unwrap_or_else(|_| panic!("No patch server found"));
+        assert_eq!(patch_server.name, "USB");
+        assert_eq!(patch_server.control.notice.as_deref(), Some("Hello"));
+        assert_eq!(
+            patch_server.patch_location,
+            PatchLocation::Local(patch_dir.path().to_path_buf())
+        );
+
+        let mut tmp_file = File::from_std(tempfile::tempfile().unwrap());
+        let mut last_progress = (0, 0);
+        download_patch_to_file(
+            &patch_server.client,
+            &patch_server.patch_location,
+            &patch_server.patch_list[0],
+            &mut tmp_file,
+            PauseGate::new(watch::channel(false).1),
+            None,
+            |copied, total| last_progress = (copied, total),
+        )
+        .await
+        .unwrap();
+        assert_eq!(last_progress, (200_000, 200_000));
+
+        tmp_file.seek(SeekFrom::Start(0)).await.unwrap();
+        let mut file_content = Vec::new();
+        tmp_file.read_to_end(&mut file_content).await.unwrap();
+        assert_eq!(patch_content, file_content);
+    }
 }
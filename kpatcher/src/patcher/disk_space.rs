@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use gruf::delta;
+use gruf::thor::ThorArchive;
+
+use super::error::PatcherError;
+use super::patching::{join_windows_relative_path, GrfPatchingMethod};
+
+/// Space kept free on top of our estimates, as they can't be exact
+const SAFETY_MARGIN: u64 = 64 * 1024 * 1024;
+
+/// Estimated amount of disk space (in bytes) needed to apply patches.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SpaceRequirement {
+    pub game_dir: u64, // Space needed in the game's directory
+    pub temp_dir: u64, // Space needed in the temporary directory
+}
+
+/// Patch file that's about to be applied.
+pub struct PatchFile<'a> {
+    pub path: &'a Path,
+    pub target_grf_name: Option<&'a str>, // Overrides the GRF targeted by the patch
+}
+
+/// Ensures that `download_size` bytes can be downloaded into `download_dir`.
+pub fn check_space_for_download(download_dir: &Path, download_size: u64) -> Result<()> {
+    ensure_available_space(download_dir, download_size)
+}
+
+/// Ensures that there's enough space to apply `patch_files` (in that order)
+/// to the game located in `game_dir`.
+pub fn check_space_for_patching(
+    patch_files: &[PatchFile],
+    default_grf_name: &str,
+    game_dir: &Path,
+    grf_patching_method: GrfPatchingMethod,
+) -> Result<()> {
+    let requirement = estimate_patching_requirement(
+        patch_files,
+        default_grf_name,
+        game_dir,
+        grf_patching_method,
+    )?;
+    log::info!(
+        "Estimated space requirement: {} in the game directory, {} in the temporary directory",
+        format_size(requirement.game_dir),
+        format_size(requirement.temp_dir)
+    );
+    let temp_dir = std::env::temp_dir();
+    if is_same_volume(game_dir, &temp_dir) {
+        ensure_available_space(game_dir, requirement.game_dir + requirement.temp_dir)
+    } else {
+        ensure_available_space(game_dir, requirement.game_dir)?;
+        ensure_available_space(&temp_dir, requirement.temp_dir)
+    }
+}
+
+/// Estimates the space needed to apply `patch_files`.
+///
+/// Estimates are pessimistic: removed files are ignored and GRF entries are
+/// assumed to be appended to their archive.
+pub fn estimate_patching_requirement(
+    patch_files: &[PatchFile],
+    default_grf_name: &str,
+    game_dir: &Path,
+    grf_patching_method: GrfPatchingMethod,
+) -> Result<SpaceRequirement> {
+    let mut estimator = PatchingEstimator {
+        grf_patching_method,
+        grf_sizes: HashMap::new(),
+        growth: 0,
+        peak: 0,
+    };
+    let mut temp_dir_peak = 0;
+    for patch_file in patch_files {
+        let patch_path = patch_file.path;
+        let extension = patch_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase())
+            .unwrap_or_default();
+        let context = || format!("Failed to read patch '{}'", patch_path.display());
+        if extension == "rgz" || extension == "gpf" || extension == "grf" {
+            // GRF-based patches are decompressed (or copied) to a temporary
+            // file, which is then merged into the default GRF
+            let grf_size = if extension == "grf" {
+                fs::metadata(patch_path).with_context(context)?.len()
+            } else {
+                gzip_uncompressed_size(patch_path).with_context(context)?
+            };
+            temp_dir_peak = temp_dir_peak.max(grf_size);
+            let target_grf_name = patch_file.target_grf_name.unwrap_or(default_grf_name);
+            estimator.add_to_grf(join_windows_relative_path(game_dir, target_grf_name), grf_size);
+        } else {
+            let mut thor_archive = ThorArchive::open(patch_path).with_context(context)?;
+            let use_grf_merging = thor_archive.use_grf_merging();
+            let entries: Vec<_> = thor_archive
+                .get_entries()
+                .filter(|e| !e.is_removed && !e.is_internal())
+                .cloned()
+                .collect();
+            let mut content_size = 0_u64;
+            for entry in entries {
+                content_size += if entry.is_delta {
+                    // Only the uncompressed size of rebuilt files is known,
+                    // it's stored in the delta's header
+                    let delta_header = thor_archive
+                        .read_file_prefix(&entry.relative_path, delta::DELTA_HEADER_SIZE)
+                        .with_context(context)?;
+                    delta::read_delta_info(&delta_header)
+                        .with_context(context)?
+                        .target_size
+                } else if use_grf_merging {
+                    entry.size_compressed as u64
+                } else {
+                    entry.size as u64
+                };
+            }
+            if use_grf_merging {
+                let target_grf_name = match patch_file.target_grf_name {
+                    Some(target_grf_name) => target_grf_name.to_string(),
+                    None if thor_archive.target_grf_name().is_empty() => {
+                        default_grf_name.to_string()
+                    }
+                    None => thor_archive.target_grf_name(),
+                };
+                estimator.add_to_grf(
+                    join_windows_relative_path(game_dir, &target_grf_name),
+                    content_size,
+                );
+            } else {
+                estimator.add_to_disk(content_size);
+            }
+        }
+    }
+
+    Ok(SpaceRequirement {
+        game_dir: estimator.growth.max(estimator.peak),
+        temp_dir: temp_dir_peak,
+    })
+}
+
+/// Keeps track of the space used while patches are applied one by one.
+struct PatchingEstimator {
+    grf_patching_method: GrfPatchingMethod,
+    grf_sizes: HashMap<PathBuf, u64>, // Estimated size of the patched GRFs
+    growth: u64,                      // Space used once patches have been applied
+    peak: u64,                        // Maximum space used while patching
+}
+
+impl PatchingEstimator {
+    fn add_to_grf(&mut self, grf_path: PathBuf, content_size: u64) {
+        let grf_size = self
+            .grf_sizes
+            .entry(grf_path)
+            .or_insert_with_key(|path| fs::metadata(path).map(|m| m.len()).unwrap_or(0));
+        if let GrfPatchingMethod::OutOfPlace = self.grf_patching_method {
+            // The patched GRF is built next to the original one
+            self.peak = self.peak.max(self.growth + *grf_size + content_size);
+        }
+        *grf_size += content_size;
+        self.add_to_disk(content_size);
+    }
+
+    fn add_to_disk(&mut self, content_size: u64) {
+        self.growth += content_size;
+        self.peak = self.peak.max(self.growth);
+    }
+}
+
+fn ensure_available_space(path: &Path, required_space: u64) -> Result<()> {
+    if required_space == 0 {
+        return Ok(());
+    }
+    let available_space = fs2::available_space(path)
+        .with_context(|| format!("Failed to query free space of '{}'", path.display()))?;
+    if required_space.saturating_add(SAFETY_MARGIN) > available_space {
+        return Err(PatcherError::InsufficientDiskSpace {
+            path: path.to_path_buf(),
+            required: required_space.saturating_add(SAFETY_MARGIN),
+            available: available_space,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Reads the uncompressed size stored at the end of a gzip file.
+fn gzip_uncompressed_size(path: &Path) -> Result<u64> {
+    let mut file = fs::File::open(path)?;
+    file.seek(SeekFrom::End(-4))?;
+    let mut size = [0_u8; 4];
+    file.read_exact(&mut size)?;
+    // The stored size is modulo 2^32, use the compressed size as a lower bound
+    let compressed_size = file.metadata()?.len();
+    Ok(std::cmp::max(
+        u32::from_le_bytes(size) as u64,
+        compressed_size,
+    ))
+}
+
+#[cfg(unix)]
+fn is_same_volume(left: &Path, right: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (fs::metadata(left), fs::metadata(right)) {
+        (Ok(l), Ok(r)) => l.dev() == r.dev(),
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
+fn is_same_volume(left: &Path, right: &Path) -> bool {
+    let volume = |path: &Path| {
+        fs::canonicalize(path).ok().and_then(|p| {
+            p.components()
+                .next()
+                .map(|c| c.as_os_str().to_ascii_lowercase())
+        })
+    };
+    match (volume(left), volume(right)) {
+        (Some(l), Some(r)) => l == r,
+        _ => false,
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_same_volume(_left: &Path, _right: &Path) -> bool {
+    false
+}
+
+pub(super) fn format_size(size: u64) -> String {
+    const MIB: f64 = 1024.0 * 1024.0;
+    format!("{:.1} MiB", size as f64 / MIB)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_patching_requirement() {
+        let grf_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/grf");
+        let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
+        let game_dir = tempfile::tempdir().unwrap();
+        let target_grf_path = game_dir.path().join("data.grf");
+        fs::copy(grf_dir_path.join("200-small.grf"), &target_grf_path).unwrap();
+        let grf_size = fs::metadata(&target_grf_path).unwrap().len();
+
+        let grf_patch_path = grf_dir_path.join("103-small.grf");
+        let grf_patch_size = fs::metadata(&grf_patch_path).unwrap().len();
+        let thor_patch_path = thor_dir_path.join("tiny.thor");
+        let patch_files = [
+            PatchFile {
+                path: &grf_patch_path,
+                target_grf_name: None,
+            },
+            PatchFile {
+                path: &thor_patch_path,
+                target_grf_name: Some("data.grf"),
+            },
+        ];
+
+        let in_place = estimate_patching_requirement(
+            &patch_files,
+            "data.grf",
+            game_dir.path(),
+            GrfPatchingMethod::InPlace,
+        )
+        .unwrap();
+        assert!(in_place.game_dir >= grf_patch_size);
+        assert_eq!(in_place.temp_dir, grf_patch_size);
+
+        let out_of_place = estimate_patching_requirement(
+            &patch_files,
+            "data.grf",
+            game_dir.path(),
+            GrfPatchingMethod::OutOfPlace,
+        )
+        .unwrap();
+        // Rebuilding the GRF requires another copy of it
+        assert!(out_of_place.game_dir >= grf_size + grf_patch_size);
+        assert!(out_of_place.game_dir > in_place.game_dir);
+        assert_eq!(out_of_place.temp_dir, grf_patch_size);
+
+        // GRF names are case-insensitive, like when patching
+        #[cfg(not(windows))]
+        {
+            let upper_case = estimate_patching_requirement(
+                &patch_files,
+                "DATA.GRF",
+                game_dir.path(),
+                GrfPatchingMethod::OutOfPlace,
+            )
+            .unwrap();
+            assert_eq!(upper_case.game_dir, out_of_place.game_dir);
+        }
+    }
+
+    #[test]
+    fn test_ensure_available_space() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        assert!(ensure_available_space(tmp_dir.path(), 0).is_ok());
+        assert!(ensure_available_space(tmp_dir.path(), 1024).is_ok());
+        assert!(ensure_available_space(tmp_dir.path(), u64::MAX / 2).is_err());
+    }
+}
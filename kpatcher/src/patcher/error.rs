@@ -0,0 +1,358 @@
+use std::error::Error;
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::Serialize;
+
+use super::disk_space::format_size;
+use super::in_use::GameFilesInUse;
+use crate::i18n;
+
+/// OS error codes returned when a disk is full (`ERROR_HANDLE_DISK_FULL` and
+/// `ERROR_DISK_FULL`)
+#[cfg(windows)]
+const DISK_FULL_ERROR_CODES: [i32; 2] = [39, 112];
+/// OS error codes returned when a disk is full (`ENOSPC`)
+#[cfg(not(windows))]
+const DISK_FULL_ERROR_CODES: [i32; 1] = [28];
+
+/// Errors the patching process can run into, which the UI can tell apart.
+///
+/// Variants that wrap another error keep it as their source, so that the most
+/// specific error of a chain can be found.
+#[derive(Debug)]
+pub enum PatcherError {
+    UpdateInProgress, // Another instance is updating the game
+    Canceled,
+    NoPatchServerAvailable,
+    PatchNotFound {
+        patch: String,
+    },
+    DownloadFailed {
+        patch: String,
+        source: anyhow::Error,
+    },
+    CorruptPatch {
+        patch: String,
+        reason: String,
+    },
+    MissingSignature {
+        file: String,
+    },
+    InvalidSignature {
+        file: String,
+    },
+    OutdatedPatchList {
+        newest_index: usize,
+        last_patch_index: usize,
+    },
+    InsufficientDiskSpace {
+        path: PathBuf,
+        required: u64,
+        available: u64,
+    },
+    FilesInUse(GameFilesInUse),
+    PatchFailed {
+        patch: String,
+        source: anyhow::Error,
+    },
+    CorruptGrf {
+        file: String,
+        source: anyhow::Error,
+    },
+    HookFailed {
+        event: String,
+        source: anyhow::Error,
+    },
+    InvalidConfiguration {
+        source: anyhow::Error,
+    },
+}
+
+impl PatcherError {
+    /// Identifier of the error, which doesn't change between versions.
+    pub fn code(&self) -> &'static str {
+        match self {
+            PatcherError::UpdateInProgress => "update_in_progress",
+            PatcherError::Canceled => "canceled",
+            PatcherError::NoPatchServerAvailable => "no_patch_server_available",
+            PatcherError::PatchNotFound { .. } => "patch_not_found",
+            PatcherError::DownloadFailed { .. } => "download_failed",
+            PatcherError::CorruptPatch { .. } => "corrupt_patch",
+            PatcherError::MissingSignature { .. } => "missing_signature",
+            PatcherError::InvalidSignature { .. } => "invalid_signature",
+            PatcherError::OutdatedPatchList { .. } => "outdated_patch_list",
+            PatcherError::InsufficientDiskSpace { .. } => "insufficient_disk_space",
+            PatcherError::FilesInUse(_) => "files_in_use",
+            PatcherError::PatchFailed { .. } => "patch_failed",
+            PatcherError::CorruptGrf { .. } => "corrupt_grf",
+            PatcherError::HookFailed { .. } => "hook_failed",
+            PatcherError::InvalidConfiguration { .. } => "invalid_configuration",
+        }
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            PatcherError::UpdateInProgress | PatcherError::FilesInUse(_) => {
+                ErrorCategory::GameFiles
+            }
+            PatcherError::Canceled => ErrorCategory::Canceled,
+            PatcherError::NoPatchServerAvailable | PatcherError::DownloadFailed { .. } => {
+                ErrorCategory::Network
+            }
+            PatcherError::PatchNotFound { .. } | PatcherError::OutdatedPatchList { .. } => {
+                ErrorCategory::Server
+            }
+            PatcherError::CorruptPatch { .. }
+            | PatcherError::MissingSignature { .. }
+            | PatcherError::InvalidSignature { .. } => ErrorCategory::Integrity,
+            PatcherError::InsufficientDiskSpace { .. } => ErrorCategory::Disk,
+            PatcherError::PatchFailed { .. } | PatcherError::CorruptGrf { .. } => {
+                ErrorCategory::Installation
+            }
+            PatcherError::HookFailed { .. } => ErrorCategory::Hook,
+            PatcherError::InvalidConfiguration { .. } => ErrorCategory::Configuration,
+        }
+    }
+
+    /// Indicates whether starting the update again can succeed without
+    /// anything being fixed on the server's side.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            PatcherError::UpdateInProgress
+                | PatcherError::Canceled
+                | PatcherError::NoPatchServerAvailable
+                | PatcherError::DownloadFailed { .. }
+                | PatcherError::CorruptPatch { .. }
+                | PatcherError::InsufficientDiskSpace { .. }
+                | PatcherError::FilesInUse(_)
+        )
+    }
+
+    fn patch(&self) -> Option<&str> {
+        match self {
+            PatcherError::PatchNotFound { patch }
+            | PatcherError::DownloadFailed { patch, .. }
+            | PatcherError::CorruptPatch { patch, .. }
+            | PatcherError::PatchFailed { patch, .. } => Some(patch),
+            _ => None,
+        }
+    }
+
+    fn file(&self) -> Option<String> {
+        match self {
+            PatcherError::MissingSignature { file }
+            | PatcherError::InvalidSignature { file }
+            | PatcherError::CorruptGrf { file, .. } => Some(file.clone()),
+            PatcherError::InsufficientDiskSpace { path, .. } => Some(path.display().to_string()),
+            PatcherError::FilesInUse(files_in_use) => files_in_use
+                .running_clients
+                .iter()
+                .chain(&files_in_use.locked_files)
+                .next()
+                .map(|path| path.display().to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PatcherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatcherError::UpdateInProgress => write!(f, "The game is already being updated"),
+            PatcherError::Canceled => write!(f, "Patching was canceled"),
+            PatcherError::NoPatchServerAvailable => {
+                write!(f, "None of the patch servers are available at the moment")
+            }
+            PatcherError::PatchNotFound { patch } => {
+                write!(f, "Patch file '{}' not found on the remote server", patch)
+            }
+            PatcherError::DownloadFailed { patch, .. } => {
+                write!(f, "Failed to download file '{}'", patch)
+            }
+            PatcherError::CorruptPatch { patch, reason } => {
+                write!(f, "Patch '{}' is corrupt ({})", patch, reason)
+            }
+            PatcherError::MissingSignature { file } => write!(f, "'{}' isn't signed", file),
+            PatcherError::InvalidSignature { file } => {
+                write!(f, "'{}' has an invalid signature", file)
+            }
+            PatcherError::OutdatedPatchList {
+                newest_index,
+                last_patch_index,
+            } => write!(
+                f,
+                "The patch list ends at patch {} but patch {} is already applied",
+                newest_index, last_patch_index
+            ),
+            PatcherError::InsufficientDiskSpace {
+                path,
+                required,
+                available,
+            } => write!(
+                f,
+                "Not enough disk space in '{}': {} required, {} available",
+                path.display(),
+                format_size(*required),
+                format_size(*available)
+            ),
+            PatcherError::FilesInUse(files_in_use) => write!(f, "{}", files_in_use),
+            PatcherError::PatchFailed { patch, .. } => {
+                write!(f, "Failed to apply patch '{}'", patch)
+            }
+            PatcherError::CorruptGrf { file, .. } => {
+                write!(f, "GRF '{}' is corrupt after patching", file)
+            }
+            PatcherError::HookFailed { event, .. } => write!(f, "The {} hook failed", event),
+            PatcherError::InvalidConfiguration { .. } => write!(f, "Invalid configuration"),
+        }
+    }
+}
+
+impl Error for PatcherError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PatcherError::DownloadFailed { source, .. }
+            | PatcherError::PatchFailed { source, .. }
+            | PatcherError::CorruptGrf { source, .. }
+            | PatcherError::HookFailed { source, .. }
+            | PatcherError::InvalidConfiguration { source } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Kind of problem, which tells the UI what kind of guidance to give.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    Network,       // The patch servers can't be reached
+    Server,        // The patch servers don't serve what they should
+    Integrity,     // Downloaded content is corrupt or isn't signed properly
+    Disk,          // Not enough space, or files can't be written
+    GameFiles,     // The game's files are used by other programs
+    Installation,  // Patches couldn't be applied to the game's files
+    Hook,          // A hook command failed
+    Configuration, // The patcher is misconfigured
+    Canceled,      // Canceled by the user
+    Internal,      // Anything else
+}
+
+/// Description of an error sent to the UI.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ErrorReport {
+    pub code: &'static str,
+    pub category: ErrorCategory,
+    pub retryable: bool,
+    pub message: String, // Description of the error, in the patcher's language
+    pub details: String, // Full description of the error, in English
+    pub patch: Option<String>, // Patch that was being processed
+    pub file: Option<String>, // File that caused the error
+    pub critical_patch: Option<String>, // Critical patch left unapplied, the game can't be started
+}
+
+impl ErrorReport {
+    /// Describes `err` with the most specific `PatcherError` it contains.
+    /// Errors that don't contain any are classified by their cause.
+    pub fn new(err: &anyhow::Error) -> Self {
+        let patcher_errors: Vec<&PatcherError> = err
+            .chain()
+            .filter_map(|e| e.downcast_ref::<PatcherError>())
+            .collect();
+        let patcher_error = patcher_errors.last();
+        let (code, category, retryable) = match patcher_error {
+            Some(patcher_error) => (
+                patcher_error.code(),
+                patcher_error.category(),
+                patcher_error.is_retryable(),
+            ),
+            None => classify_untyped_error(err),
+        };
+        let patch = patcher_errors
+            .iter()
+            .find_map(|e| e.patch())
+            .map(str::to_string);
+        let file = patcher_errors.iter().find_map(|e| e.file());
+        let message = match patcher_error {
+            Some(PatcherError::FilesInUse(files_in_use)) => files_in_use.describe(i18n::language()),
+            _ => i18n::tr_with(
+                &format!("error.{}", code),
+                &[
+                    ("patch", patch.as_deref().unwrap_or_default()),
+                    ("file", file.as_deref().unwrap_or_default()),
+                ],
+            ),
+        };
+        ErrorReport {
+            code,
+            category,
+            retryable,
+            message,
+            details: format!("{:#}", err),
+            patch,
+            file,
+            critical_patch: None,
+        }
+    }
+}
+
+fn classify_untyped_error(err: &anyhow::Error) -> (&'static str, ErrorCategory, bool) {
+    for cause in err.chain() {
+        if cause.is::<reqwest::Error>() {
+            return ("network_error", ErrorCategory::Network, true);
+        }
+        if let Some(io_error) = cause.downcast_ref::<std::io::Error>() {
+            let is_disk_full = io_error
+                .raw_os_error()
+                .map(|code| DISK_FULL_ERROR_CODES.contains(&code))
+                .unwrap_or(false);
+            if is_disk_full {
+                return ("insufficient_disk_space", ErrorCategory::Disk, true);
+            }
+            return ("io_error", ErrorCategory::Disk, false);
+        }
+    }
+    ("internal_error", ErrorCategory::Internal, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{anyhow, Context};
+
+    #[test]
+    fn test_error_report() {
+        let err = anyhow::Error::from(PatcherError::PatchFailed {
+            patch: "1.thor".to_string(),
+            source: PatcherError::CorruptGrf {
+                file: "data.grf".to_string(),
+                source: anyhow!("Failed to read 'data\\clientinfo.xml'"),
+            }
+            .into(),
+        })
+        .context("Failed to apply patches");
+        let report = ErrorReport::new(&err);
+        assert_eq!(report.code, "corrupt_grf");
+        assert_eq!(report.category, ErrorCategory::Installation);
+        assert!(!report.retryable);
+        assert_eq!(report.patch.as_deref(), Some("1.thor"));
+        assert_eq!(report.file.as_deref(), Some("data.grf"));
+        assert_eq!(report.message, "GRF 'data.grf' is corrupt after patching");
+        assert_eq!(
+            report.details,
+            "Failed to apply patches: Failed to apply patch '1.thor': GRF 'data.grf' is corrupt after patching: Failed to read 'data\\clientinfo.xml'"
+        );
+
+        // Errors without a `PatcherError` are classified by their cause
+        let err = Err::<(), _>(std::io::Error::from_raw_os_error(DISK_FULL_ERROR_CODES[0]))
+            .context("Failed to create temporary file")
+            .unwrap_err();
+        let report = ErrorReport::new(&err);
+        assert_eq!(report.code, "insufficient_disk_space");
+        assert!(report.retryable);
+        let report = ErrorReport::new(&anyhow!("Channel was closed"));
+        assert_eq!(report.code, "internal_error");
+        assert_eq!(report.category, ErrorCategory::Internal);
+    }
+}
@@ -0,0 +1,390 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use gruf::thor::{ThorArchive, HOOK_FILE_NAME};
+
+use super::config::{HookCommand, HookFailurePolicy};
+use super::error::PatcherError;
+use super::patching::join_windows_relative_path;
+use super::PatcherConfiguration;
+
+/// Time given to hook commands that don't specify a timeout
+const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+/// Interval at which running hook commands are checked
+const HOOK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Step of the patching process at which hooks run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    BeforeUpdate,
+    AfterPatch,
+    AfterUpdate,
+    BeforeLaunch,
+    Patch, // Hook carried by a patch
+}
+
+impl fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HookEvent::BeforeUpdate => "before_update",
+            HookEvent::AfterPatch => "after_patch",
+            HookEvent::AfterUpdate => "after_update",
+            HookEvent::BeforeLaunch => "before_launch",
+            HookEvent::Patch => "patch",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Lets hooks be canceled from another thread. The running command is killed
+/// and the remaining ones aren't run.
+#[derive(Clone, Default)]
+pub struct HookCanceler(Arc<AtomicBool>);
+
+impl HookCanceler {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    fn is_canceled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Patch that's just been applied, described to `after_patch` hooks.
+pub struct AppliedPatch<'a> {
+    pub name: &'a str,
+    pub index: Option<usize>, // None for manual patches
+    pub path: &'a Path,       // Local copy of the patch
+}
+
+impl AppliedPatch<'_> {
+    fn variables(&self) -> Vec<(&'static str, String)> {
+        let mut variables = vec![
+            ("KPATCHER_PATCH_NAME", self.name.to_string()),
+            ("KPATCHER_PATCH_FILE", self.path.display().to_string()),
+        ];
+        if let Some(index) = self.index {
+            variables.push(("KPATCHER_PATCH_INDEX", index.to_string()));
+        }
+        variables
+    }
+}
+
+/// Runs the hooks configured for `event`, in order. `variables` are given to
+/// the commands as environment variables.
+///
+/// Stops at the first command that fails, unless it's allowed to fail, or
+/// when `canceler` is canceled.
+pub fn run_hooks(
+    event: HookEvent,
+    config: &PatcherConfiguration,
+    game_dir: &Path,
+    variables: &[(&str, String)],
+    canceler: &HookCanceler,
+) -> Result<()> {
+    let hooks = match &config.patching.hooks {
+        None => return Ok(()),
+        Some(hooks) => hooks,
+    };
+    let commands = match event {
+        HookEvent::BeforeUpdate => &hooks.before_update,
+        HookEvent::AfterPatch => &hooks.after_patch,
+        HookEvent::AfterUpdate => &hooks.after_update,
+        HookEvent::BeforeLaunch => &hooks.before_launch,
+        HookEvent::Patch => return Ok(()),
+    };
+    for hook in commands {
+        run_hook_with_policy(event, hook, game_dir, variables, canceler)?;
+    }
+    Ok(())
+}
+
+/// Runs the `after_patch` hooks, then the hook carried by the patch if any.
+pub fn run_after_patch_hooks(
+    config: &PatcherConfiguration,
+    game_dir: &Path,
+    patch: &AppliedPatch,
+    canceler: &HookCanceler,
+) -> Result<()> {
+    let variables = patch.variables();
+    run_hooks(
+        HookEvent::AfterPatch,
+        config,
+        game_dir,
+        &variables,
+        canceler,
+    )?;
+    let hook = read_patch_hook(patch.path)
+        .with_context(|| format!("Invalid hook in patch '{}'", patch.name))?;
+    if let Some(hook) = hook {
+        let allow_patch_hooks = config
+            .patching
+            .hooks
+            .as_ref()
+            .and_then(|hooks| hooks.allow_patch_hooks)
+            .unwrap_or(false);
+        if allow_patch_hooks {
+            run_hook_with_policy(HookEvent::Patch, &hook, game_dir, &variables, canceler)?;
+        } else {
+            log::warn!(
+                "Ignoring the hook of patch '{}', patch hooks aren't allowed",
+                patch.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Reads the hook carried by a THOR patch.
+fn read_patch_hook(patch_path: &Path) -> Result<Option<HookCommand>> {
+    let is_thor_archive = patch_path
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("thor"))
+        .unwrap_or(false);
+    if !is_thor_archive {
+        return Ok(None);
+    }
+    let mut thor_archive = ThorArchive::open(patch_path)?;
+    if thor_archive.get_file_entry(HOOK_FILE_NAME).is_none() {
+        return Ok(None);
+    }
+    let content = thor_archive.read_file_content(HOOK_FILE_NAME)?;
+    Ok(Some(serde_yaml::from_slice(&content)?))
+}
+
+fn run_hook_with_policy(
+    event: HookEvent,
+    hook: &HookCommand,
+    game_dir: &Path,
+    variables: &[(&str, String)],
+    canceler: &HookCanceler,
+) -> Result<()> {
+    match run_hook(event, hook, game_dir, variables, canceler) {
+        Ok(()) => Ok(()),
+        Err(e) if canceler.is_canceled() => Err(e),
+        Err(e) => match hook.on_failure.unwrap_or(HookFailurePolicy::Abort) {
+            HookFailurePolicy::Abort => Err(PatcherError::HookFailed {
+                event: event.to_string(),
+                source: e,
+            }
+            .into()),
+            HookFailurePolicy::Continue => {
+                log::warn!("The {} hook failed: {:#}", event, e);
+                Ok(())
+            }
+        },
+    }
+}
+
+fn run_hook(
+    event: HookEvent,
+    hook: &HookCommand,
+    game_dir: &Path,
+    variables: &[(&str, String)],
+    canceler: &HookCanceler,
+) -> Result<()> {
+    if canceler.is_canceled() {
+        return Err(PatcherError::Canceled.into());
+    }
+    log::info!("Running {} hook '{}'", event, hook.command);
+    let working_directory = match &hook.working_directory {
+        Some(working_directory) => join_windows_relative_path(game_dir, working_directory),
+        None => game_dir.to_path_buf(),
+    };
+    let mut command = Command::new(hook_executable(&hook.command, game_dir));
+    command
+        .args(&hook.arguments)
+        .current_dir(working_directory)
+        .env("KPATCHER_HOOK", event.to_string())
+        .env("KPATCHER_GAME_DIR", game_dir)
+        .envs(variables.iter().map(|(key, value)| (key, value)))
+        .envs(&hook.env)
+        .stdin(Stdio::null());
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        // Console programs would open a console window otherwise
+        const CREATE_NO_WINDOW: u32 = 0x0800_0000;
+        command.creation_flags(CREATE_NO_WINDOW);
+    }
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("Failed to start '{}'", hook.command))?;
+    let timeout = hook
+        .timeout
+        .map(Duration::from_secs)
+        .unwrap_or(DEFAULT_HOOK_TIMEOUT);
+    let status = wait_with_timeout(&mut child, timeout, canceler)
+        .with_context(|| format!("Failed to run '{}'", hook.command))?;
+    match status {
+        Some(status) if status.success() => Ok(()),
+        Some(status) => Err(anyhow!("'{}' exited with {}", hook.command, status)),
+        None if canceler.is_canceled() => Err(PatcherError::Canceled.into()),
+        None => Err(anyhow!(
+            "'{}' didn't finish within {} seconds",
+            hook.command,
+            timeout.as_secs()
+        )),
+    }
+}
+
+/// Commands given as paths are relative to the game's directory, others are
+/// looked up in `PATH`.
+fn hook_executable(command: &str, game_dir: &Path) -> PathBuf {
+    if command.contains(['/', '\\']) {
+        join_windows_relative_path(game_dir, command)
+    } else {
+        PathBuf::from(command)
+    }
+}
+
+/// Waits for `child` to exit. Kills it and returns `None` if it takes longer
+/// than `timeout` or if `canceler` is canceled.
+fn wait_with_timeout(
+    child: &mut std::process::Child,
+    timeout: Duration,
+    canceler: &HookCanceler,
+) -> Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline || canceler.is_canceled() {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Ok(None);
+        }
+        std::thread::sleep(HOOK_POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gruf::thor::ThorArchiveBuilder;
+    use tempfile::tempdir;
+
+    #[cfg(unix)]
+    fn shell_hook(script: &str) -> HookCommand {
+        HookCommand {
+            command: "sh".to_string(),
+            arguments: vec!["-c".to_string(), script.to_string()],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_read_patch_hook() {
+        let patch_dir = tempdir().unwrap();
+        let patch_path = patch_dir.path().join("1.thor");
+        {
+            let thor_file = std::fs::File::create(&patch_path).unwrap();
+            let mut builder = ThorArchiveBuilder::new(thor_file, false, None, true).unwrap();
+            builder
+                .append_file_update("data\\clientinfo.xml".to_string(), &b"<clientinfo/>"[..])
+                .unwrap();
+            builder
+                .append_file_update(
+                    HOOK_FILE_NAME.to_string(),
+                    &b"command: tools\\migrate.exe\narguments: [--quiet]"[..],
+                )
+                .unwrap();
+        }
+        let hook = read_patch_hook(&patch_path).unwrap().unwrap();
+        assert_eq!(hook.command, "tools\\migrate.exe");
+        assert_eq!(hook.arguments, vec!["--quiet"]);
+        assert!(read_patch_hook(&patch_dir.path().join("1.grf"))
+            .unwrap()
+            .is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_hook() {
+        let game_dir = tempdir().unwrap();
+        let variables = [("KPATCHER_PATCH_NAME", "1.thor".to_string())];
+        let hook = shell_hook(
+            r#"test "$KPATCHER_HOOK" = after_patch && test "$KPATCHER_PATCH_NAME" = 1.thor && touch done"#,
+        );
+        let canceler = HookCanceler::default();
+        run_hook(
+            HookEvent::AfterPatch,
+            &hook,
+            game_dir.path(),
+            &variables,
+            &canceler,
+        )
+        .unwrap();
+        assert!(game_dir.path().join("done").exists());
+
+        let hook = shell_hook("exit 3");
+        assert!(run_hook_with_policy(
+            HookEvent::AfterPatch,
+            &hook,
+            game_dir.path(),
+            &[],
+            &canceler
+        )
+        .is_err());
+        let hook = HookCommand {
+            on_failure: Some(HookFailurePolicy::Continue),
+            ..hook
+        };
+        assert!(run_hook_with_policy(
+            HookEvent::AfterPatch,
+            &hook,
+            game_dir.path(),
+            &[],
+            &canceler
+        )
+        .is_ok());
+
+        let hook = HookCommand {
+            timeout: Some(1),
+            ..shell_hook("sleep 10")
+        };
+        let start = Instant::now();
+        assert!(run_hook(
+            HookEvent::BeforeLaunch,
+            &hook,
+            game_dir.path(),
+            &[],
+            &canceler
+        )
+        .is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Canceled hooks are killed, even if they're allowed to fail
+        let hook = HookCommand {
+            on_failure: Some(HookFailurePolicy::Continue),
+            ..shell_hook("sleep 10")
+        };
+        let start = Instant::now();
+        let hook_canceler = canceler.clone();
+        let canceling_thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            hook_canceler.cancel();
+        });
+        let err = run_hook_with_policy(
+            HookEvent::AfterUpdate,
+            &hook,
+            game_dir.path(),
+            &[],
+            &canceler,
+        )
+        .unwrap_err();
+        canceling_thread.join().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<PatcherError>(),
+            Some(PatcherError::Canceled)
+        ));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,324 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::PatcherConfiguration;
+use crate::i18n::{tr_in, Language};
+
+/// Game files that can't be patched right now, because other processes use
+/// them.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct GameFilesInUse {
+    pub running_clients: Vec<PathBuf>, // Game clients started from the game's directory
+    pub locked_files: Vec<PathBuf>,    // GRFs opened by other programs
+}
+
+impl GameFilesInUse {
+    pub fn is_empty(&self) -> bool {
+        self.running_clients.is_empty() && self.locked_files.is_empty()
+    }
+
+    /// Tells the player what prevents patching, in `language`.
+    pub fn describe(&self, language: Language) -> String {
+        let file_name = |path: &PathBuf| {
+            path.file_name()
+                .unwrap_or(path.as_os_str())
+                .to_string_lossy()
+                .into_owned()
+        };
+        let mut problems = Vec::new();
+        if !self.running_clients.is_empty() {
+            let names: Vec<String> = self.running_clients.iter().map(file_name).collect();
+            problems.push(tr_in(
+                language,
+                "files_in_use.clients",
+                &[("clients", &names.join(", "))],
+            ));
+        }
+        if !self.locked_files.is_empty() {
+            let names: Vec<String> = self.locked_files.iter().map(file_name).collect();
+            problems.push(match names.len() {
+                1 => tr_in(language, "files_in_use.locked_file", &[("file", &names[0])]),
+                _ => tr_in(
+                    language,
+                    "files_in_use.locked_files",
+                    &[("files", &names.join("', '"))],
+                ),
+            });
+        }
+        problems.join("; ")
+    }
+}
+
+impl fmt::Display for GameFilesInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.describe(Language::English))
+    }
+}
+
+/// Looks for game clients started from `game_dir` and for GRFs of `game_dir`
+/// that are opened by other programs.
+pub fn check_game_files_in_use(config: &PatcherConfiguration, game_dir: &Path) -> GameFilesInUse {
+    let client_executables: Vec<PathBuf> = config
+        .play
+        .launch_profiles()
+        .iter()
+        .map(|profile| game_dir.join(&profile.path))
+        .collect();
+    GameFilesInUse {
+        running_clients: find_running_executables(&client_executables),
+        locked_files: list_grf_files(game_dir)
+            .into_iter()
+            .filter(|path| is_file_locked(path))
+            .collect(),
+    }
+}
+
+/// Lists the GRFs located at the root of `game_dir`.
+pub(super) fn list_grf_files(game_dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(game_dir) {
+        Err(_) => return Vec::new(),
+        Ok(v) => v,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .map(|extension| extension.eq_ignore_ascii_case("grf"))
+                    .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Returns the executables of `executables` that are currently running.
+fn find_running_executables(executables: &[PathBuf]) -> Vec<PathBuf> {
+    let executables: Vec<(PathBuf, PathBuf)> = executables
+        .iter()
+        .filter_map(|path| Some((path.clone(), fs::canonicalize(path).ok()?)))
+        .collect();
+    if executables.is_empty() {
+        return Vec::new();
+    }
+    let running_executables = list_running_executables();
+    executables
+        .into_iter()
+        .filter(|(_, canonical_path)| {
+            running_executables
+                .iter()
+                .filter(|running_path| same_file_name(running_path, canonical_path))
+                .any(|running_path| {
+                    fs::canonicalize(running_path)
+                        .map(|running_path| same_path(&running_path, canonical_path))
+                        .unwrap_or(false)
+                })
+        })
+        .map(|(path, _)| path)
+        .collect()
+}
+
+fn same_file_name(left: &Path, right: &Path) -> bool {
+    match (left.file_name(), right.file_name()) {
+        (Some(l), Some(r)) => l.eq_ignore_ascii_case(r),
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
+fn same_path(left: &Path, right: &Path) -> bool {
+    left.as_os_str().eq_ignore_ascii_case(right.as_os_str())
+}
+
+#[cfg(not(windows))]
+fn same_path(left: &Path, right: &Path) -> bool {
+    left == right
+}
+
+/// Lists the executables of the running processes.
+#[cfg(windows)]
+fn list_running_executables() -> Vec<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::windows::ffi::OsStringExt;
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::processthreadsapi::OpenProcess;
+    use winapi::um::tlhelp32::{
+        CreateToolhelp32Snapshot, Process32FirstW, Process32NextW, PROCESSENTRY32W,
+        TH32CS_SNAPPROCESS,
+    };
+    use winapi::um::winbase::QueryFullProcessImageNameW;
+    use winapi::um::winnt::PROCESS_QUERY_LIMITED_INFORMATION;
+
+    let mut executables = Vec::new();
+    unsafe {
+        let snapshot = CreateToolhelp32Snapshot(TH32CS_SNAPPROCESS, 0);
+        if snapshot == INVALID_HANDLE_VALUE {
+            return executables;
+        }
+        let mut entry: PROCESSENTRY32W = std::mem::zeroed();
+        entry.dwSize = std::mem::size_of::<PROCESSENTRY32W>() as u32;
+        let mut has_entry = Process32FirstW(snapshot, &mut entry) != 0;
+        while has_entry {
+            let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, entry.th32ProcessID);
+            if !process.is_null() {
+                let mut buffer = [0u16; 1024];
+                let mut size = buffer.len() as u32;
+                if QueryFullProcessImageNameW(process, 0, buffer.as_mut_ptr(), &mut size) != 0 {
+                    executables.push(PathBuf::from(OsString::from_wide(&buffer[..size as usize])));
+                }
+                CloseHandle(process);
+            }
+            has_entry = Process32NextW(snapshot, &mut entry) != 0;
+        }
+        CloseHandle(snapshot);
+    }
+    executables
+}
+
+/// Lists the executables of the running processes.
+///
+/// Windows executables started with Wine run the Wine loader, so the
+/// absolute paths given on command lines are listed as well.
+#[cfg(target_os = "linux")]
+fn list_running_executables() -> Vec<PathBuf> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    let entries = match fs::read_dir("/proc") {
+        Err(_) => return Vec::new(),
+        Ok(v) => v,
+    };
+    let mut executables = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let is_process = entry
+            .file_name()
+            .to_str()
+            .map(|name| name.bytes().all(|b| b.is_ascii_digit()))
+            .unwrap_or(false);
+        if !is_process {
+            continue;
+        }
+        if let Ok(exe) = fs::read_link(entry.path().join("exe")) {
+            executables.push(exe);
+        }
+        if let Ok(cmdline) = fs::read(entry.path().join("cmdline")) {
+            executables.extend(
+                cmdline
+                    .split(|&b| b == 0)
+                    .map(|arg| PathBuf::from(OsStr::from_bytes(arg)))
+                    .filter(|arg| arg.is_absolute()),
+            );
+        }
+    }
+    executables
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn list_running_executables() -> Vec<PathBuf> {
+    Vec::new()
+}
+
+/// Indicates whether another process prevents us from getting exclusive
+/// access to `path`.
+#[cfg(windows)]
+fn is_file_locked(path: &Path) -> bool {
+    use std::os::windows::fs::OpenOptionsExt;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    match fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .share_mode(0)
+        .open(path)
+    {
+        Err(e) => matches!(
+            e.raw_os_error(),
+            Some(ERROR_SHARING_VIOLATION) | Some(ERROR_LOCK_VIOLATION)
+        ),
+        Ok(_) => false,
+    }
+}
+
+/// Indicates whether another process prevents us from getting exclusive
+/// access to `path`.
+///
+/// Only advisory locks can be detected on these systems.
+#[cfg(not(windows))]
+fn is_file_locked(path: &Path) -> bool {
+    use fs2::FileExt;
+
+    let file = match fs::File::open(path) {
+        Err(_) => return false,
+        Ok(v) => v,
+    };
+    match file.try_lock_exclusive() {
+        Err(e) => e.raw_os_error() == fs2::lock_contended_error().raw_os_error(),
+        Ok(()) => {
+            let _ = file.unlock();
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use fs2::FileExt;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_locked_files() {
+        let game_dir = tempdir().unwrap();
+        let grf_path = game_dir.path().join("data.GRF");
+        fs::write(&grf_path, b"").unwrap();
+        fs::write(game_dir.path().join("ragexe.exe"), b"").unwrap();
+        assert_eq!(list_grf_files(game_dir.path()), vec![grf_path.clone()]);
+        assert!(!is_file_locked(&grf_path));
+
+        let grf_file = fs::File::open(&grf_path).unwrap();
+        grf_file.lock_exclusive().unwrap();
+        assert!(is_file_locked(&grf_path));
+        grf_file.unlock().unwrap();
+        assert!(!is_file_locked(&grf_path));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_find_running_executables() {
+        let game_dir = tempdir().unwrap();
+        let client_path = game_dir.path().join("client");
+        fs::copy("/bin/sleep", &client_path).unwrap();
+        let executables = vec![client_path.clone(), game_dir.path().join("missing")];
+        assert!(find_running_executables(&executables).is_empty());
+
+        let mut client = std::process::Command::new(&client_path)
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let running_executables = find_running_executables(&executables);
+        client.kill().unwrap();
+        let _ = client.wait();
+        assert_eq!(running_executables, vec![client_path]);
+    }
+
+    #[test]
+    fn test_describe_files_in_use() {
+        let files_in_use = GameFilesInUse {
+            running_clients: vec![PathBuf::from("ragexe.exe")],
+            locked_files: vec![PathBuf::from("data.grf"), PathBuf::from("rdata.grf")],
+        };
+        assert_eq!(
+            files_in_use.to_string(),
+            "The game client is running (ragexe.exe), close it to continue; \
+             'data.grf', 'rdata.grf' are used by another program"
+        );
+        assert_eq!(
+            files_in_use.describe(Language::BrazilianPortuguese),
+            "O cliente do jogo está em execução (ragexe.exe), feche-o para continuar; \
+             'data.grf', 'rdata.grf' estão sendo usados por outro programa"
+        );
+        assert!(GameFilesInUse::default().is_empty());
+    }
+}
@@ -1,23 +1,53 @@
+mod bandwidth;
 mod cache;
 mod cancellation;
 mod config;
+mod control;
 mod core;
+mod diagnostics;
+mod disk_space;
+mod error;
+mod hooks;
+mod http;
+mod in_use;
 mod patching;
+mod settings;
+mod signature;
+mod source;
+mod validation;
 
 use std::env;
 use std::ffi::OsString;
 use std::path::PathBuf;
 
-pub use self::config::{retrieve_patcher_configuration, PatcherConfiguration};
+pub use self::cache::PatcherCache;
+pub use self::config::{
+    retrieve_patcher_configuration, LaunchProfile, LoggingConfiguration, PatcherConfiguration,
+    WrapperConfiguration, DEFAULT_LAUNCH_PROFILE,
+};
 pub use self::core::patcher_thread_routine;
+pub use self::diagnostics::create_diagnostics_bundle;
+pub use self::error::ErrorReport;
+pub use self::hooks::{run_hooks, HookCanceler, HookEvent};
+pub use self::settings::{UserSettings, WindowSettings};
+pub use self::validation::{validate_configuration_file, Severity};
 use anyhow::{Context, Result};
 
 pub enum PatcherCommand {
     StartUpdate,
     CancelUpdate,        // Canceled by the user
+    PauseUpdate,         // Suspends downloads until the update is resumed
+    ResumeUpdate,        // Resumes paused downloads
+    RetryUpdate,         // Checks again whether the game's files are in use
+    ProbePatchServers,   // Refreshes the availability of the patch servers
     ApplyPatch(PathBuf), // Manual patch submitted by the user
 }
 
+/// Reads the patcher's cache, which describes the state of the game client.
+pub fn read_patcher_cache() -> Result<PatcherCache> {
+    cache::read_cache_file_blocking(self::core::get_cache_file_path()?)
+}
+
 pub fn get_patcher_name() -> Result<OsString> {
     let current_exe_path = env::current_exe()?;
     Ok(current_exe_path
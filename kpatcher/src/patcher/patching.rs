@@ -4,7 +4,8 @@ use std::fs;
 use std::io::{Read, Seek};
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use gruf::delta;
 use gruf::grf::{GrfArchive, GrfArchiveBuilder};
 use gruf::thor::{ThorArchive, ThorFileEntry};
 
@@ -18,6 +19,7 @@ pub enum GrfPatchingMethod {
 enum MergeEntrySource {
     TargetGrf,
     PatchThor,
+    PatchThorDelta,
     PatchGrf,
 }
 
@@ -37,11 +39,15 @@ struct MergeEntry {
 }
 
 /// Patches a GRF file with a THOR archive/patch.
-pub fn apply_patch_to_grf<R: Read + Seek>(
+///
+/// `progress_callback` is called with the number of processed entries and the
+/// total number of entries.
+pub fn apply_patch_to_grf<R: Read + Seek, CB: FnMut(usize, usize)>(
     patching_method: GrfPatchingMethod,
     create_if_needed: bool,
     grf_file_path: impl AsRef<Path>,
     thor_archive: &mut ThorArchive<R>,
+    progress_callback: CB,
 ) -> Result<()> {
     if !grf_file_path.as_ref().exists() && create_if_needed {
         // Create a new GRF file if needed
@@ -49,46 +55,62 @@ pub fn apply_patch_to_grf<R: Read + Seek>(
         GrfArchiveBuilder::create(new_grf, 2, 0)?;
     }
     match patching_method {
-        GrfPatchingMethod::InPlace => apply_patch_to_grf_ip(grf_file_path, thor_archive),
-        GrfPatchingMethod::OutOfPlace => apply_patch_to_grf_oop(grf_file_path, thor_archive),
+        GrfPatchingMethod::InPlace => {
+            apply_patch_to_grf_ip(grf_file_path, thor_archive, progress_callback)
+        }
+        GrfPatchingMethod::OutOfPlace => {
+            apply_patch_to_grf_oop(grf_file_path, thor_archive, progress_callback)
+        }
     }
 }
 
 /// Patches a GRF file with another GRF archive/patch.
-pub fn apply_grf_to_grf(
+///
+/// `progress_callback` is called with the number of processed entries and the
+/// total number of entries.
+pub fn apply_grf_to_grf<CB: FnMut(usize, usize)>(
     patching_method: GrfPatchingMethod,
     create_if_needed: bool,
     target_grf_path: impl AsRef<Path>,
     source_grf: &mut GrfArchive,
+    progress_callback: CB,
 ) -> Result<()> {
     if !target_grf_path.as_ref().exists() && create_if_needed {
         let new_grf = fs::File::create(&target_grf_path)?;
         GrfArchiveBuilder::create(new_grf, 2, 0)?;
     }
     match patching_method {
-        GrfPatchingMethod::InPlace => apply_grf_to_grf_ip(target_grf_path, source_grf),
-        GrfPatchingMethod::OutOfPlace => apply_grf_to_grf_oop(target_grf_path, source_grf),
+        GrfPatchingMethod::InPlace => {
+            apply_grf_to_grf_ip(target_grf_path, source_grf, progress_callback)
+        }
+        GrfPatchingMethod::OutOfPlace => {
+            apply_grf_to_grf_oop(target_grf_path, source_grf, progress_callback)
+        }
     }
 }
 
-fn apply_grf_to_grf_ip(
+fn apply_grf_to_grf_ip<CB: FnMut(usize, usize)>(
     target_grf_path: impl AsRef<Path>,
     source_grf: &mut GrfArchive,
+    mut progress_callback: CB,
 ) -> Result<()> {
     let mut builder = GrfArchiveBuilder::open(target_grf_path)?;
     let entries: Vec<String> = source_grf
         .get_entries()
         .map(|e| e.relative_path.clone())
         .collect();
-    for path in entries {
+    let entry_count = entries.len();
+    for (entry_number, path) in entries.into_iter().enumerate() {
         builder.import_raw_entry_from_grf(source_grf, path)?;
+        progress_callback(1 + entry_number, entry_count);
     }
     Ok(())
 }
 
-fn apply_grf_to_grf_oop(
+fn apply_grf_to_grf_oop<CB: FnMut(usize, usize)>(
     target_grf_path: impl AsRef<Path>,
     source_grf: &mut GrfArchive,
+    mut progress_callback: CB,
 ) -> Result<()> {
     // Rename file to back it up
     let mut backup_file_path = target_grf_path.as_ref().to_path_buf();
@@ -137,18 +159,20 @@ fn apply_grf_to_grf_oop(
         let grf_file = fs::File::create(target_grf_path)?;
         // Usar versão do GRF original para preservar criptografia
         let mut builder = GrfArchiveBuilder::create(grf_file, original_version_major, original_version_minor)?;
-        for (relative_path, entry) in merge_entries {
+        let entry_count = merge_entries.len();
+        for (entry_number, (relative_path, entry)) in merge_entries.into_iter().enumerate() {
             match entry.source {
                 MergeEntrySource::TargetGrf => {
                     builder.import_raw_entry_from_grf(&mut target_archive, relative_path)?;
                 }
-                MergeEntrySource::PatchThor => {
+                MergeEntrySource::PatchThor | MergeEntrySource::PatchThorDelta => {
                     unreachable!("Thor patch source in GRF patching");
                 }
                 MergeEntrySource::PatchGrf => {
                     builder.import_raw_entry_from_grf(source_grf, relative_path)?;
                 }
             }
+            progress_callback(1 + entry_number, entry_count);
         }
     }
     // Remove backup file
@@ -159,23 +183,38 @@ fn apply_grf_to_grf_oop(
 ///
 /// This is faster but produces output of bigger size and can corrupt file in
 /// case of error.
-fn apply_patch_to_grf_ip<R: Read + Seek>(
+fn apply_patch_to_grf_ip<R: Read + Seek, CB: FnMut(usize, usize)>(
     grf_file_path: impl AsRef<Path>,
     thor_archive: &mut ThorArchive<R>,
+    mut progress_callback: CB,
 ) -> Result<()> {
-    let mut builder = GrfArchiveBuilder::open(grf_file_path)?;
     let mut thor_entries: Vec<ThorFileEntry> = thor_archive
         .get_entries()
         .filter(|e| !e.is_internal())
         .cloned()
         .collect();
     thor_entries.sort_unstable_by(|a, b| a.offset.cmp(&b.offset));
-    for entry in thor_entries {
+    // Delta entries need the current content of the files they update
+    let mut base_archive = if thor_entries.iter().any(|e| e.is_delta) {
+        Some(GrfArchive::open(grf_file_path.as_ref())?)
+    } else {
+        None
+    };
+    let mut builder = GrfArchiveBuilder::open(grf_file_path)?;
+    let entry_count = thor_entries.len();
+    for (entry_number, entry) in thor_entries.into_iter().enumerate() {
         if entry.is_removed {
             let _ = builder.remove_file(&entry.relative_path);
+        } else if entry.is_delta {
+            let base_archive = base_archive
+                .as_mut()
+                .context("GRF hasn't been opened for delta patching")?;
+            let content = apply_delta_from_grf(base_archive, thor_archive, &entry.relative_path)?;
+            builder.add_file(entry.relative_path, content.as_slice())?;
         } else {
             builder.import_raw_entry_from_thor(thor_archive, entry.relative_path)?;
         }
+        progress_callback(1 + entry_number, entry_count);
     }
     Ok(())
 }
@@ -183,9 +222,10 @@ fn apply_patch_to_grf_ip<R: Read + Seek>(
 /// Patches a GRF in an out-of-place manner.
 ///
 /// This is safer and produces output of smaller size but slower.
-fn apply_patch_to_grf_oop<R: Read + Seek>(
+fn apply_patch_to_grf_oop<R: Read + Seek, CB: FnMut(usize, usize)>(
     grf_file_path: impl AsRef<Path>,
     thor_archive: &mut ThorArchive<R>,
+    mut progress_callback: CB,
 ) -> Result<()> {
     // Rename file to back it up
     let mut backup_file_path = grf_file_path.as_ref().to_path_buf();
@@ -223,10 +263,15 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
         if entry.is_removed || entry.is_internal() {
             continue;
         }
+        let source = if entry.is_delta {
+            MergeEntrySource::PatchThorDelta
+        } else {
+            MergeEntrySource::PatchThor
+        };
         merge_entries.insert(
             entry.relative_path.clone(),
             MergeEntry {
-                source: MergeEntrySource::PatchThor,
+                source,
                 source_offset: entry.offset,
                 data_size: entry.size_compressed,
                 transformation: DataTransformation::None,
@@ -238,7 +283,8 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
         let grf_file = fs::File::create(grf_file_path)?;
         // Usar versão do GRF original para preservar criptografia
         let mut builder = GrfArchiveBuilder::create(grf_file, original_version_major, original_version_minor)?;
-        for (relative_path, entry) in merge_entries {
+        let entry_count = merge_entries.len();
+        for (entry_number, (relative_path, entry)) in merge_entries.into_iter().enumerate() {
             match entry.source {
                 MergeEntrySource::TargetGrf => {
                     builder.import_raw_entry_from_grf(&mut grf_archive, relative_path)?;
@@ -246,10 +292,16 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
                 MergeEntrySource::PatchThor => {
                     builder.import_raw_entry_from_thor(thor_archive, relative_path)?;
                 }
+                MergeEntrySource::PatchThorDelta => {
+                    let content =
+                        apply_delta_from_grf(&mut grf_archive, thor_archive, &relative_path)?;
+                    builder.add_file(relative_path, content.as_slice())?;
+                }
                 MergeEntrySource::PatchGrf => {
                     unreachable!("GRF patch source in Thor patching");
                 }
             }
+            progress_callback(1 + entry_number, entry_count);
         }
     }
     // Remove backup file once the patched GRF has been built
@@ -258,9 +310,13 @@ fn apply_patch_to_grf_oop<R: Read + Seek>(
 
 /// Patches files located in the game client's directory with a THOR
 /// archive/patch.
-pub fn apply_patch_to_disk<R: Read + Seek>(
+///
+/// `progress_callback` is called with the number of processed entries and the
+/// total number of entries.
+pub fn apply_patch_to_disk<R: Read + Seek, CB: FnMut(usize, usize)>(
     root_directory: impl AsRef<Path>,
     thor_archive: &mut ThorArchive<R>,
+    mut progress_callback: CB,
 ) -> Result<()> {
     // TODO(LinkZ): Save original files before updating/removing them in order
     // to be able to restore them in case of failure
@@ -271,9 +327,10 @@ pub fn apply_patch_to_disk<R: Read + Seek>(
         .cloned()
         .collect();
     file_entries.sort_unstable_by(|a, b| a.offset.cmp(&b.offset));
-    for entry in file_entries {
-        let mut dest_path =
-            join_windows_relative_path(root_directory.as_ref(), &entry.relative_path);
+    let entry_count = file_entries.len();
+    for (entry_number, entry) in file_entries.into_iter().enumerate() {
+        let file_path = join_windows_relative_path(root_directory.as_ref(), &entry.relative_path);
+        let mut dest_path = file_path.clone();
         if let Ok(current_exe) = env::current_exe() {
             if dest_path == current_exe {
                 dest_path = dest_path.with_extension("exe.new");
@@ -283,6 +340,17 @@ pub fn apply_patch_to_disk<R: Read + Seek>(
         if entry.is_removed {
             // Try to remove file and ignore errors (file might not exist)
             let _ignore = fs::remove_file(dest_path);
+        } else if entry.is_delta {
+            let delta = thor_archive.read_file_content(&entry.relative_path)?;
+            let base = fs::read(&file_path).with_context(|| {
+                format!(
+                    "Failed to read the base of delta entry '{}'",
+                    entry.relative_path
+                )
+            })?;
+            let content = delta::apply(&base, &delta)
+                .with_context(|| format!("Failed to apply delta to '{}'", entry.relative_path))?;
+            fs::write(&dest_path, content)?;
         } else {
             // Create parent directory if needed
             if let Some(parent_dir) = dest_path.parent() {
@@ -291,26 +359,83 @@ pub fn apply_patch_to_disk<R: Read + Seek>(
             // Extract file
             thor_archive.extract_file(&entry.relative_path, &dest_path)?;
         }
+        progress_callback(1 + entry_number, entry_count);
     }
     Ok(())
 }
 
+/// Rebuilds the content of a delta entry from the current version of the file
+/// stored in `grf_archive`.
+fn apply_delta_from_grf<R: Read + Seek>(
+    grf_archive: &mut GrfArchive,
+    thor_archive: &mut ThorArchive<R>,
+    relative_path: &str,
+) -> Result<Vec<u8>> {
+    let delta = thor_archive.read_file_content(relative_path)?;
+    let base = grf_archive
+        .read_file_content(relative_path)
+        .with_context(|| format!("Failed to read the base of delta entry '{}'", relative_path))?;
+    delta::apply(&base, &delta)
+        .with_context(|| format!("Failed to apply delta to '{}'", relative_path))
+}
+
 /// Utility function used to join path-like segments the same way it's done in
 /// the GRF file format (Windows style).
-fn join_windows_relative_path(path: &Path, windows_relative_path: &str) -> PathBuf {
+pub fn join_windows_relative_path(path: &Path, windows_relative_path: &str) -> PathBuf {
     let mut result = PathBuf::from(path);
     for component in windows_relative_path.split('\\') {
-        result.push(component);
+        push_windows_path_component(&mut result, component);
     }
     result
 }
 
+#[cfg(windows)]
+fn push_windows_path_component(path: &mut PathBuf, component: &str) {
+    path.push(component);
+}
+
+/// Windows paths are case-insensitive. On other systems, existing files and
+/// directories whose name only differs in case are reused, so that patches
+/// don't duplicate them.
+#[cfg(not(windows))]
+fn push_windows_path_component(path: &mut PathBuf, component: &str) {
+    if !component.is_empty() && !path.join(component).exists() {
+        let existing_name = fs::read_dir(&path).ok().and_then(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name())
+                .find(|name| name.to_string_lossy().eq_ignore_ascii_case(component))
+        });
+        if let Some(existing_name) = existing_name {
+            path.push(existing_name);
+            return;
+        }
+    }
+    path.push(component);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use gruf::thor::ThorArchiveBuilder;
     use tempfile::tempdir;
     use walkdir::WalkDir;
 
+    #[cfg(not(windows))]
+    #[test]
+    fn test_join_windows_relative_path() {
+        let temp_dir = tempdir().unwrap();
+        fs::create_dir_all(temp_dir.path().join("data/Texture")).unwrap();
+        assert_eq!(
+            join_windows_relative_path(temp_dir.path(), r"DATA\texture\new.bmp"),
+            temp_dir.path().join("data/Texture/new.bmp")
+        );
+        assert_eq!(
+            join_windows_relative_path(temp_dir.path(), r"data\sprite\x.spr"),
+            temp_dir.path().join("data/sprite/x.spr")
+        );
+    }
+
     #[test]
     fn test_apply_patch_to_disk() {
         let thor_dir_path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("resources/tests/thor");
@@ -335,7 +460,12 @@ mod tests {
             assert!(!expected_file_path.exists());
             assert_eq!(0, count_files(temp_dir.path()));
 
-            apply_patch_to_disk(temp_dir.path(), &mut thor_archive).unwrap();
+            let mut last_progress = (0, 0);
+            apply_patch_to_disk(temp_dir.path(), &mut thor_archive, |processed, total| {
+                last_progress = (processed, total)
+            })
+            .unwrap();
+            assert_eq!(last_progress, (nb_of_added_files, nb_of_added_files));
 
             // After patching
             assert!(expected_file_path.exists());
@@ -372,6 +502,7 @@ mod tests {
                 false,
                 &grf_archive_path,
                 &mut thor_archive,
+                |_, _| {},
             )
             .unwrap();
 
@@ -398,6 +529,7 @@ mod tests {
                 true,
                 &grf_archive_path,
                 &mut thor_archive,
+                |_, _| {},
             )
             .unwrap();
 
@@ -433,6 +565,7 @@ mod tests {
                 false,
                 &grf_archive_path,
                 &mut thor_archive,
+                |_, _| {},
             )
             .unwrap();
 
@@ -459,6 +592,7 @@ mod tests {
                 true,
                 &grf_archive_path,
                 &mut thor_archive,
+                |_, _| {},
             )
             .unwrap();
 
@@ -471,6 +605,89 @@ mod tests {
         assert!(patch_maintained_integrity(&thor_archive_path, &grf_archive_path).unwrap());
     }
 
+    #[test]
+    fn test_apply_delta_patch_to_disk() {
+        let temp_dir = tempdir().unwrap();
+        let base_content: Vec<u8> = (0..8192).map(|x| (x % 253) as u8).collect();
+        let mut new_content = base_content.clone();
+        new_content[4000..4008].copy_from_slice(b"kpatcher");
+        let thor_archive_path = temp_dir.path().join("delta.thor");
+        write_delta_thor(&thor_archive_path, false, &base_content, &new_content);
+        let game_dir = temp_dir.path().join("game");
+        fs::create_dir_all(game_dir.join("data")).unwrap();
+        fs::write(game_dir.join("data/map.gat"), &base_content).unwrap();
+
+        let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+        apply_patch_to_disk(&game_dir, &mut thor_archive, |_, _| {}).unwrap();
+        assert_eq!(fs::read(game_dir.join("data/map.gat")).unwrap(), new_content);
+        // Applying the patch a second time is harmless
+        apply_patch_to_disk(&game_dir, &mut thor_archive, |_, _| {}).unwrap();
+        assert_eq!(fs::read(game_dir.join("data/map.gat")).unwrap(), new_content);
+
+        // Patching unexpected content fails
+        fs::write(game_dir.join("data/map.gat"), b"unexpected").unwrap();
+        assert!(apply_patch_to_disk(&game_dir, &mut thor_archive, |_, _| {}).is_err());
+    }
+
+    #[test]
+    fn test_apply_delta_patch_to_grf() {
+        let base_content: Vec<u8> = (0..8192).map(|x| (x % 241) as u8).collect();
+        let mut new_content = base_content.clone();
+        new_content.splice(1000..1000, b"inserted data".iter().cloned());
+        for patching_method in [GrfPatchingMethod::InPlace, GrfPatchingMethod::OutOfPlace] {
+            let temp_dir = tempdir().unwrap();
+            let thor_archive_path = temp_dir.path().join("delta.thor");
+            write_delta_thor(&thor_archive_path, true, &base_content, &new_content);
+            let grf_archive_path = temp_dir.path().join("data.grf");
+            {
+                let grf_file = fs::File::create(&grf_archive_path).unwrap();
+                let mut builder = GrfArchiveBuilder::create(grf_file, 2, 0).unwrap();
+                builder
+                    .add_file(r"data\map.gat".to_string(), base_content.as_slice())
+                    .unwrap();
+                builder
+                    .add_file(r"data\other.gat".to_string(), &[1, 2, 3][..])
+                    .unwrap();
+            }
+
+            let mut thor_archive = ThorArchive::open(&thor_archive_path).unwrap();
+            apply_patch_to_grf(
+                patching_method,
+                false,
+                &grf_archive_path,
+                &mut thor_archive,
+                |_, _| {},
+            )
+            .unwrap();
+
+            let mut grf_archive = GrfArchive::open(&grf_archive_path).unwrap();
+            assert_eq!(2, grf_archive.file_count());
+            assert_eq!(
+                grf_archive.read_file_content(r"data\map.gat").unwrap(),
+                new_content
+            );
+            assert_eq!(
+                grf_archive.read_file_content(r"data\other.gat").unwrap(),
+                vec![1, 2, 3]
+            );
+        }
+    }
+
+    fn write_delta_thor(
+        thor_file_path: &Path,
+        use_grf_merging: bool,
+        base_content: &[u8],
+        new_content: &[u8],
+    ) {
+        let thor_file = fs::File::create(thor_file_path).unwrap();
+        let mut builder =
+            ThorArchiveBuilder::new(thor_file, use_grf_merging, None, true).unwrap();
+        builder
+            .append_file_delta(r"data\map.gat".to_string(), base_content, new_content)
+            .unwrap();
+        builder.finish().unwrap();
+    }
+
     fn patch_maintained_integrity(
         thor_file_path: &PathBuf,
         grf_file_path: &PathBuf,
@@ -0,0 +1,301 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::{PatcherConfiguration, DEFAULT_LAUNCH_PROFILE};
+use crate::i18n;
+
+/// Settings chosen by the player, stored in the working directory.
+pub const USER_SETTINGS_FILE: &str = "kpatcher_settings.json";
+/// Version of the format of the settings file.
+const USER_SETTINGS_VERSION: u64 = 1;
+/// File that contained the window's position before user settings existed.
+const LEGACY_WINDOW_STATE_FILE: &str = "kpatcher_state.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct UserSettings {
+    pub version: u64,
+    pub patch_server: Option<String>, // Patch server selected in the UI
+    pub bandwidth_limit: Option<u64>, // Maximum download speed (in KiB/s)
+    pub login: Option<String>,        // Remembered login name
+    pub language: Option<String>,
+    pub launch_profile: Option<String>, // Preferred launch profile
+    pub window: Option<WindowSettings>, // Last position and size of the window
+}
+
+impl Default for UserSettings {
+    fn default() -> Self {
+        UserSettings {
+            version: USER_SETTINGS_VERSION,
+            patch_server: None,
+            bandwidth_limit: None,
+            login: None,
+            language: None,
+            launch_profile: None,
+            window: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WindowSettings {
+    pub x: i32,
+    pub y: i32,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub monitor: Option<String>, // Name of the monitor the window was on
+}
+
+#[derive(Deserialize)]
+struct LegacyWindowState {
+    x: i32,
+    y: i32,
+}
+
+impl UserSettings {
+    /// Loads the settings, or returns the default settings if they haven't
+    /// been saved yet.
+    pub fn load() -> UserSettings {
+        let mut settings = match Self::read_from(USER_SETTINGS_FILE) {
+            Ok(settings) => settings,
+            Err(e) => {
+                if Path::new(USER_SETTINGS_FILE).exists() {
+                    log::warn!("Ignoring user settings: {:#}", e);
+                }
+                UserSettings::default()
+            }
+        };
+        if settings.window.is_none() {
+            // Import the window's position saved by older versions
+            if let Some(window) = read_legacy_window_state(LEGACY_WINDOW_STATE_FILE) {
+                settings.window = Some(window);
+                if settings.save().is_ok() {
+                    let _ = std::fs::remove_file(LEGACY_WINDOW_STATE_FILE);
+                }
+            }
+        }
+        settings
+    }
+
+    pub fn save(&self) -> Result<()> {
+        self.write_to(USER_SETTINGS_FILE)
+    }
+
+    /// Reads settings, upgrading them if they've been written by an older
+    /// version of the patcher.
+    pub fn read_from(path: impl AsRef<Path>) -> Result<UserSettings> {
+        let content = std::fs::read(path)?;
+        let mut value: Value =
+            serde_json::from_slice(&content).context("Failed to deserialize user settings")?;
+        migrate(&mut value)?;
+        serde_json::from_value(value).context("Failed to deserialize user settings")
+    }
+
+    /// Writes settings, unless the file holds settings written by a newer
+    /// version of the patcher, which would be lost.
+    pub fn write_to(&self, path: impl AsRef<Path>) -> Result<()> {
+        if let Some(version) = read_version(&path) {
+            ensure_known_version(version)?;
+        }
+        let content =
+            serde_json::to_vec_pretty(self).context("Failed to serialize user settings")?;
+        std::fs::write(path, content).context("Failed to write user settings")
+    }
+
+    /// Changes the settings given in `changes`. `null` values reset settings
+    /// to their default.
+    pub fn update(&mut self, changes: Map<String, Value>) -> Result<()> {
+        let mut value = serde_json::to_value(&*self)?;
+        let object = value
+            .as_object_mut()
+            .context("User settings must be an object")?;
+        for (key, new_value) in changes {
+            if key == "version" || !object.contains_key(&key) {
+                return Err(anyhow!("Unknown setting '{}'", key));
+            }
+            object.insert(key, new_value);
+        }
+        *self = serde_json::from_value(value).context("Invalid settings")?;
+        Ok(())
+    }
+
+    /// Returns the settings, with the values that haven't been chosen by the
+    /// player taken from the configuration. The language is the one the
+    /// patcher's messages are shown in.
+    pub fn with_defaults(mut self, config: &PatcherConfiguration) -> UserSettings {
+        self.patch_server = self.preferred_patch_server(config);
+        self.launch_profile = Some(self.preferred_launch_profile(config));
+        let language =
+            i18n::resolve_language(self.language.as_deref(), config.language.as_deref());
+        self.language = Some(language.tag().to_string());
+        self
+    }
+
+    /// Name of the launch profile used to start the game: the one selected by
+    /// the player, or the one given in the configuration.
+    pub fn preferred_launch_profile(&self, config: &PatcherConfiguration) -> String {
+        self.launch_profile
+            .clone()
+            .or_else(|| config.play.default_profile.clone())
+            .unwrap_or_else(|| DEFAULT_LAUNCH_PROFILE.to_string())
+    }
+
+    /// Name of the patch server to probe first: the one selected by the
+    /// player, or the one given in the configuration.
+    pub fn preferred_patch_server(&self, config: &PatcherConfiguration) -> Option<String> {
+        self.patch_server
+            .clone()
+            .or_else(|| config.web.preferred_patch_server.clone())
+    }
+}
+
+/// Upgrades settings to the current version of the format.
+fn migrate(value: &mut Value) -> Result<()> {
+    let object = value
+        .as_object_mut()
+        .context("User settings must be an object")?;
+    let version = object.get("version").and_then(Value::as_u64).unwrap_or(0);
+    ensure_known_version(version)?;
+    // Version 0 (unversioned) only contained `patch_server`, which is unchanged
+    object.insert("version".to_string(), USER_SETTINGS_VERSION.into());
+    Ok(())
+}
+
+fn ensure_known_version(version: u64) -> Result<()> {
+    if version > USER_SETTINGS_VERSION {
+        return Err(anyhow!(
+            "User settings have been written by a newer version of the patcher (version {})",
+            version
+        ));
+    }
+    Ok(())
+}
+
+/// Returns the version of the settings stored in `path`, if any.
+fn read_version(path: impl AsRef<Path>) -> Option<u64> {
+    let content = std::fs::read(path).ok()?;
+    let value: Value = serde_json::from_slice(&content).ok()?;
+    value.get("version")?.as_u64()
+}
+
+fn read_legacy_window_state(path: impl AsRef<Path>) -> Option<WindowSettings> {
+    let content = std::fs::read(path).ok()?;
+    let state: LegacyWindowState = serde_json::from_slice(&content).ok()?;
+    Some(WindowSettings {
+        x: state.x,
+        y: state.y,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_read_write_user_settings() {
+        let tmp_file = NamedTempFile::new().unwrap();
+        let settings = UserSettings {
+            patch_server: Some("Mirror".to_string()),
+            window: Some(WindowSettings {
+                x: 10,
+                y: 20,
+                width: Some(800),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        settings.write_to(tmp_file.path()).unwrap();
+        assert_eq!(UserSettings::read_from(tmp_file.path()).unwrap(), settings);
+
+        std::fs::write(tmp_file.path(), b"{}").unwrap();
+        assert_eq!(
+            UserSettings::read_from(tmp_file.path()).unwrap(),
+            UserSettings::default()
+        );
+    }
+
+    #[test]
+    fn test_migrate_user_settings() {
+        let tmp_file = NamedTempFile::new().unwrap();
+        // Unversioned settings
+        std::fs::write(tmp_file.path(), br#"{"patch_server": "Mirror"}"#).unwrap();
+        let settings = UserSettings::read_from(tmp_file.path()).unwrap();
+        assert_eq!(settings.version, USER_SETTINGS_VERSION);
+        assert_eq!(settings.patch_server.as_deref(), Some("Mirror"));
+
+        std::fs::write(tmp_file.path(), br#"{"version": 1000}"#).unwrap();
+        assert!(UserSettings::read_from(tmp_file.path()).is_err());
+        // Newer settings aren't overwritten
+        assert!(UserSettings::default().write_to(tmp_file.path()).is_err());
+        assert_eq!(
+            std::fs::read(tmp_file.path()).unwrap(),
+            br#"{"version": 1000}"#
+        );
+
+        std::fs::write(tmp_file.path(), br#"{"x": 5, "y": -3}"#).unwrap();
+        assert_eq!(
+            read_legacy_window_state(tmp_file.path()),
+            Some(WindowSettings {
+                x: 5,
+                y: -3,
+                ..Default::default()
+            })
+        );
+    }
+
+    #[test]
+    fn test_update_user_settings() {
+        let mut settings = UserSettings {
+            login: Some("player".to_string()),
+            ..Default::default()
+        };
+        let changes = |value: Value| value.as_object().unwrap().clone();
+        settings
+            .update(changes(json!({"bandwidth_limit": 512, "login": null})))
+            .unwrap();
+        assert_eq!(settings.bandwidth_limit, Some(512));
+        assert_eq!(settings.login, None);
+
+        assert!(settings.update(changes(json!({"unknown": 1}))).is_err());
+        assert!(settings.update(changes(json!({"version": 0}))).is_err());
+        assert!(settings
+            .update(changes(json!({"bandwidth_limit": "fast"})))
+            .is_err());
+        // Failed updates don't change anything
+        assert_eq!(settings.bandwidth_limit, Some(512));
+    }
+
+    #[test]
+    fn test_user_settings_with_defaults() {
+        let config: PatcherConfiguration = serde_yaml::from_str(
+            "
+window: {title: Test, width: 800, height: 600, resizable: false}
+play: {path: client, arguments: [], default_profile: Windowed}
+setup: {path: '', arguments: []}
+web: {index_url: 'https://example.com/', patch_servers: [], preferred_patch_server: Mirror}
+client: {default_grf_name: data.grf}
+patching: {in_place: true, check_integrity: false, create_grf: true}
+language: pt-BR
+",
+        )
+        .unwrap();
+        let settings = UserSettings::default().with_defaults(&config);
+        assert_eq!(settings.patch_server.as_deref(), Some("Mirror"));
+        assert_eq!(settings.launch_profile.as_deref(), Some("Windowed"));
+        assert_eq!(settings.language.as_deref(), Some("pt-BR"));
+
+        // The player's choices take precedence
+        let settings = UserSettings {
+            language: Some("es".to_string()),
+            ..Default::default()
+        }
+        .with_defaults(&config);
+        assert_eq!(settings.language.as_deref(), Some("es"));
+    }
+}
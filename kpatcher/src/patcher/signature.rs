@@ -0,0 +1,256 @@
+use std::convert::TryInto;
+use std::io::Read;
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{Signature, VerifyingKey};
+use sha2::{Digest, Sha512};
+
+use super::config::SignatureConfiguration;
+use super::error::PatcherError;
+
+/// Suffix of detached signature files (e.g. 'plist.txt.sig')
+pub const SIGNATURE_FILE_SUFFIX: &str = ".sig";
+/// Ed25519ph context, must be the same as the one used by mkpatch
+/// IMPORTANTE: Manter sincronizado com mkpatch/src/signature.rs
+const SIGNATURE_CONTEXT: &[u8] = b"kpatcher";
+
+/// Verifies detached Ed25519 signatures of patch lists and patches.
+///
+/// Signatures are made on the SHA-512 digest of the file's name, a null byte
+/// and the content (Ed25519ph), which allows verifying big archives without
+/// loading them in memory. Binding the name prevents a signed file from being
+/// served under another name.
+#[derive(Clone)]
+pub struct SignatureVerifier {
+    public_keys: Vec<VerifyingKey>,
+    required: bool,
+}
+
+impl SignatureVerifier {
+    pub fn from_configuration(config: Option<&SignatureConfiguration>) -> Result<Self> {
+        let config = match config {
+            None => {
+                return Ok(SignatureVerifier {
+                    public_keys: vec![],
+                    required: false,
+                })
+            }
+            Some(v) => v,
+        };
+        let public_keys = config
+            .public_keys
+            .iter()
+            .map(|public_key| parse_public_key(public_key))
+            .collect::<Result<Vec<_>>>()?;
+        let required = config.required.unwrap_or(true);
+        if required && public_keys.is_empty() {
+            return Err(anyhow!("Signatures are required but no public key is set"));
+        }
+        Ok(SignatureVerifier {
+            public_keys,
+            required,
+        })
+    }
+
+    /// Indicates whether signatures should be retrieved and checked.
+    pub fn is_enabled(&self) -> bool {
+        !self.public_keys.is_empty()
+    }
+
+    /// Indicates whether unsigned content is refused.
+    pub fn is_required(&self) -> bool {
+        self.is_enabled() && self.required
+    }
+
+    /// Verifies `content` against the content of its detached signature file,
+    /// if any. `content_name` is the file's path or URL, whose last component
+    /// is part of the signature.
+    ///
+    /// A missing signature is only accepted if signatures aren't required.
+    pub fn verify<R: Read>(
+        &self,
+        content_name: &str,
+        content: R,
+        signature_file_content: Option<&[u8]>,
+    ) -> Result<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let signature_file_content = match signature_file_content {
+            Some(v) => v,
+            None if self.required => {
+                return Err(PatcherError::MissingSignature {
+                    file: content_name.to_string(),
+                }
+                .into());
+            }
+            None => {
+                log::warn!("'{}' isn't signed", content_name);
+                return Ok(());
+            }
+        };
+        let signature = parse_signature(signature_file_content)
+            .with_context(|| format!("Invalid signature file for '{}'", content_name))?;
+        let digest = sha512_digest(signed_name(content_name), content)
+            .with_context(|| format!("Failed to read '{}'", content_name))?;
+        let is_valid = self.public_keys.iter().any(|public_key| {
+            public_key
+                .verify_prehashed(digest.clone(), Some(SIGNATURE_CONTEXT), &signature)
+                .is_ok()
+        });
+        if !is_valid {
+            return Err(PatcherError::InvalidSignature {
+                file: content_name.to_string(),
+            }
+            .into());
+        }
+        log::trace!("'{}' has a valid signature", content_name);
+        Ok(())
+    }
+}
+
+/// Name of the file, as given to mkpatch when signing it
+fn signed_name(content_name: &str) -> &str {
+    content_name
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or(content_name)
+}
+
+fn sha512_digest<R: Read>(name: &str, mut content: R) -> Result<Sha512> {
+    let mut hasher = Sha512::new();
+    hasher.update(name.as_bytes());
+    hasher.update([0]);
+    std::io::copy(&mut content, &mut hasher)?;
+    Ok(hasher)
+}
+
+fn parse_public_key(hex_public_key: &str) -> Result<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(hex_public_key.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Invalid public key '{}'", hex_public_key))?;
+    VerifyingKey::from_bytes(&bytes)
+        .with_context(|| format!("Invalid public key '{}'", hex_public_key))
+}
+
+fn parse_signature(signature_file_content: &[u8]) -> Result<Signature> {
+    let hex_signature = std::str::from_utf8(signature_file_content)?.trim();
+    let bytes: [u8; 64] = hex::decode(hex_signature)?
+        .try_into()
+        .map_err(|_| anyhow!("Invalid signature length"))?;
+    Ok(Signature::from_bytes(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+
+    const CONTENT: &[u8] = b"1 patch1.thor\n2 patch2.thor";
+
+    fn sign(signing_key: &SigningKey, name: &str, content: &[u8]) -> Vec<u8> {
+        let mut digest = Sha512::new();
+        digest.update(name.as_bytes());
+        digest.update([0]);
+        digest.update(content);
+        let signature = signing_key
+            .sign_prehashed(digest, Some(SIGNATURE_CONTEXT))
+            .unwrap();
+        hex::encode(signature.to_bytes()).into_bytes()
+    }
+
+    fn verifier(signing_key: &SigningKey, required: bool) -> SignatureVerifier {
+        let config = SignatureConfiguration {
+            public_keys: vec![hex::encode(signing_key.verifying_key().to_bytes())],
+            required: Some(required),
+        };
+        SignatureVerifier::from_configuration(Some(&config)).unwrap()
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let other_key = SigningKey::from_bytes(&[8; 32]);
+        let signature = sign(&signing_key, "plist.txt", CONTENT);
+        let verifier = verifier(&signing_key, true);
+
+        assert!(verifier
+            .verify("plist.txt", CONTENT, Some(&signature))
+            .is_ok());
+        // Tampered content
+        assert!(verifier
+            .verify("plist.txt", &b"1 evil.thor"[..], Some(&signature))
+            .is_err());
+        // Signed with another key
+        let other_signature = sign(&other_key, "plist.txt", CONTENT);
+        assert!(verifier
+            .verify("plist.txt", CONTENT, Some(&other_signature))
+            .is_err());
+        // Garbage signature
+        assert!(verifier
+            .verify("plist.txt", CONTENT, Some(&b"not a signature"[..]))
+            .is_err());
+    }
+
+    #[test]
+    fn test_renamed_file_is_rejected() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let verifier = verifier(&signing_key, true);
+        let patch = b"patch content";
+        let signature = sign(&signing_key, "2.thor", patch);
+
+        // Only the last component of the path or URL is signed
+        assert!(verifier
+            .verify(
+                "https://myserver.com/data/2.thor",
+                &patch[..],
+                Some(&signature)
+            )
+            .is_ok());
+        assert!(verifier
+            .verify("data\\2.thor", &patch[..], Some(&signature))
+            .is_ok());
+        // A validly signed patch served under another name
+        let err = verifier
+            .verify(
+                "https://myserver.com/data/3.thor",
+                &patch[..],
+                Some(&signature),
+            )
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<PatcherError>(),
+            Some(PatcherError::InvalidSignature { .. })
+        ));
+    }
+
+    #[test]
+    fn test_missing_signature() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        assert!(verifier(&signing_key, true)
+            .verify("plist.txt", CONTENT, None)
+            .is_err());
+        assert!(verifier(&signing_key, false)
+            .verify("plist.txt", CONTENT, None)
+            .is_ok());
+        // Verification is disabled without configuration
+        let disabled_verifier = SignatureVerifier::from_configuration(None).unwrap();
+        assert!(!disabled_verifier.is_enabled());
+        assert!(disabled_verifier.verify("plist.txt", CONTENT, None).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_configuration() {
+        let config = SignatureConfiguration {
+            public_keys: vec![],
+            required: None,
+        };
+        assert!(SignatureVerifier::from_configuration(Some(&config)).is_err());
+        let config = SignatureConfiguration {
+            public_keys: vec!["abcd".to_string()],
+            required: Some(false),
+        };
+        assert!(SignatureVerifier::from_configuration(Some(&config)).is_err());
+    }
+}
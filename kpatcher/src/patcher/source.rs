@@ -0,0 +1,176 @@
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use url::Url;
+
+/// Location of a patch list file or of a directory containing patches.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PatchLocation {
+    Remote(Url),    // HTTP(S) URL
+    Local(PathBuf), // Local path, `file://` URLs are converted to paths
+}
+
+impl PatchLocation {
+    /// Parses a location given in the configuration.
+    ///
+    /// `http://` and `https://` URLs are remote locations, `file://` URLs and
+    /// plain paths (absolute or relative to the working directory) are local.
+    pub fn parse(location: &str) -> Result<Self> {
+        match Url::parse(location) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(Self::Remote(url)),
+            Ok(url) if url.scheme() == "file" => url
+                .to_file_path()
+                .map(Self::Local)
+                .map_err(|_| anyhow!("Invalid file URL '{}'", location)),
+            // Windows paths such as 'C:\patches' are parsed as URLs with a
+            // one-letter scheme
+            Ok(url) if url.scheme().len() > 1 => {
+                Err(anyhow!("Unsupported URL scheme '{}'", url.scheme()))
+            }
+            _ => Ok(Self::Local(PathBuf::from(location))),
+        }
+    }
+
+    /// Returns the location of the file named after this location's file
+    /// name followed by `suffix` (e.g. 'plist.txt' -> 'plist.txt.sig').
+    pub fn with_suffix(&self, suffix: &str) -> Self {
+        match self {
+            Self::Remote(url) => {
+                let mut url = url.clone();
+                url.set_path(&format!("{}{}", url.path(), suffix));
+                Self::Remote(url)
+            }
+            Self::Local(path) => {
+                let mut path = path.clone().into_os_string();
+                path.push(suffix);
+                Self::Local(PathBuf::from(path))
+            }
+        }
+    }
+
+    /// Name of the file at this location, without the URL's query (e.g.
+    /// 'plist.txt').
+    pub fn file_name(&self) -> String {
+        match self {
+            Self::Remote(url) => url
+                .path_segments()
+                .and_then(|mut segments| segments.next_back())
+                .unwrap_or_default()
+                .to_string(),
+            Self::Local(path) => path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned(),
+        }
+    }
+
+    /// Returns the location of `file_name`, relative to this location.
+    pub fn join(&self, file_name: &str) -> Result<Self> {
+        match self {
+            Self::Remote(url) => Ok(Self::Remote(url.join(file_name)?)),
+            Self::Local(path) => {
+                if Path::new(file_name).has_root() {
+                    return Err(anyhow!("'{}' isn't a relative path", file_name));
+                }
+                // Patch lists must not reach files outside of this location
+                let escapes_location = Path::new(file_name).components().any(|component| {
+                    !matches!(component, Component::Normal(_) | Component::CurDir)
+                });
+                if escapes_location {
+                    return Err(anyhow!(
+                        "'{}' is outside of '{}'",
+                        file_name,
+                        path.display()
+                    ));
+                }
+                Ok(Self::Local(path.join(file_name)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for PatchLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Remote(url) => write!(f, "{}", url),
+            Self::Local(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_patch_location() {
+        assert_eq!(
+            PatchLocation::parse("https://myserver.com/data/").unwrap(),
+            PatchLocation::Remote(Url::parse("https://myserver.com/data/").unwrap())
+        );
+        assert_eq!(
+            PatchLocation::parse("patches/data").unwrap(),
+            PatchLocation::Local(PathBuf::from("patches/data"))
+        );
+        let absolute_path = std::env::temp_dir().join("patches");
+        assert_eq!(
+            PatchLocation::parse(Url::from_file_path(&absolute_path).unwrap().as_str()).unwrap(),
+            PatchLocation::Local(absolute_path.clone())
+        );
+        assert_eq!(
+            PatchLocation::parse(absolute_path.to_str().unwrap()).unwrap(),
+            PatchLocation::Local(absolute_path)
+        );
+        assert!(PatchLocation::parse("ftp://myserver.com/data/").is_err());
+    }
+
+    #[test]
+    fn test_patch_location_file_name() {
+        assert_eq!(
+            PatchLocation::parse("https://myserver.com/plist.txt?v=2")
+                .unwrap()
+                .file_name(),
+            "plist.txt"
+        );
+        assert_eq!(
+            PatchLocation::parse("patches/plist.txt")
+                .unwrap()
+                .file_name(),
+            "plist.txt"
+        );
+    }
+
+    #[test]
+    fn test_join_patch_location() {
+        let remote = PatchLocation::parse("https://myserver.com/data/").unwrap();
+        assert_eq!(
+            remote.join("patch1.thor").unwrap().to_string(),
+            "https://myserver.com/data/patch1.thor"
+        );
+        let local = PatchLocation::parse("data").unwrap();
+        assert_eq!(
+            local.join("patch1.thor").unwrap(),
+            PatchLocation::Local(PathBuf::from("data").join("patch1.thor"))
+        );
+        assert!(local.join("/patch1.thor").is_err());
+        assert!(local.join("../../secret").is_err());
+        assert!(local.join("patches/../../secret").is_err());
+        assert!(local.join("./patches/patch1.thor").is_ok());
+    }
+
+    #[test]
+    fn test_patch_location_with_suffix() {
+        let remote = PatchLocation::parse("https://myserver.com/plist.txt?v=2").unwrap();
+        assert_eq!(
+            remote.with_suffix(".sig").to_string(),
+            "https://myserver.com/plist.txt.sig?v=2"
+        );
+        let local = PatchLocation::parse("data/plist.txt").unwrap();
+        assert_eq!(
+            local.with_suffix(".sig"),
+            PatchLocation::Local(PathBuf::from("data/plist.txt.sig"))
+        );
+    }
+}
@@ -0,0 +1,468 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use log::LevelFilter;
+use url::Url;
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use super::config::{PatchServerInfo, PatcherConfiguration, DEFAULT_LAUNCH_PROFILE};
+use super::source::PatchLocation;
+use crate::i18n::Language;
+
+/// Largest window the patcher accepts, in pixels
+const MAX_WINDOW_SIZE: i32 = 16384;
+/// Schemes supported for the patch servers' proxies
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,   // The patcher refuses the configuration
+    Warning, // The patcher starts, but something may not work
+}
+
+/// Problem found in a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDiagnostic {
+    pub severity: Severity,
+    pub path: String, // Option concerned (e.g. web.patch_servers[1].plist_url)
+    pub message: String,
+    pub line: Option<usize>,   // Starts at 1
+    pub column: Option<usize>, // Starts at 1
+}
+
+impl fmt::Display for ConfigDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let (Some(line), Some(column)) = (self.line, self.column) {
+            write!(f, "{}:{}: ", line, column)?;
+        }
+        match self.severity {
+            Severity::Error => write!(f, "error: ")?,
+            Severity::Warning => write!(f, "warning: ")?,
+        }
+        if !self.path.is_empty() {
+            write!(f, "{}: ", self.path)?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Outcome of the validation of a configuration.
+pub struct ConfigValidation {
+    pub configuration: Option<PatcherConfiguration>, // Unless the file can't be deserialized
+    pub diagnostics: Vec<ConfigDiagnostic>,
+}
+
+impl ConfigValidation {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|diagnostic| diagnostic.severity == Severity::Error)
+    }
+}
+
+/// Validates the configuration file located at `path`. Relative paths of the
+/// configuration are resolved from the file's directory.
+pub fn validate_configuration_file(path: &Path) -> Result<ConfigValidation> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+    let base_dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Ok(validate_configuration(&content, base_dir))
+}
+
+/// Deserializes and checks a configuration. Relative paths of the
+/// configuration are resolved from `base_dir`.
+pub fn validate_configuration(content: &str, base_dir: &Path) -> ConfigValidation {
+    let configuration: PatcherConfiguration = match serde_yaml::from_str(content) {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            let location = e.location();
+            let mut message = e.to_string();
+            if let Some(location) = &location {
+                // The location is reported separately
+                let suffix = format!(" at line {} column {}", location.line(), location.column());
+                if let Some(stripped) = message.strip_suffix(&suffix) {
+                    message = stripped.to_string();
+                }
+            }
+            return ConfigValidation {
+                configuration: None,
+                diagnostics: vec![ConfigDiagnostic {
+                    severity: Severity::Error,
+                    path: String::new(),
+                    message,
+                    line: location.as_ref().map(|location| location.line()),
+                    column: location.as_ref().map(|location| location.column()),
+                }],
+            };
+        }
+    };
+    let mut validator = Validator {
+        positions: locate_options(content),
+        base_dir,
+        diagnostics: Vec::new(),
+    };
+    validator.check(&configuration);
+    ConfigValidation {
+        configuration: Some(configuration),
+        diagnostics: validator.diagnostics,
+    }
+}
+
+struct Validator<'a> {
+    positions: HashMap<String, Marker>,
+    base_dir: &'a Path,
+    diagnostics: Vec<ConfigDiagnostic>,
+}
+
+impl Validator<'_> {
+    fn check(&mut self, config: &PatcherConfiguration) {
+        self.check_window(config);
+        self.check_web(config);
+        self.check_play(config);
+        if !config.setup.path.is_empty() {
+            self.check_file_exists("setup.path", &config.setup.path);
+        }
+        if let Some(language) = &config.language {
+            if Language::from_tag(language).is_none() {
+                self.warning(
+                    "language",
+                    format!("Unsupported language '{}', using the system's", language),
+                );
+            }
+        }
+        if let Some(level) = config.logging.as_ref().and_then(|l| l.level.as_ref()) {
+            if level.parse::<LevelFilter>().is_err() {
+                self.warning(
+                    "logging.level",
+                    format!("Unknown log level '{}', using 'info'", level),
+                );
+            }
+        }
+    }
+
+    fn check_window(&mut self, config: &PatcherConfiguration) {
+        let sizes = [
+            ("window.width", config.window.width),
+            ("window.height", config.window.height),
+        ];
+        for (path, size) in sizes {
+            if size <= 0 || size > MAX_WINDOW_SIZE {
+                self.error(
+                    path,
+                    format!("Must be between 1 and {} pixels", MAX_WINDOW_SIZE),
+                );
+            }
+        }
+        if config.window.border_radius.unwrap_or(0) < 0 {
+            self.error("window.border_radius", "Can't be negative".to_string());
+        }
+    }
+
+    fn check_web(&mut self, config: &PatcherConfiguration) {
+        let web = &config.web;
+        self.check_location("web.index_url", &web.index_url);
+        if web.patch_servers.is_empty() {
+            self.warning(
+                "web.patch_servers",
+                "No patch server, only manual patches can be applied".to_string(),
+            );
+        }
+        let mut names = HashSet::new();
+        for (i, server) in web.patch_servers.iter().enumerate() {
+            let path = format!("web.patch_servers[{}]", i);
+            if server.name.is_empty() {
+                self.error(&format!("{}.name", path), "Can't be empty".to_string());
+            } else if !names.insert(server.name.as_str()) {
+                self.error(
+                    &format!("{}.name", path),
+                    format!("Duplicate patch server name '{}'", server.name),
+                );
+            }
+            self.check_patch_server(&path, server);
+        }
+        if let Some(preferred_patch_server) = &web.preferred_patch_server {
+            if !names.contains(preferred_patch_server.as_str()) {
+                self.error(
+                    "web.preferred_patch_server",
+                    format!("Unknown patch server '{}'", preferred_patch_server),
+                );
+            }
+        }
+    }
+
+    fn check_patch_server(&mut self, path: &str, server: &PatchServerInfo) {
+        self.check_location(&format!("{}.plist_url", path), &server.plist_url);
+        self.check_location(&format!("{}.patch_url", path), &server.patch_url);
+        if let Some(control_url) = &server.control_url {
+            self.check_location(&format!("{}.control_url", path), control_url);
+        }
+        if let Some(proxy) = &server.proxy {
+            let proxy_path = format!("{}.proxy", path);
+            match Url::parse(proxy) {
+                Err(e) => self.error(&proxy_path, format!("Invalid URL '{}': {}", proxy, e)),
+                Ok(url) if !PROXY_SCHEMES.contains(&url.scheme()) => self.error(
+                    &proxy_path,
+                    format!("Unsupported proxy scheme '{}'", url.scheme()),
+                ),
+                Ok(_) => {}
+            }
+        }
+    }
+
+    fn check_play(&mut self, config: &PatcherConfiguration) {
+        self.check_file_exists("play.path", &config.play.path);
+        let mut names = HashSet::from([DEFAULT_LAUNCH_PROFILE]);
+        for (i, profile) in config.play.profiles.iter().enumerate() {
+            let path = format!("play.profiles[{}]", i);
+            if profile.name.is_empty() {
+                self.error(&format!("{}.name", path), "Can't be empty".to_string());
+            } else if !names.insert(profile.name.as_str()) {
+                self.error(
+                    &format!("{}.name", path),
+                    format!("Duplicate launch profile name '{}'", profile.name),
+                );
+            }
+            self.check_file_exists(&format!("{}.path", path), &profile.path);
+        }
+        if let Some(default_profile) = &config.play.default_profile {
+            if !names.contains(default_profile.as_str()) {
+                self.error(
+                    "play.default_profile",
+                    format!("Unknown launch profile '{}'", default_profile),
+                );
+            }
+        }
+    }
+
+    /// Checks a URL or a path, which must exist if it's local.
+    fn check_location(&mut self, path: &str, location: &str) {
+        match PatchLocation::parse(location) {
+            Err(e) => self.error(path, format!("Invalid URL '{}': {}", location, e)),
+            Ok(PatchLocation::Remote(_)) => {}
+            Ok(PatchLocation::Local(local_path)) => {
+                if !self.base_dir.join(&local_path).exists() {
+                    self.warning(path, format!("'{}' doesn't exist", local_path.display()));
+                }
+            }
+        }
+    }
+
+    /// Game files may be installed by patches, so missing files are only
+    /// reported as warnings.
+    fn check_file_exists(&mut self, path: &str, file_path: &str) {
+        if !self.base_dir.join(file_path).is_file() {
+            self.warning(path, format!("File '{}' doesn't exist", file_path));
+        }
+    }
+
+    fn error(&mut self, path: &str, message: String) {
+        self.report(Severity::Error, path, message);
+    }
+
+    fn warning(&mut self, path: &str, message: String) {
+        self.report(Severity::Warning, path, message);
+    }
+
+    fn report(&mut self, severity: Severity, path: &str, message: String) {
+        // Options left to their default value are located at their parent
+        let mut located_path = path;
+        let marker = loop {
+            if let Some(marker) = self.positions.get(located_path) {
+                break Some(marker);
+            }
+            match located_path.rfind(['.', '[']) {
+                Some(end) => located_path = &located_path[..end],
+                None => break None,
+            }
+        };
+        self.diagnostics.push(ConfigDiagnostic {
+            severity,
+            path: path.to_string(),
+            message,
+            line: marker.map(|marker| marker.line()),
+            column: marker.map(|marker| marker.col() + 1),
+        });
+    }
+}
+
+/// Finds where each option is defined in a YAML document. Options are
+/// identified by their path (e.g. `web.patch_servers[1].plist_url`).
+fn locate_options(content: &str) -> HashMap<String, Marker> {
+    let mut locator = OptionLocator::default();
+    // The document's syntax has already been checked
+    let _ = Parser::new(content.chars()).load(&mut locator, false);
+    locator.positions
+}
+
+#[derive(Default)]
+struct OptionLocator {
+    positions: HashMap<String, Marker>,
+    nodes: Vec<Node>,
+}
+
+enum Node {
+    Mapping {
+        path: String,
+        key: Option<(String, Marker)>, // Key whose value is expected next
+    },
+    Sequence {
+        path: String,
+        index: usize,
+    },
+}
+
+impl OptionLocator {
+    /// Records the position of a value and returns its path. Mappings and
+    /// sequences are located at their key, if they have one.
+    fn locate_value(&mut self, mark: Marker, is_scalar: bool) -> Option<String> {
+        let (path, mark) = match self.nodes.last_mut()? {
+            Node::Mapping { path, key } => {
+                let (key, key_mark) = key.take()?;
+                (
+                    join_path(path, &key),
+                    if is_scalar { mark } else { key_mark },
+                )
+            }
+            Node::Sequence { path, index } => {
+                *index += 1;
+                (format!("{}[{}]", path, *index - 1), mark)
+            }
+        };
+        self.positions.insert(path.clone(), mark);
+        Some(path)
+    }
+}
+
+impl MarkedEventReceiver for OptionLocator {
+    fn on_event(&mut self, event: Event, mark: Marker) {
+        match event {
+            Event::Scalar(value, ..) => {
+                if let Some(Node::Mapping {
+                    key: key @ None, ..
+                }) = self.nodes.last_mut()
+                {
+                    *key = Some((value, mark));
+                } else {
+                    self.locate_value(mark, true);
+                }
+            }
+            Event::Alias(_) => {
+                self.locate_value(mark, true);
+            }
+            Event::MappingStart(_) => {
+                let path = self.locate_value(mark, false).unwrap_or_default();
+                self.nodes.push(Node::Mapping { path, key: None });
+            }
+            Event::SequenceStart(_) => {
+                let path = self.locate_value(mark, false).unwrap_or_default();
+                self.nodes.push(Node::Sequence { path, index: 0 });
+            }
+            Event::MappingEnd | Event::SequenceEnd => {
+                self.nodes.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+fn join_path(parent: &str, key: &str) -> String {
+    match parent.is_empty() {
+        true => key.to_string(),
+        false => format!("{}.{}", parent, key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const CONFIGURATION: &str = "\
+window:
+  title: Test
+  width: 0
+  height: 580
+  resizable: false
+play:
+  path: client.exe
+  arguments: []
+  default_profile: missing
+setup:
+  path: ''
+  arguments: []
+web:
+  index_url: https://example.com/index.html
+  preferred_patch_server: Backup
+  patch_servers:
+    - name: Main
+      plist_url: https://example.com/plist.txt
+      patch_url: https://example.com/data/
+    - name: Main
+      plist_url: ftp://example.com/plist.txt
+      patch_url: patches
+client:
+  default_grf_name: data.grf
+patching:
+  in_place: true
+  check_integrity: true
+  create_grf: true
+";
+
+    fn find<'a>(validation: &'a ConfigValidation, path: &str) -> &'a ConfigDiagnostic {
+        validation
+            .diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.path == path)
+            .unwrap_or_else(|| panic!("No diagnostic for '{}'", path))
+    }
+
+    #[test]
+    fn test_validate_configuration() {
+        let base_dir = tempdir().unwrap();
+        std::fs::create_dir(base_dir.path().join("patches")).unwrap();
+        let validation = validate_configuration(CONFIGURATION, base_dir.path());
+        assert!(validation.configuration.is_some());
+        assert!(validation.has_errors());
+
+        let width = find(&validation, "window.width");
+        assert_eq!(width.severity, Severity::Error);
+        assert_eq!((width.line, width.column), (Some(3), Some(10)));
+        let name = find(&validation, "web.patch_servers[1].name");
+        assert_eq!(name.message, "Duplicate patch server name 'Main'");
+        assert_eq!((name.line, name.column), (Some(20), Some(13)));
+        let plist_url = find(&validation, "web.patch_servers[1].plist_url");
+        assert_eq!(plist_url.severity, Severity::Error);
+        assert_eq!(plist_url.line, Some(21));
+        let preferred = find(&validation, "web.preferred_patch_server");
+        assert_eq!(
+            preferred.to_string(),
+            "15:27: error: web.preferred_patch_server: Unknown patch server 'Backup'"
+        );
+        assert_eq!(find(&validation, "play.default_profile").line, Some(9));
+        let play_path = find(&validation, "play.path");
+        assert_eq!(play_path.severity, Severity::Warning);
+        // Existing local directories and empty setup paths are fine
+        assert!(validation
+            .diagnostics
+            .iter()
+            .all(|d| d.path != "web.patch_servers[1].patch_url" && d.path != "setup.path"));
+        assert_eq!(validation.diagnostics.len(), 6);
+    }
+
+    #[test]
+    fn test_invalid_yaml() {
+        let configuration = CONFIGURATION.replace("  height: 580\n", "");
+        let validation = validate_configuration(&configuration, Path::new("."));
+        assert!(validation.configuration.is_none());
+        assert_eq!(
+            validation.diagnostics[0].to_string(),
+            "2:8: error: window: missing field `height`"
+        );
+    }
+}
@@ -1,26 +1,146 @@
+use std::collections::HashMap;
+use std::path::Path;
+
 use anyhow::Result;
 
+use crate::patcher::WrapperConfiguration;
+
+/// Options of a started process.
+#[derive(Default)]
+pub struct ProcessOptions<'a> {
+    pub working_directory: Option<&'a Path>, // Defaults to the directory of Windows executables
+    pub env: Option<&'a HashMap<String, String>>, // Additional environment variables
+    #[cfg_attr(windows, allow(dead_code))]
+    pub wrapper: Option<&'a WrapperConfiguration>, // Default: Wine for Windows executables
+}
+
+/// How a process exited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessExit {
+    pub code: Option<i32>, // None if the process has been killed by a signal
+    pub crashed: bool,
+}
+
+/// Process started with `start_executable`, that can be waited for.
+#[cfg(windows)]
+pub struct ChildProcess(windows::ProcessHandle);
+
+#[cfg(not(windows))]
+pub struct ChildProcess(std::process::Child);
+
+impl ChildProcess {
+    /// Blocks until the process exits.
+    #[cfg(windows)]
+    pub fn wait(self) -> Result<ProcessExit> {
+        let code = self.0.wait()?;
+        Ok(ProcessExit {
+            code: Some(code as i32),
+            // Unhandled exceptions end processes with an NTSTATUS error code
+            // (e.g. 0xC0000005 for access violations)
+            crashed: code >= 0xC000_0000,
+        })
+    }
+
+    /// Blocks until the process exits.
+    #[cfg(not(windows))]
+    pub fn wait(mut self) -> Result<ProcessExit> {
+        let status = self.0.wait()?;
+        #[cfg(unix)]
+        let crashed = {
+            use std::os::unix::process::ExitStatusExt;
+            status.signal().is_some()
+        };
+        #[cfg(not(unix))]
+        let crashed = false;
+        Ok(ProcessExit {
+            code: status.code(),
+            crashed,
+        })
+    }
+}
+
 /// Starts an executable file in a cross-platform way.
 ///
-/// This is the Windows version.
+/// This is the Windows version. Returns `None` if the started process can't
+/// be waited for.
 #[cfg(windows)]
-pub fn start_executable<I, S>(exe_path: &str, exe_arguments: I) -> Result<bool>
+pub fn start_executable<I, S>(
+    exe_path: &str,
+    exe_arguments: I,
+    options: &ProcessOptions,
+) -> Result<Option<ChildProcess>>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
 {
-    // Fold parameter list into a String
+    // ShellExecuteEx doesn't take an environment, which is only given to
+    // processes started with CreateProcess (these can't be elevated)
+    if let Some(env) = options.env.filter(|env| !env.is_empty()) {
+        let process_handle = windows::spawn_process_with_env(
+            exe_path,
+            exe_arguments,
+            env,
+            options.working_directory,
+        )?;
+        return Ok(Some(ChildProcess(process_handle)));
+    }
     let exe_parameter = exe_arguments
         .into_iter()
-        .fold(String::new(), |a: String, b| a + " " + b.as_ref() + "");
-    windows::win32_spawn_process_runas(exe_path, &exe_parameter)
+        .map(|argument| quote_argument(argument.as_ref()))
+        .collect::<Vec<String>>()
+        .join(" ");
+    let process_handle =
+        windows::win32_spawn_process_runas(exe_path, &exe_parameter, options.working_directory)?;
+    Ok(process_handle.map(ChildProcess))
+}
+
+/// Quotes `argument` so that it's parsed back as a single argument by
+/// `CommandLineToArgvW`, which is how most Windows programs split their
+/// command line.
+#[cfg(any(windows, test))]
+fn quote_argument(argument: &str) -> String {
+    let needs_quotes = argument.is_empty()
+        || argument
+            .chars()
+            .any(|c| matches!(c, ' ' | '\t' | '\n' | '\x0b' | '"'));
+    if !needs_quotes {
+        return argument.to_string();
+    }
+    let mut quoted = String::with_capacity(argument.len() + 2);
+    quoted.push('"');
+    let mut backslashes = 0;
+    for c in argument.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // Backslashes are only special before a quote
+                quoted.extend(std::iter::repeat_n('\\', 2 * backslashes + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            _ => {
+                quoted.extend(std::iter::repeat_n('\\', backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    // Don't let the closing quote be escaped
+    quoted.extend(std::iter::repeat_n('\\', 2 * backslashes));
+    quoted.push('"');
+    quoted
 }
 
 /// Starts an executable file in a cross-platform way.
 ///
-/// This is the non-Windows version.
+/// This is the non-Windows version. Windows executables are started with a
+/// wrapper (Wine by default).
 #[cfg(not(windows))]
-pub fn start_executable<I, S>(exe_path: &str, exe_arguments: I) -> Result<bool>
+pub fn start_executable<I, S>(
+    exe_path: &str,
+    exe_arguments: I,
+    options: &ProcessOptions,
+) -> Result<Option<ChildProcess>>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<str>,
@@ -31,18 +151,96 @@ where
         .into_iter()
         .map(|e| e.as_ref().into())
         .collect();
-    Command::new(exe_path)
-        .args(exe_arguments)
-        .spawn()
-        .map(|_| Ok(true))?
+    let default_wrapper = WrapperConfiguration::default();
+    let wrapper = options.wrapper.unwrap_or(&default_wrapper);
+    let use_wrapper = match wrapper.enabled {
+        Some(enabled) => enabled,
+        None => is_windows_executable(exe_path),
+    };
+    let mut command = if use_wrapper {
+        wrapped_command(exe_path, wrapper)?
+    } else {
+        Command::new(exe_path)
+    };
+    command.args(exe_arguments);
+    if let Some(working_directory) = options.working_directory {
+        command.current_dir(working_directory);
+    }
+    if let Some(env) = options.env {
+        command.envs(env);
+    }
+    Ok(Some(ChildProcess(command.spawn()?)))
+}
+
+#[cfg(not(windows))]
+const DEFAULT_WRAPPER_COMMAND: &str = "wine";
+
+/// Builds a command that starts `exe_path` with `wrapper`, from the
+/// executable's directory like on Windows.
+#[cfg(not(windows))]
+fn wrapped_command(
+    exe_path: &str,
+    wrapper: &WrapperConfiguration,
+) -> Result<std::process::Command> {
+    let current_dir = std::env::current_dir()?;
+    let exe_path = current_dir.join(exe_path);
+    let wrapper_command = wrapper
+        .command
+        .as_deref()
+        .unwrap_or(DEFAULT_WRAPPER_COMMAND);
+    log::info!(
+        "Starting '{}' with '{}'",
+        exe_path.display(),
+        wrapper_command
+    );
+    let mut command = std::process::Command::new(wrapper_command);
+    command.args(&wrapper.arguments).arg(&exe_path);
+    if let Some(prefix) = &wrapper.prefix {
+        command.env("WINEPREFIX", current_dir.join(prefix));
+    }
+    command.envs(&wrapper.env);
+    if let Some(exe_dir) = exe_path.parent() {
+        command.current_dir(exe_dir);
+    }
+    Ok(command)
+}
+
+/// Indicates whether `path` is a Windows (PE) executable.
+#[cfg(not(windows))]
+fn is_windows_executable(path: impl AsRef<Path>) -> bool {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let read_header = || -> std::io::Result<bool> {
+        let mut file = std::fs::File::open(path.as_ref())?;
+        // DOS header, which gives the offset of the PE header
+        let mut dos_header = [0u8; 64];
+        file.read_exact(&mut dos_header)?;
+        if &dos_header[..2] != b"MZ" {
+            return Ok(false);
+        }
+        let pe_offset = u32::from_le_bytes([
+            dos_header[60],
+            dos_header[61],
+            dos_header[62],
+            dos_header[63],
+        ]);
+        let mut signature = [0u8; 4];
+        file.seek(SeekFrom::Start(pe_offset as u64))?;
+        file.read_exact(&mut signature)?;
+        Ok(&signature == b"PE\0\0")
+    };
+    read_header().unwrap_or(false)
 }
 
 // Note: Taken from the rustup project
 #[cfg(windows)]
 mod windows {
     use anyhow::{anyhow, Result};
+    use std::collections::HashMap;
     use std::ffi::OsStr;
+    use std::io;
     use std::os::windows::ffi::OsStrExt;
+    use std::path::Path;
 
     fn to_u16s<S: AsRef<OsStr>>(s: S) -> Result<Vec<u16>> {
         fn inner(s: &OsStr) -> Result<Vec<u16>> {
@@ -56,9 +254,89 @@ mod windows {
         inner(s.as_ref())
     }
 
+    /// Handle of a started process, closed when dropped.
+    pub struct ProcessHandle(winapi::um::winnt::HANDLE);
+
+    // Process handles can be used from any thread
+    unsafe impl Send for ProcessHandle {}
+
+    impl ProcessHandle {
+        /// Waits for the process to exit and returns its exit code.
+        pub fn wait(&self) -> Result<u32> {
+            use winapi::um::processthreadsapi::GetExitCodeProcess;
+            use winapi::um::synchapi::WaitForSingleObject;
+            use winapi::um::winbase::{INFINITE, WAIT_FAILED};
+
+            if unsafe { WaitForSingleObject(self.0, INFINITE) } == WAIT_FAILED {
+                return Err(io::Error::last_os_error().into());
+            }
+            let mut exit_code = 0;
+            if unsafe { GetExitCodeProcess(self.0, &mut exit_code) } == 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(exit_code)
+        }
+    }
+
+    impl Drop for ProcessHandle {
+        fn drop(&mut self) {
+            unsafe { winapi::um::handleapi::CloseHandle(self.0) };
+        }
+    }
+
+    /// Starts a process with additional environment variables, without changing
+    /// the patcher's environment. The executable's directory is used as working
+    /// directory by default.
+    pub fn spawn_process_with_env<I, S>(
+        path: &str,
+        arguments: I,
+        env: &HashMap<String, String>,
+        working_directory: Option<&Path>,
+    ) -> Result<ProcessHandle>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        use std::os::windows::io::IntoRawHandle;
+        use std::process::Command;
+        // Returned by CreateProcess for executables that must be elevated
+        const ERROR_ELEVATION_REQUIRED: i32 = 740;
+
+        let current_dir = std::env::current_dir()?;
+        let exe_path = current_dir.join(path);
+        let exe_dir = match working_directory {
+            Some(working_directory) => Some(current_dir.join(working_directory)),
+            None => exe_path.parent().map(|p| p.to_path_buf()),
+        };
+        let mut command = Command::new(&exe_path);
+        command
+            .args(arguments.into_iter().map(|a| a.as_ref().to_string()))
+            .envs(env);
+        if let Some(exe_dir) = exe_dir {
+            command.current_dir(exe_dir);
+        }
+        let child = command.spawn().map_err(|e| {
+            if e.raw_os_error() == Some(ERROR_ELEVATION_REQUIRED) {
+                anyhow!(
+                    "'{}' must run as administrator, which is incompatible with 'env'",
+                    path
+                )
+            } else {
+                e.into()
+            }
+        })?;
+        Ok(ProcessHandle(
+            child.into_raw_handle() as winapi::um::winnt::HANDLE
+        ))
+    }
+
     /// This function is required to start processes that require elevation, from
     /// a non-elevated process.
-    pub fn win32_spawn_process_runas<S>(path: S, parameter: S) -> Result<bool>
+    pub fn win32_spawn_process_runas<S>(
+        path: S,
+        parameter: S,
+        working_directory: Option<&Path>,
+    ) -> Result<Option<ProcessHandle>>
     where
         S: AsRef<OsStr>,
     {
@@ -70,6 +348,7 @@ mod windows {
             pub fn ShellExecuteExW(pExecInfo: *mut SHELLEXECUTEINFOW) -> BOOL;
         }
         const SEE_MASK_CLASSNAME: ULONG = 1;
+        const SEE_MASK_NOCLOSEPROCESS: ULONG = 0x40;
         const SW_SHOW: c_int = 5;
 
         // Note: It seems `path` has to be absolute for the class overwrite to work
@@ -77,7 +356,10 @@ mod windows {
         
         // Get the directory of the executable to use as the working directory
         // This is critical for the game to find its DLLs and dependencies
-        let exe_dir = exe_path.parent().map(|p| p.to_path_buf());
+        let exe_dir = match working_directory {
+            Some(working_directory) => Some(std::env::current_dir()?.join(working_directory)),
+            None => exe_path.parent().map(|p| p.to_path_buf()),
+        };
         let exe_dir_u16 = exe_dir
             .as_ref()
             .and_then(|p| p.to_str())
@@ -90,7 +372,7 @@ mod windows {
         let class = to_u16s("exefile")?;
         let mut execute_info = SHELLEXECUTEINFOW {
             cbSize: std::mem::size_of::<SHELLEXECUTEINFOW>() as u32,
-            fMask: SEE_MASK_CLASSNAME,
+            fMask: SEE_MASK_CLASSNAME | SEE_MASK_NOCLOSEPROCESS,
             hwnd: ptr::null_mut(),
             lpVerb: operation.as_ptr(),
             lpFile: exe_path.as_ptr(),
@@ -110,6 +392,84 @@ mod windows {
         };
 
         let result = unsafe { ShellExecuteExW(&mut execute_info) };
-        Ok(result != 0)
+        if result == 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        // No handle is given when the executable is opened by another process
+        if execute_info.hProcess.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(ProcessHandle(execute_info.hProcess)))
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn run(script: &str, options: &ProcessOptions) -> ProcessExit {
+        start_executable("sh", ["-c", script], options)
+            .unwrap()
+            .unwrap()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_start_executable() {
+        let exit = run("exit 3", &ProcessOptions::default());
+        assert_eq!(
+            exit,
+            ProcessExit {
+                code: Some(3),
+                crashed: false
+            }
+        );
+        let exit = run("kill -SEGV $$", &ProcessOptions::default());
+        assert_eq!(exit.code, None);
+        assert!(exit.crashed);
+
+        let working_directory = tempfile::tempdir().unwrap();
+        let env = HashMap::from([("KPATCHER_TEST".to_string(), "42".to_string())]);
+        let options = ProcessOptions {
+            working_directory: Some(working_directory.path()),
+            env: Some(&env),
+            ..Default::default()
+        };
+        let exit = run(r#"test "$KPATCHER_TEST" = 42 && test -z "$(ls)""#, &options);
+        assert_eq!(exit.code, Some(0));
+
+        assert!(start_executable("missing-executable", ["a"], &options).is_err());
+    }
+
+    #[test]
+    fn test_quote_argument() {
+        assert_eq!(quote_argument("-t:secret"), "-t:secret");
+        assert_eq!(quote_argument(""), r#""""#);
+        assert_eq!(quote_argument("my login"), r#""my login""#);
+        assert_eq!(quote_argument(r#"pass"word"#), r#""pass\"word""#);
+        assert_eq!(quote_argument(r"C:\Games\"), r"C:\Games\");
+        assert_eq!(quote_argument(r"C:\My Games\"), r#""C:\My Games\\""#);
+        assert_eq!(quote_argument(r#"a\"b c"#), r#""a\\\"b c""#);
+    }
+
+    #[test]
+    fn test_is_windows_executable() {
+        let mut content = vec![0u8; 128];
+        content[..2].copy_from_slice(b"MZ");
+        content[60] = 64;
+        content[64..68].copy_from_slice(b"PE\0\0");
+        let exe_file = NamedTempFile::new().unwrap();
+        std::fs::write(exe_file.path(), &content).unwrap();
+        assert!(is_windows_executable(exe_file.path()));
+
+        // Not a PE file (e.g. a DOS program or a script)
+        content[64..68].copy_from_slice(b"NE\0\0");
+        std::fs::write(exe_file.path(), &content).unwrap();
+        assert!(!is_windows_executable(exe_file.path()));
+        std::fs::write(exe_file.path(), b"#!/bin/sh\n").unwrap();
+        assert!(!is_windows_executable(exe_file.path()));
+        assert!(!is_windows_executable("missing.exe"));
     }
 }
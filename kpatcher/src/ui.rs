@@ -1,46 +1,47 @@
-use crate::patcher::{get_patcher_name, PatcherCommand, PatcherConfiguration};
-use crate::process::start_executable;
+use crate::i18n;
+use crate::ipc::{self, IpcError, IpcErrorCode, IpcRequest, IpcResponse, IpcResult};
+use crate::launch::{start_game_client, LaunchVariables, DEFAULT_SERVER_ARGUMENT};
+use crate::patcher::{
+    create_diagnostics_bundle, get_patcher_name, read_patcher_cache, run_hooks, ErrorReport,
+    HookCanceler, HookEvent, LaunchProfile, PatcherCommand, PatcherConfiguration, UserSettings,
+    WindowSettings, DEFAULT_LAUNCH_PROFILE,
+};
+use crate::process::{start_executable, ChildProcess, ProcessExit, ProcessOptions};
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 
-use serde_json::Value;
+use serde_json::{json, Value};
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tao::{
-    dpi::{LogicalSize, PhysicalPosition},
+    dpi::{LogicalSize, PhysicalPosition, PhysicalSize},
     event_loop::{EventLoop, EventLoopProxy},
     window::{Window, WindowBuilder},
 };
 use tinyfiledialogs as tfd;
 use wry::webview::{WebView, WebViewBuilder};
 
-const WINDOW_STATE_FILE: &str = "kpatcher_state.json";
-
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
-pub struct WindowState {
-    pub x: i32,
-    pub y: i32,
-}
-
-impl WindowState {
-    pub fn load() -> Option<WindowState> {
-        let content = fs::read_to_string(WINDOW_STATE_FILE).ok()?;
-        serde_json::from_str(&content).ok()
-    }
-
-    pub fn save(&self) {
-        if let Ok(content) = serde_json::to_string_pretty(self) {
-            let _ = fs::write(WINDOW_STATE_FILE, content);
-        }
-    }
-}
-
-pub fn save_window_position(window: &Window) {
-    if let Ok(pos) = window.outer_position() {
-        let state = WindowState { x: pos.x, y: pos.y };
-        state.save();
+/// Saves the window's position and size, restored on the next launch.
+pub fn save_window_state(window: &Window) {
+    let pos = match window.outer_position() {
+        Ok(pos) => pos,
+        Err(_) => return,
+    };
+    let size = window.inner_size();
+    let mut settings = UserSettings::load();
+    settings.window = Some(WindowSettings {
+        x: pos.x,
+        y: pos.y,
+        width: Some(size.width),
+        height: Some(size.height),
+        monitor: window.current_monitor().and_then(|monitor| monitor.name()),
+    });
+    if let Err(e) = settings.save() {
+        log::warn!("Failed to save the window's state: {:#}", e);
     }
 }
 
@@ -57,16 +58,23 @@ pub enum UiEvent {
     SetPatchInProgress(bool),
     Exit,
     RunScript(String),
+    GameExited(ProcessExit, usize), // A game client exited (clients still running)
+    Activate(Option<PathBuf>),      // The patcher's been started again, maybe with a patch to apply
 }
 
 #[derive(Clone)]
 pub struct UiController {
     proxy: EventLoopProxy<UiEvent>,
+    // Latest probe result of each patch server, by name
+    probe_results: Arc<Mutex<HashMap<String, PatchServerProbe>>>,
 }
 
 impl UiController {
     pub fn new(proxy: EventLoopProxy<UiEvent>) -> UiController {
-        UiController { proxy }
+        UiController {
+            proxy,
+            probe_results: Default::default(),
+        }
     }
 
     pub fn dispatch_patching_status(&self, status: PatchingStatus) {
@@ -76,25 +84,198 @@ impl UiController {
     pub fn set_patch_in_progress(&self, value: bool) {
         let _ = self.proxy.send_event(UiEvent::SetPatchInProgress(value));
     }
+
+    /// Displays a message sent by the patch server, if the UI supports it.
+    pub fn dispatch_notice(&self, notice: &str) {
+        let notice_json = serde_json::to_string(notice).unwrap_or_default();
+        let _ = self.proxy.send_event(UiEvent::RunScript(format!(
+            "if(typeof patchingNotice==='function')patchingNotice({});{}",
+            notice_json,
+            ipc::event_script("notice", &notice)
+        )));
+    }
+
+    /// Sends detailed progress information to the UI (as JSON), if it
+    /// supports it.
+    pub fn dispatch_progress(&self, progress: &PatchingProgress) {
+        if let Ok(progress_json) = serde_json::to_string(progress) {
+            let _ = self.proxy.send_event(UiEvent::RunScript(format!(
+                "if(typeof patchingProgress==='function')patchingProgress({});{}",
+                progress_json,
+                ipc::event_script("progress", progress)
+            )));
+        }
+    }
+
+    /// Keeps the result of a patch server's probe and notifies the UI.
+    pub fn record_probe_result(&self, probe: PatchServerProbe) {
+        let _ = self.proxy.send_event(UiEvent::RunScript(ipc::event_script(
+            "probe_result",
+            &probe,
+        )));
+        if let Ok(mut probe_results) = self.probe_results.lock() {
+            probe_results.insert(probe.name.clone(), probe);
+        }
+    }
+
+    /// Returns the latest probe result of the patch server named `name`.
+    pub fn probe_result(&self, name: &str) -> Option<PatchServerProbe> {
+        let probe_results = self.probe_results.lock().ok()?;
+        probe_results.get(name).cloned()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum PatchingStatus {
     Ready,
-    Error(String),
+    Error(ErrorReport),
     DownloadInProgress(usize, usize, u64),
     InstallationInProgress(usize, usize),
     ManualPatchApplied(String),
+    Maintenance(String),
+    Paused,
+    FilesInUse(String), // Waiting for other programs to release the game's files
+}
+
+/// What currently prevents starting the game client, shared with the event loop
+pub type SharedPlayBlock = Arc<Mutex<Option<PlayBlock>>>;
+
+/// Reason why the game client can't be started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PlayBlock {
+    Maintenance,
+    CriticalPatchMissing(String), // Name of the critical patch
+}
+
+impl PlayBlock {
+    /// Returns what prevents starting the game once `status` is reached, or
+    /// `None` if `status` doesn't change it.
+    pub fn after_status(status: &PatchingStatus) -> Option<Option<PlayBlock>> {
+        match status {
+            PatchingStatus::Ready => Some(None),
+            PatchingStatus::Error(report) => Some(
+                report
+                    .critical_patch
+                    .clone()
+                    .map(PlayBlock::CriticalPatchMissing),
+            ),
+            PatchingStatus::Maintenance(_) => Some(Some(PlayBlock::Maintenance)),
+            _ => None,
+        }
+    }
+
+    fn to_ipc_error(&self) -> IpcError {
+        match self {
+            PlayBlock::Maintenance => {
+                IpcError::new(IpcErrorCode::Maintenance, i18n::tr("maintenance"))
+            }
+            PlayBlock::CriticalPatchMissing(patch) => IpcError::new(
+                IpcErrorCode::CriticalPatchMissing,
+                i18n::tr_with("critical_patch_missing", &[("patch", patch)]),
+            ),
+        }
+    }
+}
+
+impl PatchingStatus {
+    /// Payload of the "status" event sent to the UI.
+    pub fn to_event_payload(&self, play_with_error: bool) -> Value {
+        match self {
+            PatchingStatus::Ready => json!({ "status": "ready" }),
+            PatchingStatus::Error(report) => json!({
+                "status": "error",
+                "message": report.message,
+                "play_with_error": play_with_error,
+                "error": report,
+            }),
+            PatchingStatus::DownloadInProgress(
+                downloaded_patches,
+                total_patches,
+                bytes_per_sec,
+            ) => {
+                json!({
+                    "status": "downloading",
+                    "downloaded_patches": downloaded_patches,
+                    "total_patches": total_patches,
+                    "bytes_per_sec": bytes_per_sec,
+                })
+            }
+            PatchingStatus::InstallationInProgress(installed_patches, total_patches) => json!({
+                "status": "installing",
+                "installed_patches": installed_patches,
+                "total_patches": total_patches,
+            }),
+            PatchingStatus::ManualPatchApplied(patch_name) => json!({
+                "status": "patch_applied",
+                "patch_name": patch_name,
+            }),
+            PatchingStatus::Maintenance(message) => json!({
+                "status": "maintenance",
+                "message": message,
+            }),
+            PatchingStatus::Paused => json!({ "status": "paused" }),
+            PatchingStatus::FilesInUse(message) => json!({
+                "status": "files_in_use",
+                "message": message,
+            }),
+        }
+    }
+}
+
+/// Result of the latest probe of a patch server.
+#[derive(Debug, Clone, Serialize)]
+pub struct PatchServerProbe {
+    pub name: String,
+    pub available: bool,
+    pub latency_ms: Option<u64>,
+    pub error: Option<String>, // Reason why the server is unavailable
+}
+
+impl PatchServerProbe {
+    pub fn new(name: &str, probe_res: std::result::Result<Duration, &anyhow::Error>) -> Self {
+        PatchServerProbe {
+            name: name.to_string(),
+            available: probe_res.is_ok(),
+            latency_ms: probe_res
+                .as_ref()
+                .ok()
+                .map(|latency| latency.as_millis() as u64),
+            error: probe_res.err().map(|e| format!("{:#}", e)),
+        }
+    }
+}
+
+/// Detailed progress of an update, complements `PatchingStatus`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "stage", rename_all = "snake_case")]
+pub enum PatchingProgress {
+    Download {
+        patch_name: String, // Patch that's being downloaded
+        downloaded_patches: usize,
+        total_patches: usize,
+        downloaded_bytes: u64,
+        total_bytes: u64,
+        bytes_per_sec: u64,
+        eta_secs: Option<u64>, // Unknown until the download speed is known
+    },
+    Installation {
+        patch_name: String, // Patch that's being applied
+        installed_patches: usize,
+        total_patches: usize,
+        processed_entries: usize, // Entries of the current patch
+        total_entries: usize,
+    },
 }
 
 /// Builds the Window and WebView, setting up IPC handling.
-/// Returns the Window, WebView, and a shared flag for patching status.
+/// Returns the Window, WebView, and shared states for patching status and for
+/// what prevents starting the game.
 pub fn build_webview(
     event_loop: &EventLoop<UiEvent>,
     config: PatcherConfiguration,
     patching_thread_tx: flume::Sender<PatcherCommand>,
-    proxy: EventLoopProxy<UiEvent>,
-) -> Result<(WebView, Arc<AtomicBool>)> {
+    ui_controller: UiController,
+) -> Result<(WebView, Arc<AtomicBool>, SharedPlayBlock)> {
     let mut window_builder = WindowBuilder::new()
         .with_title(&config.window.title)
         .with_inner_size(LogicalSize::new(
@@ -105,9 +286,23 @@ pub fn build_webview(
         .with_decorations(!config.window.frameless.unwrap_or(false))
         .with_transparent(true);
 
-    // Restore saved window position if available
-    if let Some(state) = WindowState::load() {
-        window_builder = window_builder.with_position(PhysicalPosition::new(state.x, state.y));
+    // Restore the window's last position and size if available
+    if let Some(state) = UserSettings::load().window {
+        // Don't open the window on a monitor that has been unplugged
+        let monitor_available = match &state.monitor {
+            Some(name) => event_loop
+                .available_monitors()
+                .any(|monitor| monitor.name().as_ref() == Some(name)),
+            None => true,
+        };
+        if monitor_available {
+            window_builder = window_builder.with_position(PhysicalPosition::new(state.x, state.y));
+        }
+        if config.window.resizable {
+            if let (Some(width), Some(height)) = (state.width, state.height) {
+                window_builder = window_builder.with_inner_size(PhysicalSize::new(width, height));
+            }
+        }
     }
 
     let window = window_builder
@@ -122,69 +317,25 @@ pub fn build_webview(
 
     // Shared state for IPC handler
     let patching_in_progress = Arc::new(AtomicBool::new(false));
-    let pip_clone = patching_in_progress.clone();
-
-    // Capture config and tx for IPC
-    let ipc_config = config.clone();
-    let ipc_tx = patching_thread_tx.clone();
-    let ipc_proxy = proxy.clone();
+    let play_blocked = SharedPlayBlock::default();
+    let ipc_context = IpcContext {
+        config: config.clone(),
+        patching_thread_tx,
+        proxy: ui_controller.proxy.clone(),
+        ui_controller,
+        patching_in_progress: patching_in_progress.clone(),
+        play_blocked: play_blocked.clone(),
+        running_clients: Default::default(),
+    };
 
     // The IPC handler for Wry
-    let ipc_handler = move |window: &Window, request: String| {
-        match request.as_str() {
-            "play" => {
-                let args = ipc_config.play.arguments.clone();
-                start_game_client(&ipc_config, &args);
-                if ipc_config.play.exit_on_success.unwrap_or(true) {
-                    let _ = ipc_proxy.send_event(UiEvent::Exit);
-                } else if ipc_config.play.minimize_on_start.unwrap_or(false) {
-                    window.set_minimized(true);
-                }
-            }
-            "setup" => {
-                handle_setup(&ipc_config);
-                if ipc_config.setup.exit_on_success.unwrap_or(false) {
-                    let _ = ipc_proxy.send_event(UiEvent::Exit);
-                }
-            }
-            "exit" => {
-                let _ = ipc_proxy.send_event(UiEvent::Exit);
-            }
-            "start_update" => {
-                if pip_clone.load(Ordering::Relaxed) {
-                    let _ = ipc_proxy
-                        .send_event(UiEvent::RunScript("notificationInProgress()".to_string()));
-                } else {
-                    let _ = ipc_tx.send(PatcherCommand::StartUpdate);
-                }
-            }
-            "cancel_update" => {
-                let _ = ipc_tx.send(PatcherCommand::CancelUpdate);
-            }
-            "reset_cache" => {
-                handle_reset_cache();
-            }
-            "manual_patch" => {
-                if pip_clone.load(Ordering::Relaxed) {
-                    let _ = ipc_proxy
-                        .send_event(UiEvent::RunScript("notificationInProgress()".to_string()));
-                } else {
-                    // We need to open a dialog. tfd::open_file_dialog blocks.
-                    // Is it safe on this thread? If this is the UI thread, it blocks UI.
-                    // But `web-view` did it in invoke handler.
-                    handle_manual_patch(&ipc_tx);
-                }
-            }
-            "minimize" => {
-                window.set_minimized(true);
-            }
-            "start_drag" => {
-                let _ = window.drag_window();
-            }
-            req => {
-                handle_json_request(req, &ipc_config, window, &ipc_proxy);
-            }
+    let ipc_handler = move |window: &Window, message: String| match ipc::parse_request(&message) {
+        Some(Ok(request)) => {
+            let result = handle_command(&ipc_context, window, &request);
+            ipc_context.reply(IpcResponse::new(request.id, result));
         }
+        Some(Err(response)) => ipc_context.reply(response),
+        None => handle_legacy_request(&ipc_context, window, &message),
     };
 
     let webview = WebViewBuilder::new(window)?
@@ -194,23 +345,474 @@ pub fn build_webview(
         .with_initialization_script(
             "window.external = { invoke: function(s) { window.ipc.postMessage(s); } };",
         )
+        .with_initialization_script(ipc::BRIDGE_SCRIPT)
         .with_ipc_handler(ipc_handler)
         .build()?;
 
-    Ok((webview, patching_in_progress))
+    Ok((webview, patching_in_progress, play_blocked))
+}
+
+/// State shared with the IPC handler
+struct IpcContext {
+    config: PatcherConfiguration,
+    patching_thread_tx: flume::Sender<PatcherCommand>,
+    proxy: EventLoopProxy<UiEvent>,
+    ui_controller: UiController,
+    patching_in_progress: Arc<AtomicBool>,
+    play_blocked: SharedPlayBlock,
+    running_clients: Arc<AtomicUsize>, // Game clients started by the patcher
+}
+
+impl IpcContext {
+    fn reply(&self, response: IpcResponse) {
+        let _ = self
+            .proxy
+            .send_event(UiEvent::RunScript(ipc::reply_script(&response)));
+    }
+
+    fn send_command(&self, command: PatcherCommand) -> IpcResult {
+        self.patching_thread_tx
+            .send(command)
+            .map(|_| Value::Null)
+            .map_err(|_| IpcError::new(IpcErrorCode::Failed, "Patching thread isn't running"))
+    }
+
+    fn ensure_no_update_in_progress(&self) -> Result<(), IpcError> {
+        if self.patching_in_progress.load(Ordering::Relaxed) {
+            return Err(IpcError::new(
+                IpcErrorCode::UpdateInProgress,
+                i18n::tr("update_in_progress"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Game clients keep GRF files open, which prevents patching them.
+    fn ensure_no_client_running(&self) -> Result<(), IpcError> {
+        if self.running_clients.load(Ordering::SeqCst) > 0 {
+            return Err(IpcError::new(
+                IpcErrorCode::ClientRunning,
+                i18n::tr("client_running"),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Starts the game client and keeps track of it until it exits.
+    fn launch_game_client(
+        &self,
+        profile: &LaunchProfile,
+        arguments: &[String],
+    ) -> Result<(), IpcError> {
+        // Note: Hooks block the UI thread until they're done
+        let game_dir = std::env::current_dir().map_err(failed)?;
+        let variables = [("KPATCHER_LAUNCH_PROFILE", profile.name.clone())];
+        run_hooks(
+            HookEvent::BeforeLaunch,
+            &self.config,
+            &game_dir,
+            &variables,
+            &HookCanceler::default(),
+        )
+        .map_err(failed)?;
+        let child = start_game_client(profile, arguments).map_err(failed)?;
+        if let Some(child) = child {
+            self.supervise_game_client(child);
+        }
+        Ok(())
+    }
+
+    fn supervise_game_client(&self, child: ChildProcess) {
+        let running_clients = self.running_clients.clone();
+        let proxy = self.proxy.clone();
+        running_clients.fetch_add(1, Ordering::SeqCst);
+        std::thread::spawn(move || {
+            let exit = child.wait();
+            let remaining_clients = running_clients.fetch_sub(1, Ordering::SeqCst) - 1;
+            match exit {
+                Ok(exit) => {
+                    let _ = proxy.send_event(UiEvent::GameExited(exit, remaining_clients));
+                }
+                Err(e) => log::warn!("Failed to wait for the game client: {:#}", e),
+            }
+        });
+    }
+
+    fn play_block(&self) -> Option<PlayBlock> {
+        self.play_blocked.lock().ok()?.clone()
+    }
+
+    fn ensure_play_allowed(&self) -> Result<(), IpcError> {
+        if let Some(play_block) = self.play_block() {
+            return Err(play_block.to_ipc_error());
+        }
+        Ok(())
+    }
 }
 
-pub fn start_game_client(config: &PatcherConfiguration, args: &[String]) {
-    let client_exe = &config.play.path;
-    let _ =
-        start_executable(client_exe, args).map_err(|e| log::warn!("Failed to start client: {}", e));
+/// Handles a command sent by the UI, either with `kpatcher.call` or with
+/// `external.invoke`.
+fn handle_command(context: &IpcContext, window: &Window, request: &IpcRequest) -> IpcResult {
+    let config = &context.config;
+    match request.method.as_str() {
+        "get_version" => Ok(json!({
+            "version": crate::PKG_VERSION,
+            "protocol": ipc::PROTOCOL_VERSION,
+        })),
+        "get_state" => Ok(get_state(context)),
+        "get_patch_servers" => Ok(get_patch_servers(context)),
+        "select_patch_server" => {
+            let params: SelectPatchServerParameters = request.params()?;
+            select_patch_server(config, params.name)?;
+            Ok(Value::Null)
+        }
+        "get_settings" => {
+            let settings = UserSettings::load().with_defaults(config);
+            serde_json::to_value(settings).map_err(failed)
+        }
+        "set_settings" => {
+            let changes: serde_json::Map<String, Value> = request.params()?;
+            let settings = update_settings(config, changes)?;
+            serde_json::to_value(settings.with_defaults(config)).map_err(failed)
+        }
+        "get_launch_profiles" => Ok(get_launch_profiles(config)),
+        "select_launch_profile" => {
+            let params: SelectLaunchProfileParameters = request.params()?;
+            let mut settings = UserSettings::load();
+            validate_launch_profile(config, params.name.as_deref())?;
+            settings.launch_profile = params.name;
+            settings.save().map_err(failed)?;
+            Ok(Value::Null)
+        }
+        "probe_patch_servers" => {
+            context.ensure_no_update_in_progress()?;
+            context.send_command(PatcherCommand::ProbePatchServers)
+        }
+        "get_window_config" => Ok(json!({
+            "title": config.window.title,
+            "width": config.window.width,
+            "height": config.window.height,
+            "resizable": config.window.resizable,
+            "frameless": config.window.frameless.unwrap_or(false),
+        })),
+        "play" => {
+            context.ensure_play_allowed()?;
+            let params: PlayParameters = request.params()?;
+            let settings = UserSettings::load();
+            let profile = resolve_launch_profile(config, &settings, params.profile.as_deref())?;
+            let variables = LaunchVariables {
+                login: settings.login.as_deref().unwrap_or_default(),
+                server: DEFAULT_SERVER_ARGUMENT,
+                lang: i18n::language().tag(),
+                ..Default::default()
+            };
+            context.launch_game_client(&profile, &profile.play_arguments(&variables))?;
+            if config.play.exit_on_success.unwrap_or(true) {
+                let _ = context.proxy.send_event(UiEvent::Exit);
+            } else if config.play.minimize_on_start.unwrap_or(false) {
+                window.set_minimized(true);
+            }
+            Ok(Value::Null)
+        }
+        "login" => {
+            context.ensure_play_allowed()?;
+            let params: LoginParameters = request.params()?;
+            let settings = UserSettings::load();
+            let profile = resolve_launch_profile(config, &settings, params.profile.as_deref())?;
+            let variables = LaunchVariables {
+                login: &params.login,
+                password: &params.password,
+                server: params.server.as_deref().unwrap_or(DEFAULT_SERVER_ARGUMENT),
+                lang: i18n::language().tag(),
+            };
+            context.launch_game_client(&profile, &profile.login_arguments(&variables))?;
+            Ok(Value::Null)
+        }
+        "setup" => {
+            handle_setup(config).map_err(failed)?;
+            if config.setup.exit_on_success.unwrap_or(false) {
+                let _ = context.proxy.send_event(UiEvent::Exit);
+            }
+            Ok(Value::Null)
+        }
+        "exit" => {
+            let _ = context.proxy.send_event(UiEvent::Exit);
+            Ok(Value::Null)
+        }
+        "start_update" => {
+            context.ensure_no_update_in_progress()?;
+            context.ensure_no_client_running()?;
+            context.send_command(PatcherCommand::StartUpdate)
+        }
+        "cancel_update" => context.send_command(PatcherCommand::CancelUpdate),
+        "pause_update" => context.send_command(PatcherCommand::PauseUpdate),
+        "resume_update" => context.send_command(PatcherCommand::ResumeUpdate),
+        "retry_update" => context.send_command(PatcherCommand::RetryUpdate),
+        "reset_cache" => {
+            handle_reset_cache();
+            Ok(Value::Null)
+        }
+        "manual_patch" => {
+            context.ensure_no_update_in_progress()?;
+            context.ensure_no_client_running()?;
+            // Note: The file dialog blocks the UI thread until it's closed
+            let selected = handle_manual_patch(&context.patching_thread_tx);
+            Ok(Value::Bool(selected))
+        }
+        "create_diagnostics" => {
+            // GRFs are being rewritten during updates
+            context.ensure_no_update_in_progress()?;
+            // Note: The file dialog blocks the UI thread until it's closed
+            let output_path = tfd::save_file_dialog_with_filter(
+                &i18n::tr("save_diagnostics"),
+                &format!("{}-diagnostics.zip", env!("CARGO_PKG_NAME")),
+                &["*.zip"],
+                &i18n::tr("zip_archives"),
+            );
+            match output_path {
+                None => Ok(Value::Null),
+                Some(output_path) => {
+                    let game_dir = std::env::current_dir().map_err(failed)?;
+                    create_diagnostics_bundle(Path::new(&output_path), config, &game_dir)
+                        .map_err(failed)?;
+                    Ok(Value::String(output_path))
+                }
+            }
+        }
+        "minimize" => {
+            window.set_minimized(true);
+            Ok(Value::Null)
+        }
+        "start_drag" => {
+            let _ = window.drag_window();
+            Ok(Value::Null)
+        }
+        "open_url" => {
+            let params: OpenUrlParameters = request.params()?;
+            open::that(params.url).map_err(failed)?;
+            Ok(Value::Null)
+        }
+        _ => Err(IpcError::new(
+            IpcErrorCode::MethodNotFound,
+            format!("Unknown method '{}'", request.method),
+        )),
+    }
 }
 
-fn handle_setup(config: &PatcherConfiguration) {
+/// Describes the state of the patcher and of the game client.
+fn get_state(context: &IpcContext) -> Value {
+    // The cache doesn't exist until the first update
+    let cache = read_patcher_cache().unwrap_or_default();
+    let play_block = context.play_block();
+    json!({
+        "version": crate::PKG_VERSION,
+        "language": i18n::language().tag(),
+        "update_in_progress": context.patching_in_progress.load(Ordering::Relaxed),
+        "maintenance": play_block == Some(PlayBlock::Maintenance),
+        "critical_patch_missing": match play_block {
+            Some(PlayBlock::CriticalPatchMissing(patch)) => Some(patch),
+            _ => None,
+        },
+        "client_running": context.running_clients.load(Ordering::SeqCst) > 0,
+        "last_patch_index": cache.last_patch_index,
+        "last_patch_server": cache.last_patch_server,
+        "selected_patch_server": UserSettings::load().preferred_patch_server(&context.config),
+    })
+}
+
+/// Lists the configured patch servers along with their latest probe result
+/// (`null` until they're probed). Credentials and other connection settings
+/// aren't exposed.
+fn get_patch_servers(context: &IpcContext) -> Value {
+    let cache = read_patcher_cache().unwrap_or_default();
+    let selected_patch_server = UserSettings::load().preferred_patch_server(&context.config);
+    context
+        .config
+        .web
+        .patch_servers
+        .iter()
+        .map(|server| {
+            let probe = context.ui_controller.probe_result(&server.name);
+            json!({
+                "name": server.name,
+                "plist_url": server.plist_url,
+                "patch_url": server.patch_url,
+                "selected": Some(&server.name) == selected_patch_server.as_ref(),
+                "last_used": Some(&server.name) == cache.last_patch_server.as_ref(),
+                "available": probe.as_ref().map(|probe| probe.available),
+                "latency_ms": probe.as_ref().and_then(|probe| probe.latency_ms),
+                "error": probe.and_then(|probe| probe.error),
+            })
+        })
+        .collect()
+}
+
+/// Saves the patch server selected by the player. `None` restores the
+/// automatic selection.
+fn select_patch_server(
+    config: &PatcherConfiguration,
+    name: Option<String>,
+) -> Result<(), IpcError> {
+    validate_patch_server(config, name.as_deref())?;
+    let mut settings = UserSettings::load();
+    settings.patch_server = name;
+    settings.save().map_err(failed)
+}
+
+/// Changes the user settings given by the UI and saves them.
+fn update_settings(
+    config: &PatcherConfiguration,
+    changes: serde_json::Map<String, Value>,
+) -> Result<UserSettings, IpcError> {
+    let patch_server_changed = changes.contains_key("patch_server");
+    let launch_profile_changed = changes.contains_key("launch_profile");
+    let language_changed = changes.contains_key("language");
+    let mut settings = UserSettings::load();
+    settings
+        .update(changes)
+        .map_err(|e| IpcError::new(IpcErrorCode::InvalidParams, format!("{:#}", e)))?;
+    if patch_server_changed {
+        validate_patch_server(config, settings.patch_server.as_deref())?;
+    }
+    if launch_profile_changed {
+        validate_launch_profile(config, settings.launch_profile.as_deref())?;
+    }
+    settings.save().map_err(failed)?;
+    if language_changed {
+        i18n::set_language(i18n::resolve_language(
+            settings.language.as_deref(),
+            config.language.as_deref(),
+        ));
+    }
+    Ok(settings)
+}
+
+fn validate_patch_server(
+    config: &PatcherConfiguration,
+    name: Option<&str>,
+) -> Result<(), IpcError> {
+    if let Some(name) = name {
+        if !config
+            .web
+            .patch_servers
+            .iter()
+            .any(|server| server.name == name)
+        {
+            return Err(IpcError::new(
+                IpcErrorCode::InvalidParams,
+                format!("Unknown patch server '{}'", name),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Lists the launch profiles. Only what the UI needs to present them is
+/// exposed.
+fn get_launch_profiles(config: &PatcherConfiguration) -> Value {
+    let selected_profile = UserSettings::load().preferred_launch_profile(config);
+    config
+        .play
+        .launch_profiles()
+        .iter()
+        .map(|profile| {
+            json!({
+                "name": profile.name,
+                "path": profile.path,
+                "selected": profile.name == selected_profile,
+            })
+        })
+        .collect()
+}
+
+/// Returns the launch profile requested by the UI, or the one selected by
+/// the player.
+fn resolve_launch_profile(
+    config: &PatcherConfiguration,
+    settings: &UserSettings,
+    requested_profile: Option<&str>,
+) -> Result<LaunchProfile, IpcError> {
+    if let Some(name) = requested_profile {
+        return config.play.launch_profile(name).ok_or_else(|| {
+            IpcError::new(
+                IpcErrorCode::InvalidParams,
+                format!("Unknown launch profile '{}'", name),
+            )
+        });
+    }
+    let name = settings.preferred_launch_profile(config);
+    match config.play.launch_profile(&name) {
+        Some(profile) => Ok(profile),
+        None => {
+            // The profile may have been removed from the configuration
+            log::warn!("Unknown launch profile '{}', using the default one", name);
+            config
+                .play
+                .launch_profile(DEFAULT_LAUNCH_PROFILE)
+                .ok_or_else(|| failed("No launch profile"))
+        }
+    }
+}
+
+fn validate_launch_profile(
+    config: &PatcherConfiguration,
+    name: Option<&str>,
+) -> Result<(), IpcError> {
+    if let Some(name) = name {
+        if config.play.launch_profile(name).is_none() {
+            return Err(IpcError::new(
+                IpcErrorCode::InvalidParams,
+                format!("Unknown launch profile '{}'", name),
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn failed(e: impl std::fmt::Display) -> IpcError {
+    IpcError::new(IpcErrorCode::Failed, format!("{:#}", e))
+}
+
+/// Handles messages sent with `external.invoke`: plain commands or
+/// `{function, parameters}` objects. They get no reply.
+fn handle_legacy_request(context: &IpcContext, window: &Window, message: &str) {
+    let request = match serde_json::from_str::<Value>(message) {
+        Ok(json_req) => match json_req["function"].as_str() {
+            Some(function_name) => IpcRequest {
+                kpatcher: ipc::PROTOCOL_VERSION,
+                id: Value::Null,
+                method: function_name.to_string(),
+                params: json_req["parameters"].clone(),
+            },
+            None => return,
+        },
+        Err(_) => IpcRequest {
+            kpatcher: ipc::PROTOCOL_VERSION,
+            id: Value::Null,
+            method: message.to_string(),
+            params: Value::Null,
+        },
+    };
+    match handle_command(context, window, &request) {
+        Err(e) if e.code == IpcErrorCode::UpdateInProgress => {
+            let _ = context
+                .proxy
+                .send_event(UiEvent::RunScript("notificationInProgress()".to_string()));
+        }
+        Err(e) => log::warn!("'{}' failed: {}", request.method, e.message),
+        Ok(_) => {}
+    }
+}
+
+fn handle_setup(config: &PatcherConfiguration) -> Result<()> {
     let setup_exe = &config.setup.path;
     let setup_args = &config.setup.arguments;
-    let _ = start_executable(setup_exe, setup_args)
-        .map_err(|e| log::warn!("Failed to start setup: {}", e));
+    let options = ProcessOptions {
+        wrapper: config.setup.wrapper.as_ref(),
+        ..Default::default()
+    };
+    start_executable(setup_exe, setup_args, &options).context("Failed to start setup")?;
+    Ok(())
 }
 
 fn handle_reset_cache() {
@@ -220,21 +822,32 @@ fn handle_reset_cache() {
     }
 }
 
-fn handle_manual_patch(tx: &flume::Sender<PatcherCommand>) {
+/// Returns `true` if a patch has been selected.
+fn handle_manual_patch(tx: &flume::Sender<PatcherCommand>) -> bool {
+    let filter_description = i18n::tr("patch_files");
     let opt_path = tfd::open_file_dialog(
-        "Select a file",
+        &i18n::tr("select_patch"),
         "",
-        Some((&["*.thor"], "Patch Files (*.thor)")),
+        Some((&["*.thor"], filter_description.as_str())),
     );
     if let Some(path) = opt_path {
         let _ = tx.send(PatcherCommand::ApplyPatch(PathBuf::from(path)));
+        return true;
     }
+    false
+}
+
+#[derive(Deserialize)]
+struct PlayParameters {
+    profile: Option<String>, // Launch profile, instead of the selected one
 }
 
 #[derive(Deserialize)]
 struct LoginParameters {
     login: String,
     password: String,
+    server: Option<String>,
+    profile: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -242,39 +855,14 @@ struct OpenUrlParameters {
     url: String,
 }
 
-fn handle_json_request(
-    request: &str,
-    config: &PatcherConfiguration,
-    _window: &Window,
-    _proxy: &EventLoopProxy<UiEvent>,
-) {
-    if let Ok(json_req) = serde_json::from_str::<Value>(request) {
-        if let Some(function_name) = json_req["function"].as_str() {
-            match function_name {
-                "login" => {
-                    if let Ok(params) =
-                        serde_json::from_value::<LoginParameters>(json_req["parameters"].clone())
-                    {
-                        let mut args = vec![
-                            format!("-t:{}", params.password),
-                            params.login,
-                            "server".to_string(),
-                        ];
-                        args.extend(config.play.arguments.iter().cloned());
-                        start_game_client(config, &args);
-                    }
-                }
-                "open_url" => {
-                    if let Ok(params) =
-                        serde_json::from_value::<OpenUrlParameters>(json_req["parameters"].clone())
-                    {
-                        let _ = open::that(params.url);
-                    }
-                }
-                _ => log::error!("Unknown function '{}'", function_name),
-            }
-        }
-    }
+#[derive(Deserialize)]
+struct SelectPatchServerParameters {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SelectLaunchProfileParameters {
+    name: Option<String>,
 }
 
 #[cfg(windows)]
@@ -2,8 +2,9 @@ use std::fs::File;
 use std::path::Path;
 
 use anyhow::{anyhow, Result};
-use gruf::thor::ThorArchiveBuilder;
+use gruf::thor::{ThorArchiveBuilder, ThorPatchInfo, HOOK_FILE_NAME};
 use log;
+use sha2::{Digest, Sha256};
 use walkdir::WalkDir;
 
 use crate::patch_definition::PatchDefinition;
@@ -23,7 +24,8 @@ where
         patch_definition.use_grf_merging,
         patch_definition.target_grf_name,
         patch_definition.include_checksums,
-    )?;
+    )?
+    .with_sha256_checksums(patch_definition.include_sha256_checksums);
     for entry in patch_definition.entries {
         let win32_relative_path = win32_path(&entry.relative_path);
         let target_win32_relative_path = entry.in_grf_path.unwrap_or(win32_relative_path.clone());
@@ -37,7 +39,21 @@ where
         let native_path = patch_data_directory
             .as_ref()
             .join(posix_path(entry.relative_path));
-        if native_path.is_file() {
+        if let Some(delta_base) = entry.delta_base {
+            // Path points to a single file that's shipped as a delta
+            let base_path = patch_data_directory.as_ref().join(posix_path(&delta_base));
+            if !native_path.is_file() || !base_path.is_file() {
+                return Err(anyhow!(
+                    "Delta entries require existing files ('{}', '{}')",
+                    native_path.to_string_lossy(),
+                    base_path.to_string_lossy()
+                ));
+            }
+            log::trace!("'{}' will be UPDATED (delta)", &target_win32_relative_path);
+            let base_file = File::open(base_path)?;
+            let file = File::open(native_path)?;
+            archive_builder.append_file_delta(target_win32_relative_path, base_file, file)?;
+        } else if native_path.is_file() {
             // Path points to a single file
             log::trace!("'{}' will be UPDATED", &target_win32_relative_path);
             let file = File::open(native_path)?;
@@ -56,9 +72,41 @@ where
             ));
         }
     }
+    if let Some(hook) = patch_definition.hook {
+        let hook_path = patch_data_directory.as_ref().join(posix_path(&hook));
+        if !hook_path.is_file() {
+            return Err(anyhow!(
+                "Hook file '{}' does not exist",
+                hook_path.to_string_lossy()
+            ));
+        }
+        log::trace!("'{}' will be run after the patch is applied", &hook);
+        let file = File::open(hook_path)?;
+        archive_builder.append_file_update(HOOK_FILE_NAME.to_string(), file)?;
+    }
     Ok(())
 }
 
+/// Generates the plist.txt entry of the patch located at `patch_path`,
+/// including its size and SHA-256 digest.
+pub fn patch_list_entry(index: usize, patch_path: impl AsRef<Path>) -> Result<ThorPatchInfo> {
+    let patch_path = patch_path.as_ref();
+    let file_name = patch_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Invalid patch file name"))?;
+    let mut patch_file = File::open(patch_path)?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut patch_file, &mut hasher)?;
+    Ok(ThorPatchInfo {
+        index,
+        file_name: file_name.to_string(),
+        size: Some(size),
+        sha256: Some(hasher.finalize().into()),
+        ..Default::default()
+    })
+}
+
 fn append_directory_update<P1, P2>(
     archive_builder: &mut ThorArchiveBuilder<File>,
     patch_data_directory: P1,
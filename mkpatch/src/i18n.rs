@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+/// Mensagens de cada idioma, as mensagens ausentes são exibidas em inglês
+const CATALOGS: [(&str, &str); 3] = [
+    ("en", include_str!("../locales/en.yml")),
+    ("pt-BR", include_str!("../locales/pt-BR.yml")),
+    ("es", include_str!("../locales/es.yml")),
+];
+const FALLBACK_LANGUAGE: &str = "en";
+
+static MESSAGES: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Idioma da interface, de acordo com o locale do sistema.
+pub fn language() -> &'static str {
+    sys_locale::get_locale()
+        .as_deref()
+        .and_then(language_from_locale)
+        .unwrap_or(FALLBACK_LANGUAGE)
+}
+
+/// Aceita tags de idioma (`pt-BR`) e locales POSIX (`pt_BR.UTF-8`).
+fn language_from_locale(locale: &str) -> Option<&'static str> {
+    let primary_subtag = locale.split(['-', '_', '.', '@']).next()?;
+    match primary_subtag.to_ascii_lowercase().as_str() {
+        "en" => Some("en"),
+        "pt" => Some("pt-BR"),
+        "es" => Some("es"),
+        _ => None,
+    }
+}
+
+/// Mensagens do idioma da interface, completadas com as mensagens em inglês.
+/// Também são usadas pela página da interface.
+pub fn messages() -> &'static HashMap<String, String> {
+    MESSAGES.get_or_init(|| {
+        let mut messages = load_catalog(FALLBACK_LANGUAGE);
+        messages.extend(load_catalog(language()));
+        messages
+    })
+}
+
+fn load_catalog(language: &str) -> HashMap<String, String> {
+    CATALOGS
+        .iter()
+        .find(|(catalog_language, _)| *catalog_language == language)
+        .and_then(|(_, catalog)| serde_yaml::from_str(catalog).ok())
+        .unwrap_or_default()
+}
+
+/// Retorna a mensagem `key`, com os marcadores `{nome}` substituídos.
+pub fn tr(key: &str, args: &[(&str, &str)]) -> String {
+    let mut message = match messages().get(key) {
+        Some(message) => message.clone(),
+        None => return key.to_string(),
+    };
+    for (name, value) in args {
+        message = message.replace(&format!("{{{}}}", name), value);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalogs_are_complete() {
+        let mut english_keys: Vec<String> = load_catalog("en").into_keys().collect();
+        english_keys.sort_unstable();
+        assert!(!english_keys.is_empty());
+        for (language, _) in CATALOGS {
+            let mut keys: Vec<String> = load_catalog(language).into_keys().collect();
+            keys.sort_unstable();
+            assert_eq!(keys, english_keys, "{}", language);
+        }
+        assert_eq!(language_from_locale("pt_BR.UTF-8"), Some("pt-BR"));
+        assert_eq!(language_from_locale("es-AR"), Some("es"));
+        assert_eq!(language_from_locale("de"), None);
+    }
+}
@@ -1,7 +1,10 @@
 mod patch_definition;
 mod generator;
 mod ui;
+mod i18n;
+mod validation;
 pub mod embed;
+mod signature;
 
 use std::path::PathBuf;
 use std::{env, process};
@@ -11,7 +14,9 @@ use log::LevelFilter;
 use patch_definition::{parse_patch_definition};
 use simple_logger::SimpleLogger;
 use structopt::StructOpt;
-use generator::generate_patch_from_definition; // Import from new module
+use generator::{generate_patch_from_definition, patch_list_entry}; // Import from new module
+use ed25519_dalek::SigningKey;
+use signature::{generate_signing_key, read_signing_key, sign_file};
 
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 const PKG_AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
@@ -23,7 +28,7 @@ struct Opt {
     #[structopt(short, long, help = "Enable verbose logging")]
     verbose: bool,
     #[structopt(parse(from_os_str), help = "Path to a patch definition file")]
-    patch_definition_file: PathBuf,
+    patch_definition_file: Option<PathBuf>,
     #[structopt(
         parse(from_os_str),
         short,
@@ -38,31 +43,73 @@ struct Opt {
         help = "Path to the output archive (default: <patch_definition_file_name>.thor)"
     )]
     output_file: Option<PathBuf>,
+    #[structopt(
+        long,
+        help = "Index of the patch in plist.txt, prints the matching plist.txt line (with size and SHA-256)"
+    )]
+    plist_index: Option<usize>,
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "Generate a new signing key at the given path and print its public key"
+    )]
+    generate_signing_key: Option<PathBuf>,
+    #[structopt(
+        parse(from_os_str),
+        long,
+        help = "Path to a signing key, used to sign the generated archive"
+    )]
+    signing_key: Option<PathBuf>,
+    #[structopt(
+        parse(from_os_str),
+        long,
+        requires = "signing-key",
+        help = "Additional files to sign (e.g. plist.txt)"
+    )]
+    sign: Vec<PathBuf>,
 }
 
 fn run(cli_args: Opt) -> Result<()> {
+    if let Some(key_path) = &cli_args.generate_signing_key {
+        let public_key =
+            generate_signing_key(key_path).context("Failed to generate signing key")?;
+        log::info!("Signing key generated at '{}'", key_path.to_string_lossy());
+        log::info!("Public key: {}", public_key);
+        return Ok(());
+    }
+    let signing_key = match &cli_args.signing_key {
+        None => None,
+        Some(key_path) => Some(read_signing_key(key_path).context("Failed to load signing key")?),
+    };
+    let patch_definition_file = match cli_args.patch_definition_file {
+        Some(patch_definition_file) => patch_definition_file,
+        None if !cli_args.sign.is_empty() => {
+            return sign_files(signing_key.as_ref(), &cli_args.sign);
+        }
+        None => return Err(anyhow!("No patch definition file given")),
+    };
     let patch_data_directory = cli_args
         .patch_data_directory
         .unwrap_or_else(|| PathBuf::from("."));
     let output_file_path = cli_args.output_file.unwrap_or(PathBuf::from(
-        cli_args
-            .patch_definition_file
+        patch_definition_file
             .with_extension("thor")
             .file_name()
             .ok_or_else(|| anyhow!("Invalid patch definition file name"))?,
     ));
 
     // Parse the YAML definition file
-    log::info!(
-        "Processing '{}'",
-        cli_args.patch_definition_file.to_string_lossy()
-    );
-    let patch_definition = parse_patch_definition(&cli_args.patch_definition_file)
+    log::info!("Processing '{}'", patch_definition_file.to_string_lossy());
+    let patch_definition = parse_patch_definition(&patch_definition_file)
         .context("Failed to parse the patch definition")?;
 
     // Display patch info
     log::info!("GRF merging: {}", patch_definition.use_grf_merging);
     log::info!("Checksums included: {}", patch_definition.include_checksums);
+    log::info!(
+        "SHA-256 checksums included: {}",
+        patch_definition.include_sha256_checksums
+    );
     if let Some(target_grf_name) = &patch_definition.target_grf_name {
         log::info!("Target GRF: '{}'", target_grf_name);
     } else {
@@ -76,6 +123,29 @@ fn run(cli_args: Opt) -> Result<()> {
         "Patch generated at '{}'",
         output_file_path.to_string_lossy()
     );
+    if let Some(plist_index) = cli_args.plist_index {
+        let patch_info = patch_list_entry(plist_index, &output_file_path)
+            .context("Failed to generate the plist.txt line")?;
+        log::info!("plist.txt line: {}", patch_info);
+    }
+    if signing_key.is_some() {
+        sign_files(signing_key.as_ref(), &[output_file_path])?;
+    }
+    sign_files(signing_key.as_ref(), &cli_args.sign)
+}
+
+fn sign_files(signing_key: Option<&SigningKey>, file_paths: &[PathBuf]) -> Result<()> {
+    if file_paths.is_empty() {
+        return Ok(());
+    }
+    let signing_key = signing_key.ok_or_else(|| anyhow!("No signing key given"))?;
+    for file_path in file_paths {
+        let signature_path = sign_file(signing_key, file_path).context("Failed to sign file")?;
+        log::info!(
+            "Signature written to '{}'",
+            signature_path.to_string_lossy()
+        );
+    }
     Ok(())
 }
 
@@ -9,9 +9,13 @@ use serde::Deserialize;
 pub struct PatchDefinition {
     #[serde(default)] // Defaults to false
     pub include_checksums: bool,
+    #[serde(default)] // Defaults to false
+    pub include_sha256_checksums: bool,
     pub use_grf_merging: bool,
     pub target_grf_name: Option<String>,
     pub entries: Vec<PatchEntry>,
+    // File describing a command that the patcher runs after applying the patch
+    pub hook: Option<String>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -19,7 +23,9 @@ pub struct PatchEntry {
     pub relative_path: String,
     #[serde(default)] // Defaults to false
     pub is_removed: bool,
-    pub in_grf_path: Option<String>
+    pub in_grf_path: Option<String>,
+    // Previous version of the file, a delta is shipped instead of the whole file
+    pub delta_base: Option<String>,
 }
 
 pub fn parse_patch_definition(file_path: impl AsRef<Path>) -> Result<PatchDefinition> {
@@ -0,0 +1,134 @@
+//! Módulo para assinar patches e o plist.txt com Ed25519.
+//!
+//! As assinaturas são destacadas: para cada arquivo assinado, um arquivo
+//! '<nome>.sig' contendo a assinatura em hexadecimal é gerado ao lado dele.
+//! A assinatura é feita sobre o SHA-512 do nome do arquivo, de um byte nulo e
+//! do conteúdo (Ed25519ph): um arquivo assinado não pode ser servido com outro
+//! nome.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::SigningKey;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha512};
+
+/// Sufixo dos arquivos de assinatura (ex: 'plist.txt.sig')
+pub const SIGNATURE_FILE_SUFFIX: &str = ".sig";
+/// Contexto Ed25519ph
+/// IMPORTANTE: Manter sincronizado com kpatcher/src/patcher/signature.rs
+const SIGNATURE_CONTEXT: &[u8] = b"kpatcher";
+
+/// Gera uma nova chave de assinatura e a salva em `key_path`.
+///
+/// Retorna a chave pública correspondente (em hexadecimal), que deve ser
+/// adicionada à configuração do kpatcher.
+pub fn generate_signing_key(key_path: &Path) -> Result<String> {
+    // O arquivo é criado somente se não existir, legível apenas pelo dono
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut key_file = options.open(key_path).map_err(|e| {
+        if e.kind() == io::ErrorKind::AlreadyExists {
+            anyhow!(
+                "'{}' already exists, refusing to overwrite it",
+                key_path.display()
+            )
+        } else {
+            anyhow!("Failed to create '{}': {}", key_path.display(), e)
+        }
+    })?;
+    let signing_key = SigningKey::generate(&mut OsRng);
+    key_file
+        .write_all(hex::encode(signing_key.to_bytes()).as_bytes())
+        .with_context(|| format!("Failed to write '{}'", key_path.display()))?;
+    Ok(hex::encode(signing_key.verifying_key().to_bytes()))
+}
+
+/// Lê uma chave de assinatura gerada por `generate_signing_key`.
+pub fn read_signing_key(key_path: &Path) -> Result<SigningKey> {
+    let content = fs::read_to_string(key_path)
+        .with_context(|| format!("Failed to read '{}'", key_path.display()))?;
+    let bytes: [u8; 32] = hex::decode(content.trim())
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| anyhow!("Invalid signing key in '{}'", key_path.display()))?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Assina `file_path` e escreve a assinatura em '<file_path>.sig'.
+///
+/// O arquivo deve ser publicado com o mesmo nome. Retorna o caminho do arquivo
+/// de assinatura.
+pub fn sign_file(signing_key: &SigningKey, file_path: &Path) -> Result<PathBuf> {
+    let file_name = file_path
+        .file_name()
+        .and_then(|file_name| file_name.to_str())
+        .ok_or_else(|| anyhow!("Invalid file name '{}'", file_path.display()))?;
+    let mut file = File::open(file_path)
+        .with_context(|| format!("Failed to open '{}'", file_path.display()))?;
+    let mut digest = Sha512::new();
+    digest.update(file_name.as_bytes());
+    digest.update([0]);
+    io::copy(&mut file, &mut digest)?;
+    let signature = signing_key
+        .sign_prehashed(digest, Some(SIGNATURE_CONTEXT))
+        .map_err(|e| anyhow!("Failed to sign '{}': {}", file_path.display(), e))?;
+
+    let mut signature_path = file_path.as_os_str().to_owned();
+    signature_path.push(SIGNATURE_FILE_SUFFIX);
+    let signature_path = PathBuf::from(signature_path);
+    fs::write(&signature_path, hex::encode(signature.to_bytes()))
+        .with_context(|| format!("Failed to write '{}'", signature_path.display()))?;
+    Ok(signature_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    #[test]
+    fn test_sign_file() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let key_path = tmp_dir.path().join("patch.key");
+        let public_key = generate_signing_key(&key_path).unwrap();
+        // Existing keys are never overwritten
+        assert!(generate_signing_key(&key_path).is_err());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&key_path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let plist_path = tmp_dir.path().join("plist.txt");
+        fs::write(&plist_path, "1 patch1.thor").unwrap();
+        let signing_key = read_signing_key(&key_path).unwrap();
+        let signature_path = sign_file(&signing_key, &plist_path).unwrap();
+        assert_eq!(signature_path, tmp_dir.path().join("plist.txt.sig"));
+
+        let public_key: [u8; 32] = hex::decode(public_key).unwrap().try_into().unwrap();
+        let public_key = VerifyingKey::from_bytes(&public_key).unwrap();
+        let signature: [u8; 64] = hex::decode(fs::read_to_string(&signature_path).unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let mut digest = Sha512::new();
+        digest.update(b"plist.txt\0");
+        digest.update(b"1 patch1.thor");
+        assert!(public_key
+            .verify_prehashed(
+                digest,
+                Some(SIGNATURE_CONTEXT),
+                &Signature::from_bytes(&signature)
+            )
+            .is_ok());
+    }
+}
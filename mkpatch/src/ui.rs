@@ -10,6 +10,8 @@ use tao::{
 use serde::Deserialize;
 
 use crate::embed::embed_config_in_exe;
+use crate::validation::{validate_config_with_patcher, ConfigValidation};
+use crate::i18n::{self, tr};
 use crate::generator::generate_patch_from_definition;
 use crate::patch_definition::{PatchDefinition, PatchEntry};
 
@@ -56,6 +58,7 @@ pub fn run_ui() {
         .with_html(html_content)
         .unwrap()
         .with_initialization_script("window.external = { invoke: function(s) { window.ipc.postMessage(s); } };")
+        .with_initialization_script(&i18n_script())
         .with_ipc_handler(handler)
         .build()
         .unwrap();
@@ -89,9 +92,28 @@ pub fn run_ui() {
     });
 }
 
+/// Entrega as mensagens do idioma da interface para a página
+fn i18n_script() -> String {
+    format!(
+        "window.i18n = {}; window.i18nLanguage = {};",
+        serde_json::to_string(i18n::messages()).unwrap_or("{}".to_string()),
+        serde_json::to_string(i18n::language()).unwrap_or("\"en\"".to_string())
+    )
+}
+
+fn log_message(webview: &WebView, message: &str) {
+    let message = serde_json::to_string(message).unwrap_or_default();
+    let _ = webview.evaluate_script(&format!("logMessage({})", message));
+}
+
+fn alert(webview: &WebView, message: &str) {
+    let message = serde_json::to_string(message).unwrap_or_default();
+    let _ = webview.evaluate_script(&format!("alert({})", message));
+}
+
 fn handle_select_files(webview: &WebView) {
     let files = tfd::open_file_dialog_multi(
-        "Select Files to Patch",
+        &tr("select_files_dialog", &[]),
         "",
         None,
     );
@@ -104,10 +126,11 @@ fn handle_select_files(webview: &WebView) {
 }
 
 fn handle_select_exe(webview: &WebView) {
+    let filter_description = tr("executables", &[]);
     let file = tfd::open_file_dialog(
-        "Selecionar Executável do Patcher",
+        &tr("select_exe_dialog", &[]),
         "",
-        Some((&["*.exe"], "Executáveis (*.exe)")),
+        Some((&["*.exe"], filter_description.as_str())),
     );
 
     if let Some(path) = file {
@@ -118,10 +141,11 @@ fn handle_select_exe(webview: &WebView) {
 }
 
 fn handle_select_yml(webview: &WebView) {
+    let filter_description = tr("yaml_files", &[]);
     let file = tfd::open_file_dialog(
-        "Selecionar Arquivo de Configuração",
+        &tr("select_yml_dialog", &[]),
         "",
-        Some((&["*.yml", "*.yaml"], "Arquivos YAML (*.yml, *.yaml)")),
+        Some((&["*.yml", "*.yaml"], filter_description.as_str())),
     );
 
     if let Some(path) = file {
@@ -141,7 +165,7 @@ fn handle_embed(webview: &WebView, json_str: &str) {
     let input: EmbedInput = match serde_json::from_str(json_str) {
         Ok(i) => i,
         Err(e) => {
-            let _ = webview.evaluate_script(&format!("logMessage('Erro ao processar input: {}')", e));
+            log_message(webview, &tr("invalid_input", &[("error", &e.to_string())]));
             return;
         }
     };
@@ -159,24 +183,38 @@ fn handle_embed(webview: &WebView, json_str: &str) {
         .map(|p| p.join(&output_filename))
         .unwrap_or_else(|| PathBuf::from(&output_filename));
 
-    let _ = webview.evaluate_script("logMessage('Processando...')");
+    log_message(webview, &tr("validating", &[]));
+    match validate_config_with_patcher(&exe_path, &yml_path) {
+        ConfigValidation::Valid(report) => {
+            for line in report.lines() {
+                log_message(webview, line);
+            }
+        },
+        ConfigValidation::Invalid(report) => {
+            for line in report.lines() {
+                log_message(webview, line);
+            }
+            alert(webview, &tr("config_invalid_alert", &[("errors", &report)]));
+            return;
+        },
+        ConfigValidation::Unavailable(reason) => {
+            log_message(webview, &tr("validation_unavailable", &[("reason", &reason)]));
+        }
+    }
+
+    log_message(webview, &tr("processing", &[]));
 
     match embed_config_in_exe(&exe_path, &yml_path, &output_path) {
         Ok(_) => {
-            let output_display = output_path.display().to_string().replace('\\', "\\\\");
-            let _ = webview.evaluate_script(&format!(
-                "logMessage('✅ Sucesso! Arquivo gerado: {}')", 
-                output_display
-            ));
-            let _ = webview.evaluate_script(&format!(
-                "alert('Config embutido com sucesso!\\n\\nArquivo gerado:\\n{}')",
-                output_path.file_name().unwrap_or_default().to_string_lossy()
-            ));
+            let output_display = output_path.display().to_string();
+            log_message(webview, &tr("embed_succeeded", &[("file", &output_display)]));
+            let output_name = output_path.file_name().unwrap_or_default().to_string_lossy();
+            alert(webview, &tr("embed_succeeded_alert", &[("file", &output_name)]));
         },
         Err(e) => {
-            let error_msg = format!("{:#}", e).replace('"', "'");
-            let _ = webview.evaluate_script(&format!("logMessage('❌ Erro: {}')", error_msg));
-            let _ = webview.evaluate_script(&format!("alert('Erro ao embutir config:\\n{}')", error_msg));
+            let error_msg = format!("{:#}", e);
+            log_message(webview, &tr("error", &[("error", &error_msg)]));
+            alert(webview, &tr("embed_failed_alert", &[("error", &error_msg)]));
         }
     }
 }
@@ -193,13 +231,13 @@ fn handle_generate(webview: &WebView, json_str: &str) {
     let input: UIInput = match serde_json::from_str(json_str) {
         Ok(i) => i,
         Err(e) => {
-            let _ = webview.evaluate_script(&format!("logMessage('Error parsing input: {}')", e));
+            log_message(webview, &tr("invalid_input", &[("error", &e.to_string())]));
             return;
         }
     };
 
     if input.files.is_empty() {
-        let _ = webview.evaluate_script("logMessage('No files selected!')");
+        log_message(webview, &tr("no_files_selected", &[]));
         return;
     }
 
@@ -210,14 +248,17 @@ fn handle_generate(webview: &WebView, json_str: &str) {
             relative_path: f.clone(),
             is_removed: false,
             in_grf_path: Some(filename),
+            delta_base: None,
         }
     }).collect();
     
     let def_for_gen = PatchDefinition {
         include_checksums: true,
+        include_sha256_checksums: true,
         use_grf_merging: input.merge_grf,
         target_grf_name: if input.target_grf.is_empty() { None } else { Some(input.target_grf) },
         entries: entries_mapped,
+        hook: None,
     };
 
     let output_path = PathBuf::from(&input.output_filename);
@@ -227,16 +268,16 @@ fn handle_generate(webview: &WebView, json_str: &str) {
         output_path
     };
 
-    let _ = webview.evaluate_script("logMessage('Generating patch...')");
+    log_message(webview, &tr("generating", &[]));
     
     match generate_patch_from_definition(def_for_gen, ".", &output_path) {
         Ok(_) => {
-            let output_display = output_path.display().to_string().replace("\\", "\\\\");
-            let _ = webview.evaluate_script(&format!("logMessage('Success! Patch saved to: {}')", output_display));
-            let _ = webview.evaluate_script("alert('Patch Generated Successfully!')");
+            let output_display = output_path.display().to_string();
+            log_message(webview, &tr("patch_saved", &[("file", &output_display)]));
+            alert(webview, &tr("patch_generated_alert", &[]));
         },
         Err(e) => {
-            let _ = webview.evaluate_script(&format!("logMessage('Error: {}')", e));
+            log_message(webview, &tr("error", &[("error", &format!("{:#}", e))]));
         }
     }
 }
@@ -0,0 +1,42 @@
+//! Validação da configuração antes de embuti-la no patcher.
+
+use std::path::Path;
+use std::process::Command;
+
+/// Código de saída do `kpatcher --validate-config` quando a configuração tem erros
+/// IMPORTANTE: Manter sincronizado com kpatcher/src/main.rs
+const INVALID_CONFIG_EXIT_CODE: i32 = 2;
+
+pub enum ConfigValidation {
+    Valid(String),       // Avisos encontrados
+    Invalid(String),     // Erros e avisos encontrados
+    Unavailable(String), // Motivo pelo qual a configuração não foi validada
+}
+
+/// Valida `config_path` com o próprio patcher em que a configuração será
+/// embutida, que conhece as opções da sua versão.
+///
+/// Patchers antigos não têm `--validate-config` e executáveis de outros
+/// sistemas não podem ser executados, nesses casos a validação não é feita.
+pub fn validate_config_with_patcher(exe_path: &Path, config_path: &Path) -> ConfigValidation {
+    let output = match Command::new(exe_path)
+        .arg("--validate-config")
+        .arg(config_path)
+        .output()
+    {
+        Ok(output) => output,
+        Err(e) => return ConfigValidation::Unavailable(e.to_string()),
+    };
+    let report = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stderr),
+        String::from_utf8_lossy(&output.stdout)
+    )
+    .trim()
+    .to_string();
+    match output.status.code() {
+        Some(0) => ConfigValidation::Valid(report),
+        Some(INVALID_CONFIG_EXIT_CODE) => ConfigValidation::Invalid(report),
+        _ => ConfigValidation::Unavailable(report),
+    }
+}